@@ -1,14 +1,26 @@
+mod motion;
+
 use eframe::epaint::PathStroke;
 use eframe::{egui, run_native, App, Frame, NativeOptions};
 use egui::{
-  include_image, pos2, vec2, Align2, CentralPanel, Color32, Context, FontId, Image, ImageSource,
-  Key, Pos2, Rect, Rounding, Ui, Vec2, ViewportBuilder,
+  include_image, pos2, vec2, Align2, Area, CentralPanel, Color32, Context, FontId, Id, Image,
+  ImageSource, InputState, Key, Painter, Pos2, Rect, Rounding, ScrollArea, TextEdit, Ui, Vec2,
+  ViewportBuilder,
+};
+use motion::{Clock, Prediction, Timeline, Track};
+use physics::{Hull, Machinery, Motion};
+use protocol::binary::{
+  encode_client, read_datagram, read_frame, write_frame, ServerDecoder, MAX_DATAGRAM_LENGTH,
+};
+use protocol::{
+  Capability, ChatScope, ClientMessage, Colour, ParseError, ServerMessage, ShipState, Splash, Wake,
+  MAX_CHAT_LENGTH, PROTOCOL_VERSION,
 };
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Write};
-use std::net::{SocketAddr, TcpStream};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::thread::spawn;
 use std::time::{Duration, Instant};
 
@@ -37,9 +49,72 @@ const SPRITES: &[ImageSource] = &[
   include_image!("../../resources/Smoke.png"),
 ];
 
+// Optional messages this client understands
+const CAPABILITIES: &[Capability] = &[
+  Capability::Welcome,
+  Capability::Say,
+  Capability::Binary,
+  Capability::Snapshot,
+  Capability::Appear,
+  Capability::Udp,
+  Capability::Notice,
+  Capability::Session,
+  Capability::Spectate,
+  Capability::Chat,
+  Capability::Ping,
+  Capability::Predict,
+  Capability::Weather,
+];
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// How often the token is sent, which also keeps NAT mappings open
+const UDP_KEEPALIVE: Duration = Duration::from_secs(1);
+
+const LOST_CONNECTION: &str = "Lost connection to Midway";
+// How often to try getting back to a ship after losing the connection, and for how long
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+const RECONNECT_PATIENCE: Duration = Duration::from_secs(30);
+
+const MESSAGE_DURATION: Duration = Duration::from_secs(8);
+const TOAST_MARGIN: f32 = 10.0;
+const TOAST_PADDING: f32 = 6.0;
+
+// Lines kept for scrolling back, and how long new ones show while the chat is closed
+const CHAT_HISTORY: usize = 200;
+const CHAT_DURATION: Duration = Duration::from_secs(15);
+const CHAT_RECENT_LINES: usize = 8;
+const CHAT_WIDTH: f32 = 400.0;
+// Clear of the speed and throttle in the corner
+const CHAT_LEFT: f32 = 100.0;
+const WHISPER: Color32 = Color32::from_rgb(255, 160, 255);
+
+// Screen pixels per second that the spectator camera moves with the keyboard
+const PAN_SPEED: f32 = 600.0;
+// How far from a small ship a click can be and still follow it, in screen pixels
+const CLICK_RADIUS: f32 = 20.0;
+
+// How far the helm order moves per second while A or D is held, as a fraction of hard over
+const HELM_RATE: f32 = 1.5;
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+// How often the message rates in the network overlay are worked out
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+// Statuses closer together than this are measured from an earlier one, as two read in the same
+// frame would make the server look impossibly fast
+const MIN_STATUS_INTERVAL: Duration = Duration::from_millis(500);
+
 const WAKE: ImageSource = include_image!("../../resources/Wake.png");
 
+// Whitecaps start showing at this sea state, more of them for each step above it
+const ROUGH_SEA: u8 = 3;
+// Metres of sea that each set of whitecaps is scattered over
+const WHITECAP_CELL: f32 = 250.0;
+// Cells smaller than this on screen, in pixels, are too zoomed out to bother
+const MIN_WHITECAP_CELL: f32 = 40.0;
+// Seconds before a whitecap breaks somewhere else
+const WHITECAP_TIME: f32 = 2.0;
+
 struct Ship {
+  // Where it is drawn this frame
   coords: Pos2,
   angle: f32,
   velocity: f32,
@@ -47,6 +122,141 @@ struct Ship {
   colour: Color32,
   size: f32,
   health: f32,
+  track: Track,
+}
+
+// Errors and notices from the server, shown in the corner until they expire
+struct Toast {
+  text: String,
+  colour: Color32,
+  expiry: Instant,
+}
+
+impl Toast {
+  fn new(text: String, colour: Color32) -> Self {
+    Self {
+      text,
+      colour,
+      expiry: Instant::now() + MESSAGE_DURATION,
+    }
+  }
+}
+
+// Where a spectator is looking, instead of at their own ship
+struct Camera {
+  center: Pos2,
+  following: Option<String>,
+}
+
+struct ChatLine {
+  text: String,
+  colour: Color32,
+  received: Instant,
+}
+
+// Chat with other players, opened with enter
+#[derive(Default)]
+struct Chat {
+  open: bool,
+  input: String,
+  lines: Vec<ChatLine>,
+}
+
+impl Chat {
+  fn push(&mut self, text: String, colour: Color32) {
+    if self.lines.len() >= CHAT_HISTORY {
+      self.lines.remove(0);
+    }
+    self.lines.push(ChatLine {
+      text,
+      colour,
+      received: Instant::now(),
+    });
+  }
+}
+
+// Measurements for the network overlay, toggled with F3
+struct Network {
+  overlay: bool,
+  // Pings carry the microseconds since this
+  epoch: Instant,
+  next_ping: Instant,
+  rtt: Option<Duration>,
+  // Counted since the window started, and the rates from the last window
+  window_start: Instant,
+  messages: u32,
+  snapshots: u32,
+  message_rate: f32,
+  snapshot_rate: f32,
+  // When the last status arrived and its tick, to work out how fast the server really ticks
+  last_status: Option<(Instant, u32)>,
+  tick_rate: Option<f32>,
+  tps: u32,
+  load: f32,
+}
+
+impl Network {
+  fn new() -> Self {
+    let now = Instant::now();
+    Self {
+      overlay: false,
+      epoch: now,
+      next_ping: now,
+      rtt: None,
+      window_start: now,
+      messages: 0,
+      snapshots: 0,
+      message_rate: 0.0,
+      snapshot_rate: 0.0,
+      last_status: None,
+      tick_rate: None,
+      tps: 0,
+      load: 0.0,
+    }
+  }
+
+  fn count(&mut self, message: &ServerMessage) {
+    self.messages += 1;
+    if let ServerMessage::Snapshot(..) = message {
+      self.snapshots += 1;
+    }
+    let elapsed = self.window_start.elapsed();
+    if elapsed >= RATE_WINDOW {
+      self.message_rate = self.messages as f32 / elapsed.as_secs_f32();
+      self.snapshot_rate = self.snapshots as f32 / elapsed.as_secs_f32();
+      self.messages = 0;
+      self.snapshots = 0;
+      self.window_start = Instant::now();
+    }
+  }
+
+  fn ping(&mut self) -> ClientMessage {
+    self.next_ping = Instant::now() + PING_INTERVAL;
+    ClientMessage::Ping(self.epoch.elapsed().as_micros() as u64)
+  }
+
+  fn pong(&mut self, timestamp: u64) {
+    // Timestamps from the future can only come from a confused server
+    self.rtt = self
+      .epoch
+      .elapsed()
+      .checked_sub(Duration::from_micros(timestamp));
+  }
+
+  fn status(&mut self, tick: u32, tps: u32, load: f32) {
+    let now = Instant::now();
+    match self.last_status {
+      Some((last, _)) if now - last < MIN_STATUS_INTERVAL => (),
+      Some((last, last_tick)) => {
+        let seconds = (now - last).as_secs_f32();
+        self.tick_rate = Some(tick.wrapping_sub(last_tick) as f32 / seconds);
+        self.last_status = Some((now, tick));
+      }
+      None => self.last_status = Some((now, tick)),
+    }
+    self.tps = tps;
+    self.load = load;
+  }
 }
 
 #[derive(Default)]
@@ -55,45 +265,176 @@ struct ShipData {
   helm: f32,
 }
 
-enum MidwayMessage {
-  Ship(String, Ship),
-  Sunk(String),
-  Radius(f32),
-  Splash(f32, f32, f32, f32, usize, Color32),
-  Wake(f32, f32, f32, f32, f32, f32),
+// Textures this client doesn't have show up as missing
+const fn texture(index: usize) -> usize {
+  if index < SHIP_TEXTURES.len() {
+    index
+  } else {
+    0
+  }
 }
 
-struct MidwayData {
-  rx: Receiver<MidwayMessage>,
+fn motion(ship: &ShipState) -> Motion {
+  Motion {
+    coords: (ship.x, ship.y),
+    angle: ship.angle,
+    velocity: ship.velocity,
+    ..Motion::default()
+  }
+}
+
+impl Ship {
+  // The state is from the tick at time, which is when it is drawn exactly as it is
+  fn new(ship: &ShipState, time: Instant) -> Self {
+    Self {
+      coords: pos2(ship.x, ship.y),
+      angle: ship.angle,
+      velocity: ship.velocity,
+      texture: texture(ship.texture),
+      colour: to_color32(ship.colour),
+      size: ship.size,
+      health: ship.health,
+      track: Track::new(time, motion(ship)),
+    }
+  }
+
+  // Everything but where it is applies straight away
+  fn update(&mut self, ship: &ShipState, time: Instant) {
+    self.texture = texture(ship.texture);
+    self.colour = to_color32(ship.colour);
+    self.size = ship.size;
+    self.health = ship.health;
+    self.track.push(time, motion(ship));
+  }
+
+  fn show(&mut self, motion: Motion) {
+    self.coords = pos2(motion.coords.0, motion.coords.1);
+    self.angle = motion.angle;
+    self.velocity = motion.velocity;
+  }
+}
+
+fn to_color32(colour: Colour) -> Color32 {
+  Color32::from_rgba_unmultiplied(colour.r, colour.g, colour.b, colour.a)
+}
+
+struct Connection {
   stream: TcpStream,
+  // Both sides agreed to use binary frames
+  binary: bool,
+  // The server answers pings and reports its load
+  ping: bool,
+}
+
+impl Connection {
+  fn send(&mut self, message: &ClientMessage) -> std::io::Result<()> {
+    if self.binary {
+      write_frame(&mut self.stream, &encode_client(message))
+    } else {
+      self.stream.write_all(format!("{message}\n").as_bytes())
+    }
+  }
+}
+
+struct MidwayData {
+  rx: Receiver<ServerMessage>,
+  connection: Connection,
+  address: Option<SocketAddr>,
+  // Lets the ship be resumed if the connection drops
+  session: Option<u64>,
   name: String,
+  server_name: String,
   scale: i32,
   radius: Option<f32>,
+  // None when playing, spectators have no ship
+  camera: Option<Camera>,
   ship_data: ShipData,
   ships: HashMap<String, Ship>,
-  splashes: Vec<(f32, f32, f32, Instant, usize, Color32)>,
-  wakes: Vec<(f32, f32, f32, f32, Instant, f32, f32)>,
+  // With when each arrived, since some of them drift
+  splashes: Vec<(Splash, Instant)>,
+  wakes: Vec<(Wake, Instant)>,
+  messages: Vec<(String, Instant)>,
+  toasts: Vec<Toast>,
+  chat: Chat,
+  network: Network,
+  timeline: Timeline,
+  // When the tick of the snapshot being read happened, None if the server doesn't number them
+  stamp: Option<Instant>,
+  // The newest snapshot applied, and whether the one being read is older so its ships are skipped
+  snapshot: Option<u32>,
+  stale: bool,
+  hull: Option<Hull>,
+  // Where the server last said the rudder and engine were
+  machinery: Option<Machinery>,
+  // Yaw rate and drift of the own ship as of the last snapshot, which positions don't carry
+  turn: (f32, f32),
+  current: (f32, f32),
+  prediction: Option<Prediction>,
+  // Wind direction and speed, and sea state, None if the server doesn't say
+  weather: Option<(f32, f32, u8)>,
 }
 
 impl MidwayData {
-  fn new(name: String, rx: Receiver<MidwayMessage>, stream: TcpStream) -> Self {
+  fn new(name: String, server_name: String, connection: Connection) -> Self {
+    let (tx, rx) = channel();
+    let stream_clone = connection.stream.try_clone().expect("Try-clone broke");
+    let binary = connection.binary;
+    spawn(move || handle_midway_connection(stream_clone, &tx, binary));
     Self {
       rx,
-      stream,
+      address: connection.stream.peer_addr().ok(),
+      connection,
+      session: None,
       name,
+      server_name,
       scale: 0,
       radius: None,
+      camera: None,
       ship_data: ShipData::default(),
       ships: HashMap::new(),
       splashes: Vec::new(),
       wakes: Vec::new(),
+      messages: Vec::new(),
+      toasts: Vec::new(),
+      chat: Chat::default(),
+      network: Network::new(),
+      timeline: Timeline::new(),
+      stamp: None,
+      snapshot: None,
+      stale: false,
+      hull: None,
+      machinery: None,
+      turn: (0.0, 0.0),
+      current: (0.0, 0.0),
+      prediction: None,
+      weather: None,
+    }
+  }
+
+  fn spectator(server_name: String, connection: Connection) -> Self {
+    Self {
+      camera: Some(Camera {
+        center: Pos2::ZERO,
+        following: None,
+      }),
+      ..Self::new(String::new(), server_name, connection)
     }
   }
 }
 
+// Trying to get back to a ship after losing the connection
+struct Reconnecting {
+  address: SocketAddr,
+  name: String,
+  session: u64,
+  next_attempt: Instant,
+  give_up: Instant,
+}
+
 enum Window {
-  MainMenu(String, String, String, Option<&'static str>),
-  Midway(MidwayData),
+  MainMenu(String, String, String, Option<String>),
+  Midway(Box<MidwayData>),
+  Reconnecting(Reconnecting),
 }
 
 impl Default for Window {
@@ -138,21 +479,46 @@ impl App for Enterprise {
   fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
     CentralPanel::default().show(ctx, |ui| match &mut self.window {
       Window::MainMenu(name, ip, port, message) => {
-        if let Some(stream) = draw_main_menu(ui, name, ip, port, message) {
-          let (tx, rx) = channel();
-          let stream_clone = stream.try_clone().expect("Try-clone broke");
-          spawn(move || handle_midway_connection(stream_clone, &tx));
-          self.window = Window::Midway(MidwayData::new(name.clone(), rx, stream));
+        if let Some(data) = draw_main_menu(ui, name, ip, port, message) {
+          self.window = Window::Midway(Box::new(data));
         }
       }
-      Window::Midway(ref mut data) => {
-        if draw_midway(ui, data).is_none() {
-          self.window = Window::MainMenu(
-            String::new(),
-            String::new(),
-            String::new(),
-            Some("Lost connection to Midway"),
-          );
+      Window::Midway(ref mut data) => match draw_midway(ui, data) {
+        Ok(()) => (),
+        Err(None) => {
+          self.window = match (data.address, data.session) {
+            (Some(address), Some(session)) => Window::Reconnecting(Reconnecting {
+              address,
+              name: data.name.clone(),
+              session,
+              next_attempt: Instant::now(),
+              give_up: Instant::now() + RECONNECT_PATIENCE,
+            }),
+            _ => main_menu(LOST_CONNECTION),
+          }
+        }
+        Err(Some(message)) => self.window = main_menu(&message),
+      },
+      Window::Reconnecting(reconnecting) => {
+        ui.label(LOST_CONNECTION);
+        ui.label("Reconnecting to your ship...");
+        let now = Instant::now();
+        if ui.button("Give up").clicked() {
+          self.window = main_menu(LOST_CONNECTION);
+        } else if now >= reconnecting.next_attempt {
+          let resume = ClientMessage::Resume(reconnecting.session);
+          match TcpStream::connect_timeout(&reconnecting.address, RECONNECT_INTERVAL)
+            .map_err(|_| "Could not connect to Midway".to_owned())
+            .and_then(|stream| handshake(stream, &resume))
+          {
+            Ok((connection, server_name)) => {
+              let name = reconnecting.name.clone();
+              self.window =
+                Window::Midway(Box::new(MidwayData::new(name, server_name, connection)));
+            }
+            Err(error) if now >= reconnecting.give_up => self.window = main_menu(&error),
+            Err(_) => reconnecting.next_attempt = now + RECONNECT_INTERVAL,
+          }
         }
       }
     });
@@ -160,101 +526,418 @@ impl App for Enterprise {
   }
 }
 
+fn main_menu(message: &str) -> Window {
+  Window::MainMenu(
+    String::new(),
+    String::new(),
+    String::new(),
+    Some(message.to_owned()),
+  )
+}
+
+/// Negotiates the protocol version and joins, returning the name of the server
+fn handshake(mut stream: TcpStream, join: &ClientMessage) -> Result<(Connection, String), String> {
+  let lost = |_| "Could not connect to Midway".to_owned();
+  let hello = ClientMessage::Hello(PROTOCOL_VERSION, CAPABILITIES.to_vec());
+  stream
+    .write_all(format!("{hello}\n").as_bytes())
+    .map_err(lost)?;
+  stream
+    .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+    .map_err(lost)?;
+  let mut reader = BufReader::new(stream.try_clone().map_err(lost)?);
+  let mut buf = String::new();
+  match reader.read_line(&mut buf) {
+    // Servers from before the handshake hang up when they see hello
+    Ok(0) => return Err("This Midway is too old for this version of Enterprise".to_owned()),
+    Ok(_) => (),
+    Err(_) => return Err("Midway did not answer".to_owned()),
+  }
+  let capabilities = match buf.parse() {
+    Ok(ServerMessage::Hello(_, capabilities)) => capabilities,
+    Ok(ServerMessage::Reject(reason)) => return Err(reason),
+    _ => return Err("Midway sent an invalid reply".to_owned()),
+  };
+  if *join == ClientMessage::Spectate && !capabilities.contains(&Capability::Spectate) {
+    return Err("This Midway doesn't allow spectators".to_owned());
+  }
+  let binary = capabilities.contains(&Capability::Binary);
+  let ping = capabilities.contains(&Capability::Ping);
+  buf.clear();
+  reader.read_line(&mut buf).map_err(lost)?;
+  let Ok(ServerMessage::Server(server_name)) = buf.parse() else {
+    return Err("Midway sent an invalid reply".to_owned());
+  };
+  stream.set_read_timeout(None).map_err(lost)?;
+  let mut connection = Connection {
+    stream,
+    binary,
+    ping,
+  };
+  connection.send(join).map_err(lost)?;
+  Ok((connection, server_name))
+}
+
 fn draw_main_menu(
   ui: &mut Ui,
   name: &mut String,
   ip: &mut String,
   port: &mut String,
-  message: &mut Option<&'static str>,
-) -> Option<TcpStream> {
+  message: &mut Option<String>,
+) -> Option<MidwayData> {
   ui.label("Ship name");
   ui.text_edit_singleline(name);
   ui.label("Location of Midway");
   ui.text_edit_singleline(ip);
   ui.label("Port");
   ui.text_edit_singleline(port);
-  if ui.button("Connect").clicked() {
+  let (connect, spectate) = ui
+    .horizontal(|ui| {
+      (
+        ui.button("Connect").clicked(),
+        ui.button("Spectate").clicked(),
+      )
+    })
+    .inner;
+  if connect || spectate {
+    let join = if spectate {
+      ClientMessage::Spectate
+    } else {
+      ClientMessage::Ship(name.clone())
+    };
     match format!("{ip}:{port}").parse::<SocketAddr>() {
       Ok(address) => match TcpStream::connect(address) {
-        Ok(mut stream) => {
-          if stream
-            .write_all(format!("ship {name}\n").as_bytes())
-            .is_ok()
-          {
-            return Some(stream);
-          } else {
-            *message = Some("Could not connect to Midway");
+        Ok(stream) => match handshake(stream, &join) {
+          Ok((connection, server_name)) if spectate => {
+            return Some(MidwayData::spectator(server_name, connection))
           }
-        }
-        Err(_) => *message = Some("Could not connect to Midway"),
+          Ok((connection, server_name)) => {
+            return Some(MidwayData::new(name.clone(), server_name, connection))
+          }
+          Err(error) => *message = Some(error),
+        },
+        Err(_) => *message = Some("Could not connect to Midway".to_owned()),
       },
-      Err(_) => *message = Some("Invalid ip address"),
+      Err(_) => *message = Some("Invalid ip address".to_owned()),
     }
   }
   if let Some(message) = message {
-    ui.label(*message);
+    ui.label(message.as_str());
   }
   None
 }
 
-fn draw_midway(ui: &Ui, data: &mut MidwayData) -> Option<()> {
-  let screen_size = ui.clip_rect().right_bottom();
-  ui.ctx().input(|i| {
-    data.ship_data.helm = match (i.key_down(Key::A), i.key_down(Key::D)) {
-      (true, false) => -1.0,
-      (false, true) => 1.0,
-      (true, true) | (false, false) => 0.0,
-    };
-    match (i.key_down(Key::Z), i.key_down(Key::X), i.key_down(Key::C)) {
-      (true, false, false) => data.ship_data.power = 1.0,
-      (false, true, false) => data.ship_data.power = 0.0,
-      (false, false, true) => data.ship_data.power = -0.5,
-      (false, false, false) => {
-        if i.key_down(Key::W) {
-          data.ship_data.power += 0.01;
+/// Steers the player's own ship
+fn control_ship(i: &InputState, data: &mut MidwayData) {
+  // The wheel stays where it is left, Q puts the rudder back amidships
+  if i.key_down(Key::Q) {
+    data.ship_data.helm = 0.0;
+  }
+  if i.key_down(Key::A) {
+    data.ship_data.helm -= HELM_RATE * i.stable_dt;
+  }
+  if i.key_down(Key::D) {
+    data.ship_data.helm += HELM_RATE * i.stable_dt;
+  }
+  data.ship_data.helm = data.ship_data.helm.clamp(-1.0, 1.0);
+  match (i.key_down(Key::Z), i.key_down(Key::X), i.key_down(Key::C)) {
+    (true, false, false) => data.ship_data.power = 1.0,
+    (false, true, false) => data.ship_data.power = 0.0,
+    (false, false, true) => data.ship_data.power = -0.5,
+    (false, false, false) => {
+      if i.key_down(Key::W) {
+        data.ship_data.power += 0.01;
+      }
+      if i.key_down(Key::S) {
+        data.ship_data.power -= 0.01;
+      }
+      data.ship_data.power = data.ship_data.power.clamp(-0.5, 1.0);
+    }
+    _ => (),
+  };
+  if i.key_down(Key::V) {
+    data.connection.send(&ClientMessage::Anchor).ok();
+  }
+  if i.key_pressed(Key::Backtick) {
+    data.connection.send(&ClientMessage::Smoke).ok();
+  }
+  if i.key_pressed(Key::Num1) {
+    data.connection.send(&ClientMessage::Action(1)).ok();
+  }
+  if i.key_pressed(Key::Num2) {
+    data.connection.send(&ClientMessage::Action(2)).ok();
+  }
+  if i.key_pressed(Key::Num3) {
+    data.connection.send(&ClientMessage::Action(3)).ok();
+  }
+  if i.key_pressed(Key::Num4) {
+    data.connection.send(&ClientMessage::Action(4)).ok();
+  }
+  if i.key_pressed(Key::Num5) {
+    data.connection.send(&ClientMessage::Action(5)).ok();
+  }
+  if i.key_pressed(Key::Num6) {
+    data.connection.send(&ClientMessage::Action(6)).ok();
+  }
+  if i.key_pressed(Key::Num7) {
+    data.connection.send(&ClientMessage::Action(7)).ok();
+  }
+  if i.key_pressed(Key::Num8) {
+    data.connection.send(&ClientMessage::Action(9)).ok();
+  }
+  if i.key_pressed(Key::Num9) {
+    data.connection.send(&ClientMessage::Action(9)).ok();
+  }
+  if i.key_pressed(Key::Num0) {
+    data.connection.send(&ClientMessage::Action(10)).ok();
+  }
+}
+
+/// Moves a spectator's camera, which stops following whatever ship it was on
+fn control_camera(i: &InputState, camera: &mut Camera, scale: f32) {
+  let mut direction = Vec2::ZERO;
+  if i.key_down(Key::W) {
+    direction.y -= 1.0;
+  }
+  if i.key_down(Key::S) {
+    direction.y += 1.0;
+  }
+  if i.key_down(Key::A) {
+    direction.x -= 1.0;
+  }
+  if i.key_down(Key::D) {
+    direction.x += 1.0;
+  }
+  let mut movement = direction * PAN_SPEED * i.stable_dt;
+  if i.pointer.is_decidedly_dragging() {
+    movement -= i.pointer.delta();
+  }
+  if movement != Vec2::ZERO {
+    camera.center += movement / scale;
+    camera.following = None;
+  }
+}
+
+// The ship drawn under a point on the screen, or the closest one to it that is small enough
+fn ship_at(
+  ships: &HashMap<String, Ship>,
+  render_state: &RenderState,
+  point: Pos2,
+) -> Option<String> {
+  ships
+    .iter()
+    .map(|(name, ship)| {
+      let distance = render_state.transform(ship.coords).distance(point);
+      (name, distance)
+    })
+    .filter(|(name, distance)| {
+      *distance <= CLICK_RADIUS.max(render_state.scale(ships[*name].size) / 2.0)
+    })
+    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+    .map(|(name, _)| name.clone())
+}
+
+/// Turns what the player typed into a chat message, /t for the team and /w <name> to whisper
+fn parse_chat(input: &str) -> Option<ClientMessage> {
+  let input = input.trim();
+  let (scope, text) = if let Some(text) = input.strip_prefix("/t ") {
+    (ChatScope::Team, text)
+  } else if let Some(rest) = input.strip_prefix("/w ") {
+    let (name, text) = rest.trim_start().split_once(' ')?;
+    (ChatScope::Whisper(name.to_owned()), text)
+  } else {
+    (ChatScope::Global, input)
+  };
+  let text = text.trim();
+  if text.is_empty() {
+    return None;
+  }
+  Some(ClientMessage::Chat(scope, text.to_owned()))
+}
+
+/// Shows recent chat, or all of it and a box to type in while the chat is open
+fn draw_chat(ctx: &Context, data: &mut MidwayData) {
+  let chat = &mut data.chat;
+  let now = Instant::now();
+  Area::new(Id::new("chat"))
+    .anchor(Align2::LEFT_BOTTOM, vec2(CHAT_LEFT, -TOAST_MARGIN))
+    .show(ctx, |ui| {
+      ui.set_width(CHAT_WIDTH);
+      if !chat.open {
+        let recent: Vec<_> = chat
+          .lines
+          .iter()
+          .rev()
+          .take(CHAT_RECENT_LINES)
+          .take_while(|line| now < line.received + CHAT_DURATION)
+          .collect();
+        for line in recent.into_iter().rev() {
+          ui.colored_label(line.colour, &line.text);
         }
-        if i.key_down(Key::S) {
-          data.ship_data.power -= 0.01;
+        return;
+      }
+      ScrollArea::vertical()
+        .max_height(200.0)
+        .stick_to_bottom(true)
+        .show(ui, |ui| {
+          for line in &chat.lines {
+            ui.colored_label(line.colour, &line.text);
+          }
+        });
+      let response = ui.add(
+        TextEdit::singleline(&mut chat.input)
+          .char_limit(MAX_CHAT_LENGTH)
+          .desired_width(CHAT_WIDTH)
+          .hint_text("/t to your team, /w <name> to whisper"),
+      );
+      // Enter sends, escape or clicking away closes the chat
+      if response.lost_focus() {
+        if ui.input(|i| i.key_pressed(Key::Enter)) {
+          if let Some(message) = parse_chat(&chat.input) {
+            data.connection.send(&message).ok();
+          }
+          chat.input.clear();
         }
-        data.ship_data.power = data.ship_data.power.clamp(-0.5, 1.0);
+        chat.open = false;
+      } else {
+        response.request_focus();
       }
-      _ => (),
-    };
-    if i.key_down(Key::V) {
-      data.stream.write_all(b"anchor\n").ok();
-    }
-    if i.key_pressed(Key::Backtick) {
-      data.stream.write_all(b"smoke\n").ok();
-    }
-    if i.key_pressed(Key::Num1) {
-      data.stream.write_all(b"action 1\n").ok();
-    }
-    if i.key_pressed(Key::Num2) {
-      data.stream.write_all(b"action 2\n").ok();
-    }
-    if i.key_pressed(Key::Num3) {
-      data.stream.write_all(b"action 3\n").ok();
-    }
-    if i.key_pressed(Key::Num4) {
-      data.stream.write_all(b"action 4\n").ok();
-    }
-    if i.key_pressed(Key::Num5) {
-      data.stream.write_all(b"action 5\n").ok();
+    });
+}
+
+/// Shows the round trip time, how much is arriving and how well the server is keeping up
+fn draw_network(painter: &Painter, network: &Network, top_right: Pos2) {
+  let rtt = network.rtt.map_or("RTT unknown".to_owned(), |rtt| {
+    format!("RTT {} ms", rtt.as_millis())
+  });
+  let rates = format!(
+    "{:.0} messages/s, {:.0} snapshots/s",
+    network.message_rate, network.snapshot_rate
+  );
+  let (server, health) = match network.tick_rate {
+    Some(rate) => {
+      let tps = network.tps as f32;
+      // Red once ticks are being dropped or about to be
+      let health = if rate < tps * 0.9 || network.load > 0.9 {
+        Color32::RED
+      } else if network.load > 0.6 {
+        Color32::YELLOW
+      } else {
+        Color32::GREEN
+      };
+      let load = network.load * 100.0;
+      let text = format!("Server {rate:.0}/{tps} ticks/s, {load:.0}% load");
+      (text, health)
     }
-    if i.key_pressed(Key::Num6) {
-      data.stream.write_all(b"action 6\n").ok();
+    None => ("Server health unknown".to_owned(), Color32::GRAY),
+  };
+  let lines = [
+    (rtt, Color32::WHITE),
+    (rates, Color32::WHITE),
+    (server, health),
+  ];
+  for (i, (text, colour)) in lines.into_iter().enumerate() {
+    painter.text(
+      top_right + vec2(0.0, 18.0 * i as f32),
+      Align2::RIGHT_TOP,
+      text,
+      FontId::proportional(16.0),
+      colour,
+    );
+  }
+}
+
+/// Shows which way the wind blows, with its speed and the sea state alongside
+fn draw_wind(painter: &Painter, direction: f32, speed: f32, sea_state: u8, center: Pos2) {
+  painter.circle_stroke(center, 20.0, (1.0, Color32::WHITE));
+  let (sin, cos) = direction.sin_cos();
+  let downwind = vec2(sin, -cos);
+  painter.arrow(
+    center - downwind * 16.0,
+    downwind * 32.0,
+    (2.0, Color32::WHITE),
+  );
+  painter.text(
+    center + vec2(28.0, 0.0),
+    Align2::LEFT_CENTER,
+    format!("Wind {:.0} kt, sea state {sea_state}", speed * 2.0),
+    FontId::proportional(16.0),
+    Color32::WHITE,
+  );
+}
+
+// Mixes a seed into a well spread number, so whitecaps land in the same places every frame
+const fn scatter(seed: u64) -> u64 {
+  let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  z ^ (z >> 31)
+}
+
+/// Flecks of foam over the visible sea, crosswise to the wind and thicker the rougher it is
+fn draw_whitecaps(
+  painter: &Painter,
+  render_state: &RenderState,
+  area: Rect,
+  direction: f32,
+  sea_state: u8,
+  time: f32,
+) {
+  if sea_state < ROUGH_SEA || render_state.scale(WHITECAP_CELL) < MIN_WHITECAP_CELL {
+    return;
+  }
+  let per_cell = u64::from(sea_state - ROUGH_SEA + 1) * 2;
+  let half_length = render_state.scale(f32::from(sea_state) * 3.0);
+  let (sin, cos) = direction.sin_cos();
+  // Crests run across the wind
+  let crest = vec2(cos, sin) * half_length;
+  let cells_x =
+    (area.left() / WHITECAP_CELL).floor() as i64..=(area.right() / WHITECAP_CELL) as i64;
+  let cells_y =
+    (area.top() / WHITECAP_CELL).floor() as i64..=(area.bottom() / WHITECAP_CELL) as i64;
+  for cell_x in cells_x {
+    for cell_y in cells_y.clone() {
+      let cell = scatter((cell_x as u64) << 32 ^ cell_y as u32 as u64);
+      for i in 0..per_cell {
+        // Each whitecap breaks at its own moment, fades, then breaks somewhere else in the cell
+        let phase = (scatter(cell ^ i) % 1000) as f32 / 1000.0;
+        let age = time / WHITECAP_TIME + phase;
+        let generation = age as u64;
+        let spot = scatter(cell ^ i ^ generation.wrapping_mul(0x1_0000_0001));
+        let x = (spot & 0xffff) as f32 / 65536.0;
+        let y = (spot >> 16 & 0xffff) as f32 / 65536.0;
+        let position = pos2(
+          (cell_x as f32 + x) * WHITECAP_CELL,
+          (cell_y as f32 + y) * WHITECAP_CELL,
+        );
+        let center = render_state.transform(position);
+        let colour = Color32::from_white_alpha((200.0 * (1.0 - age.fract())) as u8);
+        painter.line_segment(
+          [center - crest, center + crest],
+          PathStroke::new(2.0, colour),
+        );
+      }
     }
-    if i.key_pressed(Key::Num7) {
-      data.stream.write_all(b"action 7\n").ok();
+  }
+}
+
+// Fails with the reason the server gave, or None if the connection was lost
+fn draw_midway(ui: &Ui, data: &mut MidwayData) -> Result<(), Option<String>> {
+  let screen_size = ui.clip_rect().right_bottom();
+  ui.ctx().input(|i| {
+    // Keys typed into the chat mustn't steer the ship as well, which holds its course meanwhile
+    if data.chat.open {
+      return;
     }
-    if i.key_pressed(Key::Num8) {
-      data.stream.write_all(b"action 9\n").ok();
+    if i.key_pressed(Key::Enter) {
+      data.chat.open = true;
     }
-    if i.key_pressed(Key::Num9) {
-      data.stream.write_all(b"action 9\n").ok();
+    if i.key_pressed(Key::F3) {
+      data.network.overlay = !data.network.overlay;
     }
-    if i.key_pressed(Key::Num0) {
-      data.stream.write_all(b"action 10\n").ok();
+    match &mut data.camera {
+      Some(camera) => control_camera(i, camera, 0.9_f32.powi(data.scale)),
+      None => control_ship(i, data),
     }
     if (data.scale < 25) && i.key_pressed(Key::Minus) {
       data.scale += 1;
@@ -263,41 +946,180 @@ fn draw_midway(ui: &Ui, data: &mut MidwayData) -> Option<()> {
       data.scale -= 1;
     }
   });
-  data
-    .stream
-    .write_all(format!("sail {} {}\n", data.ship_data.power, data.ship_data.helm).as_bytes())
-    .ok()?;
-  for message in data.rx.try_iter() {
+  if data.camera.is_none() {
+    let sail = ClientMessage::Sail(data.ship_data.power, data.ship_data.helm);
+    data.connection.send(&sail).map_err(|_| None)?;
+  }
+  if data.connection.ping && Instant::now() >= data.network.next_ping {
+    let ping = data.network.ping();
+    data.connection.send(&ping).map_err(|_| None)?;
+  }
+  loop {
+    let message = match data.rx.try_recv() {
+      Ok(message) => message,
+      Err(TryRecvError::Empty) => break,
+      Err(TryRecvError::Disconnected) => return Err(None),
+    };
+    data.network.count(&message);
+    let now = Instant::now();
     match message {
-      MidwayMessage::Ship(name, position) => {
-        data.ships.insert(name.to_string(), position);
+      // They would undo what a newer snapshot did
+      ServerMessage::Ship(_)
+      | ServerMessage::Appear(_)
+      | ServerMessage::Disappear(_)
+      | ServerMessage::Sunk(_)
+        if data.stale => {}
+      ServerMessage::Ship(ship) | ServerMessage::Appear(ship) => {
+        let time = data.stamp.unwrap_or(now);
+        if ship.name == data.name && data.camera.is_none() {
+          let ShipData { power, helm } = data.ship_data;
+          let (yaw_rate, drift) = data.turn;
+          let server = Motion {
+            yaw_rate,
+            drift,
+            ..motion(&ship)
+          };
+          match (&mut data.prediction, data.hull, data.timeline.clock) {
+            (Some(prediction), ..) => {
+              let age = data.network.rtt.unwrap_or_default();
+              prediction.correct(server, age, power, helm);
+            }
+            (None, Some(hull), Some(clock)) => {
+              let machinery = data.machinery.unwrap_or_default();
+              let mut prediction = Prediction::new(hull, clock, server, machinery, now);
+              prediction.current = data.current;
+              data.prediction = Some(prediction);
+            }
+            (None, ..) => (),
+          }
+        }
+        match data.ships.get_mut(&ship.name) {
+          Some(existing) => existing.update(&ship, time),
+          None => {
+            data.ships.insert(ship.name.clone(), Ship::new(&ship, time));
+          }
+        }
       }
-      MidwayMessage::Sunk(name) => {
+      ServerMessage::Sunk(name) | ServerMessage::Disappear(name) => {
+        // Whatever comes back under this name starts afresh
+        if name == data.name {
+          data.prediction = None;
+        }
         data.ships.remove(&name);
       }
-      MidwayMessage::Radius(radius) => data.radius = Some(radius),
-      MidwayMessage::Splash(x, y, size, duration, texture, colour) => data.splashes.push((
-        x,
-        y,
-        size,
-        Instant::now() + Duration::from_secs_f32(duration),
-        texture,
-        colour,
-      )),
-      MidwayMessage::Wake(x, y, size, angle, duration, velocity) => {
-        data
-          .wakes
-          .push((x, y, size, angle, Instant::now(), duration, velocity))
+      ServerMessage::Clock(tps, acceleration) => {
+        data.timeline.clock = Some(Clock { tps, acceleration });
+      }
+      ServerMessage::Hull(hull) => {
+        data.hull = Some(hull);
+        if let Some(prediction) = &mut data.prediction {
+          prediction.hull = hull;
+        }
+      }
+      ServerMessage::Machinery(machinery) => {
+        data.machinery = Some(machinery);
+        if let Some(prediction) = &mut data.prediction {
+          prediction.server_machinery = machinery;
+        }
+      }
+      ServerMessage::Turn(yaw_rate, drift) => data.turn = (yaw_rate, drift),
+      ServerMessage::Current(x, y) => {
+        data.current = (x, y);
+        if let Some(prediction) = &mut data.prediction {
+          prediction.current = (x, y);
+        }
+      }
+      ServerMessage::Radius(radius) => data.radius = Some(radius),
+      ServerMessage::Weather(direction, speed, sea_state) => {
+        data.weather = Some((direction, speed, sea_state));
+      }
+      ServerMessage::Splash(splash) => data.splashes.push((splash, now)),
+      ServerMessage::Wake(wake) => data.wakes.push((wake, now)),
+      ServerMessage::Say(text) => data
+        .messages
+        .push((text, Instant::now() + MESSAGE_DURATION)),
+      ServerMessage::Notice(text) => data.toasts.push(Toast::new(text, Color32::WHITE)),
+      ServerMessage::Error(_, text) => data
+        .toasts
+        .push(Toast::new(text, Color32::from_rgb(255, 96, 96))),
+      ServerMessage::Chat(scope, from, text) => {
+        let (text, colour) = match scope {
+          ChatScope::Global => (format!("{from}: {text}"), Color32::WHITE),
+          ChatScope::Team => (format!("[team] {from}: {text}"), Color32::LIGHT_BLUE),
+          ChatScope::Whisper(to) if from == data.name => (format!("to {to}: {text}"), WHISPER),
+          ChatScope::Whisper(_) => (format!("{from} whispers: {text}"), WHISPER),
+        };
+        data.chat.push(text, colour);
+      }
+      // Measured when the frame gets to it, which is when the player would see a response
+      ServerMessage::Pong(timestamp) => data.network.pong(timestamp),
+      ServerMessage::Status(tick, tps, load) => data.network.status(tick, tps, load),
+      ServerMessage::Welcome(name) => data.name = name,
+      ServerMessage::Reject(reason) => return Err(Some(reason)),
+      ServerMessage::Session(token) => data.session = Some(token),
+      ServerMessage::Snapshot(tick, base) => {
+        // Ones too big for a datagram come the reliable way, and can be overtaken by later ones
+        data.stale = data
+          .snapshot
+          .is_some_and(|applied| tick.wrapping_sub(applied) as i32 <= 0);
+        if !data.stale {
+          data.snapshot = Some(tick);
+          // A full resync replaces every ship
+          if base.is_none() {
+            data.ships.clear();
+            data.prediction = None;
+          }
+          data.stamp = Some(data.timeline.arrive(tick, now));
+          data.connection.send(&ClientMessage::Ack(tick)).ok();
+        }
+      }
+      // Only sent during the handshake, or handled by the connection thread
+      ServerMessage::Hello(..) | ServerMessage::Server(_) | ServerMessage::Udp(_) => (),
+    };
+  }
+  // Other ships are drawn a little in the past so there is a state either side of them
+  let now = Instant::now();
+  let render_time = data.timeline.render_time(now);
+  for (name, ship) in &mut data.ships {
+    let motion = match &mut data.prediction {
+      Some(prediction) if *name == data.name => {
+        let ShipData { power, helm } = data.ship_data;
+        prediction.advance(now, power, helm);
+        prediction.drawn()
       }
+      _ => ship.track.sample(render_time, data.timeline.clock),
     };
+    ship.show(motion);
   }
   let painter = ui.painter();
-  let ship_coords = match data.ships.get(&data.name) {
-    Some(ship) => ship.coords,
-    None => Pos2::ZERO,
+  let ship_coords = match &mut data.camera {
+    Some(camera) => {
+      // A followed ship that has sunk is picked up again when it respawns
+      let followed = camera
+        .following
+        .as_ref()
+        .and_then(|name| data.ships.get(name));
+      if let Some(ship) = followed {
+        camera.center = ship.coords;
+      }
+      camera.center
+    }
+    None => match data.ships.get(&data.name) {
+      Some(ship) => ship.coords,
+      None => Pos2::ZERO,
+    },
   };
   let scale = 0.9_f32.powi(data.scale);
   let render_state = RenderState::new(scale, ship_coords, screen_size / 2.0);
+  if let Some(camera) = &mut data.camera {
+    ui.ctx().input(|i| {
+      if i.pointer.primary_clicked() {
+        if let Some(point) = i.pointer.interact_pos() {
+          camera.following = ship_at(&data.ships, &render_state, point);
+        }
+      }
+    });
+  }
   let top_left = render_state.reverse_transform(Pos2::ZERO);
   let bottom_right = render_state.reverse_transform(screen_size);
   // Show the map
@@ -325,19 +1147,27 @@ fn draw_midway(ui: &Ui, data: &mut MidwayData) -> Option<()> {
     let Pos2 { x: _, y } = render_state.transform(pos2(0.0, y));
     painter.hline(0.0..=screen_size.x, y, PathStroke::new(2.0, Color32::BLUE));
   }
+  if let Some((direction, _, sea_state)) = data.weather {
+    let time = data.network.epoch.elapsed().as_secs_f32();
+    let area = Rect::from_min_max(top_left, bottom_right);
+    draw_whitecaps(painter, &render_state, area, direction, sea_state, time);
+  }
   // Wakes
-  data
-    .wakes
-    .retain(|(x, y, size, angle, instant, duration, velocity)| {
-      let coords = render_state.transform(pos2(*x, *y));
-      let elapsed = instant.elapsed().as_secs_f32();
-      let scale = render_state.scale(*size + velocity * elapsed);
-      let rect = Rect::from_center_size(coords, Vec2::splat(scale));
-      Image::new(WAKE)
-        .rotate(*angle, Vec2::splat(0.5))
-        .paint_at(ui, rect);
-      elapsed <= *duration
-    });
+  data.wakes.retain(|(wake, arrival)| {
+    let elapsed = now.saturating_duration_since(*arrival).as_secs_f32();
+    let (drift_x, drift_y) = wake.drift;
+    let position = pos2(
+      drift_x.mul_add(elapsed, wake.x),
+      drift_y.mul_add(elapsed, wake.y),
+    );
+    let coords = render_state.transform(position);
+    let scale = render_state.scale(wake.growth.mul_add(elapsed, wake.size));
+    let rect = Rect::from_center_size(coords, Vec2::splat(scale));
+    Image::new(WAKE)
+      .rotate(wake.angle, Vec2::splat(0.5))
+      .paint_at(ui, rect);
+    elapsed <= wake.duration
+  });
   // Ships
   for (ship, data) in &data.ships {
     let coords = render_state.transform(data.coords);
@@ -371,18 +1201,22 @@ fn draw_midway(ui: &Ui, data: &mut MidwayData) -> Option<()> {
     }
   }
   // Splashes
-  let now = Instant::now();
-  data
-    .splashes
-    .retain(|(x, y, size, duration, texture, colour)| {
-      let coords = render_state.transform(pos2(*x, *y));
-      let scale = render_state.scale(*size);
-      let rect = Rect::from_center_size(coords, Vec2::splat(scale));
-      Image::new(SPRITES[*texture].clone())
-        .tint(*colour)
-        .paint_at(ui, rect);
-      now < *duration
-    });
+  data.splashes.retain(|(splash, arrival)| {
+    let elapsed = now.saturating_duration_since(*arrival).as_secs_f32();
+    let (drift_x, drift_y) = splash.drift;
+    let position = pos2(
+      drift_x.mul_add(elapsed, splash.x),
+      drift_y.mul_add(elapsed, splash.y),
+    );
+    let coords = render_state.transform(position);
+    let scale = render_state.scale(splash.size);
+    let rect = Rect::from_center_size(coords, Vec2::splat(scale));
+    let sprite = SPRITES.get(splash.sprite).unwrap_or(&SPRITES[0]);
+    Image::new(sprite.clone())
+      .tint(to_color32(splash.colour))
+      .paint_at(ui, rect);
+    elapsed < splash.duration
+  });
   // Location
   let latitude = match ship_coords.y.total_cmp(&0.0) {
     Ordering::Greater => {
@@ -421,6 +1255,66 @@ fn draw_midway(ui: &Ui, data: &mut MidwayData) -> Option<()> {
     FontId::proportional(20.0),
     Color32::WHITE,
   );
+  if let Some(camera) = &data.camera {
+    let status = match &camera.following {
+      Some(name) => format!("Spectating {name}, click the water or move to look around"),
+      None => "Spectating, click a ship to follow it".to_owned(),
+    };
+    painter.text(
+      pos2(0.0, 24.0),
+      Align2::LEFT_TOP,
+      status,
+      FontId::proportional(16.0),
+      Color32::WHITE,
+    );
+  }
+  painter.text(
+    pos2(screen_size.x, 0.0),
+    Align2::RIGHT_TOP,
+    &data.server_name,
+    FontId::proportional(20.0),
+    Color32::WHITE,
+  );
+  if data.network.overlay {
+    draw_network(painter, &data.network, pos2(screen_size.x, 24.0));
+  }
+  if let Some((direction, speed, sea_state)) = data.weather {
+    draw_wind(painter, direction, speed, sea_state, pos2(30.0, 80.0));
+  }
+  // Toasts stack upwards from the corner, newest at the bottom
+  data.toasts.retain(|toast| now < toast.expiry);
+  let mut bottom = screen_size.y - TOAST_MARGIN;
+  for toast in data.toasts.iter().rev() {
+    let galley = painter.layout(
+      toast.text.clone(),
+      FontId::proportional(16.0),
+      toast.colour,
+      screen_size.x / 3.0,
+    );
+    let rect = Align2::RIGHT_BOTTOM.anchor_size(
+      pos2(screen_size.x - TOAST_MARGIN, bottom),
+      galley.size() + vec2(TOAST_PADDING, TOAST_PADDING) * 2.0,
+    );
+    painter.rect_filled(rect, Rounding::same(4.0), Color32::from_black_alpha(192));
+    painter.galley(
+      rect.min + vec2(TOAST_PADDING, TOAST_PADDING),
+      galley,
+      toast.colour,
+    );
+    bottom = rect.top() - TOAST_MARGIN;
+  }
+  draw_chat(ui.ctx(), data);
+  // Messages from the server
+  data.messages.retain(|(_, expiry)| now < *expiry);
+  for (i, (text, _)) in data.messages.iter().enumerate() {
+    painter.text(
+      pos2(screen_size.x / 2.0, 30.0 * i as f32),
+      Align2::CENTER_TOP,
+      text,
+      FontId::proportional(20.0),
+      Color32::YELLOW,
+    );
+  }
   if let Some(ship) = data.ships.get(&data.name) {
     // Speed
     painter.text(
@@ -458,155 +1352,128 @@ fn draw_midway(ui: &Ui, data: &mut MidwayData) -> Option<()> {
       }
       Ordering::Equal => (),
     }
+    // What the rudder and engine are actually doing, as far as anyone knows
+    let actual = data
+      .prediction
+      .as_ref()
+      .map(|prediction| prediction.machinery)
+      .or(data.machinery);
+    if let Some(Machinery { engine, .. }) = actual {
+      // On the scale of the orders, which are squared to get the engine output
+      let level = engine.abs().sqrt().copysign(engine);
+      let y = mid_throttle - 100.0 * level;
+      painter.line_segment(
+        [pos2(0.0, y), pos2(26.0, y)],
+        PathStroke::new(2.0, Color32::YELLOW),
+      );
+    }
+    // Rudder, filled to where it is with the order marked
+    let rudder_y = screen_size.y - 195.0;
+    let amidships = 60.0;
+    painter.line_segment(
+      [
+        pos2(amidships - 50.0, rudder_y),
+        pos2(amidships + 50.0, rudder_y),
+      ],
+      PathStroke::new(1.0, Color32::WHITE),
+    );
+    let rudder = actual.map_or(data.ship_data.helm, |machinery| machinery.rudder);
+    let rect = Rect::from_two_pos(
+      pos2(amidships, rudder_y - 4.0),
+      pos2(amidships + 50.0 * rudder, rudder_y + 4.0),
+    );
+    painter.rect_filled(rect, Rounding::ZERO, Color32::YELLOW);
+    let ordered = amidships + 50.0 * data.ship_data.helm;
+    painter.line_segment(
+      [pos2(ordered, rudder_y - 8.0), pos2(ordered, rudder_y + 8.0)],
+      PathStroke::new(2.0, Color32::WHITE),
+    );
   }
-  Some(())
+  Ok(())
 }
 
-fn handle_midway_connection(stream: TcpStream, tx: &Sender<MidwayMessage>) -> Option<()> {
+/// Sends the token until the connection closes, passing on datagrams that aren't stale
+fn handle_datagrams(
+  server: SocketAddr,
+  token: u64,
+  tx: &Sender<ServerMessage>,
+  stop: &Receiver<()>,
+) -> Option<()> {
+  let local = if server.is_ipv4() {
+    "0.0.0.0:0"
+  } else {
+    "[::]:0"
+  };
+  let socket = UdpSocket::bind(local).ok()?;
+  socket.connect(server).ok()?;
+  socket.set_read_timeout(Some(UDP_KEEPALIVE)).ok()?;
+  let mut token_sent: Option<Instant> = None;
+  let mut latest: Option<u32> = None;
+  let mut buf = [0; MAX_DATAGRAM_LENGTH];
+  while let Err(TryRecvError::Empty) = stop.try_recv() {
+    if token_sent.is_none_or(|sent| sent.elapsed() >= UDP_KEEPALIVE) {
+      socket.send(&token.to_be_bytes()).ok();
+      token_sent = Some(Instant::now());
+    }
+    let Ok(length) = socket.recv(&mut buf) else {
+      continue;
+    };
+    let Ok((sequence, frames)) = read_datagram(&buf[..length]) else {
+      continue;
+    };
+    // Anything that arrives after a later datagram is out of date
+    if latest.is_some_and(|latest| sequence.wrapping_sub(latest) as i32 <= 0) {
+      continue;
+    }
+    latest = Some(sequence);
+    let mut decoder = ServerDecoder::default();
+    for frame in frames {
+      match decoder.decode(frame) {
+        Ok(message) => tx.send(message).ok()?,
+        Err(error) => println!("{error}"),
+      }
+    }
+  }
+  None
+}
+
+fn handle_midway_connection(
+  stream: TcpStream,
+  tx: &Sender<ServerMessage>,
+  binary: bool,
+) -> Option<()> {
+  let server = stream.peer_addr().ok()?;
+  // The datagram thread stops once this is dropped
+  let (_stop, stop) = channel();
+  let mut stop = Some(stop);
+  let mut forward = |message: Result<ServerMessage, ParseError>| {
+    match message {
+      Ok(ServerMessage::Udp(token)) => {
+        if let Some(stop) = stop.take() {
+          let tx = tx.clone();
+          spawn(move || handle_datagrams(server, token, &tx, &stop));
+        }
+      }
+      Ok(message) => tx.send(message).ok()?,
+      Err(error) => println!("{error}"),
+    }
+    Some(())
+  };
   let mut stream = BufReader::new(stream);
+  if binary {
+    let mut decoder = ServerDecoder::default();
+    let mut frame = Vec::new();
+    while read_frame(&mut stream, &mut frame).is_ok() {
+      forward(decoder.decode(&frame))?;
+    }
+    return None;
+  }
   let mut buf = String::new();
   while let Ok(chars) = stream.read_line(&mut buf) {
     if chars == 0 {
       None?;
     }
-    let mut words = buf.split_whitespace();
-    match words.next() {
-      Some("ship") => {
-        let Some(name) = words.next() else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(x) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(y) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let coords = pos2(x, y);
-        let Some(angle) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(velocity) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let size = words.next().and_then(|w| w.parse().ok()).unwrap_or(60.0);
-        let mut texture = words.next().and_then(|w| w.parse().ok()).unwrap_or(0);
-        if texture >= SHIP_TEXTURES.len() {
-          texture = 0;
-        }
-        let colour = words
-          .next()
-          .and_then(|w| Color32::from_hex(w).ok())
-          .unwrap_or(Color32::GRAY);
-        let Some(health) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let ship = Ship {
-          coords,
-          angle,
-          velocity,
-          texture,
-          colour,
-          size,
-          health,
-        };
-        tx.send(MidwayMessage::Ship(name.to_string(), ship)).ok()?;
-      }
-      Some("sunk") => {
-        let Some(name) = words.next() else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        tx.send(MidwayMessage::Sunk(name.to_string())).ok()?;
-      }
-      Some("radius") => {
-        let Some(radius) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        tx.send(MidwayMessage::Radius(radius)).ok()?;
-      }
-      Some("splash") => {
-        let Some(x) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(y) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(size) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(duration) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let mut texture = words.next().and_then(|w| w.parse().ok()).unwrap_or(0);
-        if texture >= SPRITES.len() {
-          texture = 0;
-        }
-        let colour = words
-          .next()
-          .and_then(|w| Color32::from_hex(w).ok())
-          .unwrap_or(Color32::WHITE);
-        tx.send(MidwayMessage::Splash(x, y, size, duration, texture, colour))
-          .ok()?;
-      }
-      Some("wake") => {
-        let Some(x) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(y) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(size) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(angle) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(duration) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        let Some(velocity) = words.next().and_then(|w| w.parse().ok()) else {
-          println!("Invalid input");
-          buf.clear();
-          continue;
-        };
-        tx.send(MidwayMessage::Wake(x, y, size, angle, duration, velocity))
-          .ok()?;
-      }
-      _ => println!("Unknown line"),
-    }
+    forward(buf.parse())?;
     buf.clear();
   }
   None
@@ -0,0 +1,461 @@
+//! Smooths out where ships are drawn, since their states arrive in uneven bursts
+//!
+//! Other ships are drawn a short delay behind the newest state, between the states either side of
+//! that moment, so there is nearly always something to move towards. The player's own ship is
+//! simulated here with the same physics as the server, and eased towards what the server says.
+use physics::{throttle, FixedStep, Hull, Machinery, Motion};
+use std::collections::VecDeque;
+use std::f32::consts::{PI, TAU};
+use std::time::{Duration, Instant};
+
+// Never drawn further behind than this, however bad the connection
+const MIN_DELAY: f32 = 0.02;
+const MAX_DELAY: f32 = 0.5;
+// How long a ship keeps going on its own once its updates stop
+const MAX_EXTRAPOLATION: f32 = 0.5;
+// How quickly the lateness estimates follow changes in the connection
+const SMOOTHING: f32 = 0.1;
+// Lets the timeline catch up with a server that ticks slower than it should
+const ORIGIN_CREEP: f32 = 0.05;
+const MAX_SAMPLES: usize = 64;
+// Seconds for most of a correction to the player's own ship to be drawn
+const CORRECTION_TIME: f32 = 0.2;
+// Further off than this and the ship jumps straight to where it should be
+const SNAP_DISTANCE: f32 = 100.0;
+const SNAP_ANGLE: f32 = 1.0;
+// Most ticks simulated at once, in case the window wasn't drawn for a while
+const MAX_STEPS: u32 = 60;
+
+// Shifts an instant by a number of seconds that could be negative
+fn offset(instant: Instant, seconds: f32) -> Instant {
+  let duration = Duration::from_secs_f32(seconds.abs());
+  let shifted = if seconds < 0.0 {
+    instant.checked_sub(duration)
+  } else {
+    instant.checked_add(duration)
+  };
+  shifted.unwrap_or(instant)
+}
+
+// Seconds from one instant to another, negative if the second is earlier
+fn seconds_between(from: Instant, to: Instant) -> f32 {
+  match to.checked_duration_since(from) {
+    Some(duration) => duration.as_secs_f32(),
+    None => -from.duration_since(to).as_secs_f32(),
+  }
+}
+
+// The shortest way to turn from one angle to another
+fn angle_difference(from: f32, to: f32) -> f32 {
+  (to - from + PI).rem_euclid(TAU) - PI
+}
+
+fn lerp(from: Motion, to: Motion, t: f32) -> Motion {
+  Motion {
+    coords: (
+      from.coords.0 + (to.coords.0 - from.coords.0) * t,
+      from.coords.1 + (to.coords.1 - from.coords.1) * t,
+    ),
+    angle: from.angle + angle_difference(from.angle, to.angle) * t,
+    velocity: from.velocity + (to.velocity - from.velocity) * t,
+    yaw_rate: from.yaw_rate + (to.yaw_rate - from.yaw_rate) * t,
+    drift: from.drift + (to.drift - from.drift) * t,
+  }
+}
+
+/// How the server's ticks line up with real time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Clock {
+  pub tps: u32,
+  // Seconds of game time in each real one
+  pub acceleration: f32,
+}
+
+impl Clock {
+  // Real seconds
+  pub fn tick_length(self) -> f32 {
+    1.0 / self.tps as f32
+  }
+
+  // Game seconds
+  pub fn delta_t(self) -> f32 {
+    self.acceleration / self.tps as f32
+  }
+}
+
+/// Works out when each tick happened in local time, and how far behind to draw other ships
+pub struct Timeline {
+  // None until the server says, which old servers don't
+  pub clock: Option<Clock>,
+  // A tick, and when it would have arrived if nothing was ever late
+  origin: Option<(u32, Instant)>,
+  // Average seconds that snapshots arrive after the origin says they should
+  lateness: f32,
+  // Average difference from that
+  jitter: f32,
+}
+
+impl Timeline {
+  pub const fn new() -> Self {
+    Self {
+      clock: None,
+      origin: None,
+      lateness: 0.0,
+      jitter: 0.0,
+    }
+  }
+
+  fn tick_length(&self) -> f32 {
+    self.clock.map_or(1.0 / 60.0, Clock::tick_length)
+  }
+
+  /// Notes that a snapshot arrived, returning when its tick happened in local time
+  pub fn arrive(&mut self, tick: u32, now: Instant) -> Instant {
+    let Some((first, mut origin)) = self.origin else {
+      self.origin = Some((tick, now));
+      return now;
+    };
+    let ticks = tick.wrapping_sub(first) as i32;
+    let mut expected = offset(origin, ticks as f32 * self.tick_length());
+    let mut late = seconds_between(expected, now);
+    if late < 0.0 {
+      // Nothing has arrived this quickly before
+      origin = offset(origin, late);
+      expected = now;
+      late = 0.0;
+    } else {
+      origin = offset(origin, late * ORIGIN_CREEP);
+    }
+    self.origin = Some((first, origin));
+    self.jitter += SMOOTHING * ((late - self.lateness).abs() - self.jitter);
+    self.lateness += SMOOTHING * (late - self.lateness);
+    expected
+  }
+
+  /// How far behind the newest state other ships are drawn, enough to cover a late snapshot
+  pub fn delay(&self) -> Duration {
+    let delay = 2.0f32.mul_add(self.jitter, self.tick_length() + self.lateness);
+    Duration::from_secs_f32(delay.clamp(MIN_DELAY, MAX_DELAY))
+  }
+
+  pub fn render_time(&self, now: Instant) -> Instant {
+    now.checked_sub(self.delay()).unwrap_or(now)
+  }
+}
+
+/// Recent states of a ship, in the order they happened
+pub struct Track {
+  samples: VecDeque<(Instant, Motion)>,
+}
+
+impl Track {
+  pub fn new(time: Instant, motion: Motion) -> Self {
+    Self {
+      samples: VecDeque::from([(time, motion)]),
+    }
+  }
+
+  pub fn push(&mut self, time: Instant, motion: Motion) {
+    // Whatever arrived later but happened after this is kept, this goes in before it
+    let index = self.samples.partition_point(|(sample, _)| *sample <= time);
+    if index > 0 && self.samples[index - 1].0 == time {
+      self.samples[index - 1].1 = motion;
+    } else {
+      self.samples.insert(index, (time, motion));
+    }
+    if self.samples.len() > MAX_SAMPLES {
+      self.samples.pop_front();
+    }
+  }
+
+  /// Where the ship was at a time, guessing along its heading if that is after the newest state
+  ///
+  /// Without a clock there is no telling how fast it goes in real time, so it stays put instead.
+  pub fn sample(&mut self, time: Instant, clock: Option<Clock>) -> Motion {
+    // Only the last state before the time is needed from now on
+    while self.samples.len() > 1 && self.samples[1].0 <= time {
+      self.samples.pop_front();
+    }
+    let (first_time, first) = self.samples[0];
+    let Some(&(next_time, next)) = self.samples.get(1) else {
+      let Some(clock) = clock else {
+        return first;
+      };
+      let elapsed = seconds_between(first_time, time).clamp(0.0, MAX_EXTRAPOLATION);
+      let distance = first.velocity * clock.acceleration * elapsed;
+      return Motion {
+        coords: (
+          distance.mul_add(first.angle.sin(), first.coords.0),
+          (-distance).mul_add(first.angle.cos(), first.coords.1),
+        ),
+        ..first
+      };
+    };
+    if time <= first_time {
+      return first;
+    }
+    let t = seconds_between(first_time, time) / seconds_between(first_time, next_time);
+    lerp(first, next, t)
+  }
+}
+
+/// The player's own ship, moved as soon as it is steered instead of a round trip later
+pub struct Prediction {
+  pub hull: Hull,
+  clock: Clock,
+  motion: Motion,
+  pub machinery: Machinery,
+  // As of the last state from the server, which the corrections start from
+  pub server_machinery: Machinery,
+  // The current the ship is being carried along by, in metres per second
+  pub current: (f32, f32),
+  // What is drawn is this far from the simulated ship, shrinking away to nothing
+  offset: Motion,
+  ticks: FixedStep,
+  last: Instant,
+}
+
+impl Prediction {
+  pub fn new(hull: Hull, clock: Clock, motion: Motion, machinery: Machinery, now: Instant) -> Self {
+    Self {
+      hull,
+      clock,
+      motion,
+      machinery,
+      server_machinery: machinery,
+      current: (0.0, 0.0),
+      offset: Motion::default(),
+      ticks: FixedStep::new(clock.tick_length()),
+      last: now,
+    }
+  }
+
+  fn run(&self, motion: &mut Motion, machinery: &mut Machinery, power: f32, helm: f32, ticks: u32) {
+    let delta_t = self.clock.delta_t();
+    let ticks = ticks.min(MAX_STEPS);
+    self
+      .hull
+      .run(motion, machinery, throttle(power), helm, delta_t, ticks);
+    // The server lets the water carry the ship once it has moved through it, tick by tick
+    let carried = delta_t * ticks as f32;
+    motion.coords.0 += self.current.0 * carried;
+    motion.coords.1 += self.current.1 * carried;
+  }
+
+  /// Simulates the time since the last frame, steered the way the server is being told to
+  pub fn advance(&mut self, now: Instant, power: f32, helm: f32) {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f32();
+    self.last = now;
+    let ticks = self.ticks.ticks(elapsed);
+    let (mut motion, mut machinery) = (self.motion, self.machinery);
+    self.run(&mut motion, &mut machinery, power, helm, ticks);
+    (self.motion, self.machinery) = (motion, machinery);
+    let decay = (-elapsed / CORRECTION_TIME).exp();
+    self.offset.coords.0 *= decay;
+    self.offset.coords.1 *= decay;
+    self.offset.angle *= decay;
+    self.offset.velocity *= decay;
+  }
+
+  /// Takes the server's word for where the ship is, as of age ago
+  ///
+  /// The steering since then hasn't reached the server yet, so it is simulated again on top.
+  pub fn correct(&mut self, server: Motion, age: Duration, power: f32, helm: f32) {
+    let drawn = self.drawn();
+    let ticks = (age.as_secs_f32() / self.clock.tick_length()).round() as u32;
+    let (mut motion, mut machinery) = (server, self.server_machinery);
+    self.run(&mut motion, &mut machinery, power, helm, ticks);
+    (self.motion, self.machinery) = (motion, machinery);
+    self.offset = Motion {
+      coords: (
+        drawn.coords.0 - motion.coords.0,
+        drawn.coords.1 - motion.coords.1,
+      ),
+      angle: angle_difference(motion.angle, drawn.angle),
+      velocity: drawn.velocity - motion.velocity,
+      ..Motion::default()
+    };
+    let distance = self.offset.coords.0.hypot(self.offset.coords.1);
+    if distance > SNAP_DISTANCE || self.offset.angle.abs() > SNAP_ANGLE {
+      self.offset = Motion::default();
+    }
+  }
+
+  pub fn drawn(&self) -> Motion {
+    Motion {
+      coords: (
+        self.motion.coords.0 + self.offset.coords.0,
+        self.motion.coords.1 + self.offset.coords.1,
+      ),
+      angle: self.motion.angle + self.offset.angle,
+      velocity: self.motion.velocity + self.offset.velocity,
+      ..self.motion
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CLOCK: Clock = Clock {
+    tps: 60,
+    acceleration: 4.0,
+  };
+
+  fn motion(x: f32, angle: f32, velocity: f32) -> Motion {
+    Motion {
+      coords: (x, 0.0),
+      angle,
+      velocity,
+      ..Motion::default()
+    }
+  }
+
+  fn hull() -> Hull {
+    Hull {
+      length: 93.3,
+      mass: 1_500_000.0,
+      power: 9_000_000.0,
+      surface_area: 2000.0,
+      screw_area: 5.0,
+      k: 0.2,
+      froude_scale_factor: 0.05,
+      turning_circle: 600.0,
+      rudder_time: 10.0,
+      spool_time: 20.0,
+      wave_height: 0.0,
+      submerged: false,
+    }
+  }
+
+  #[test]
+  fn ships_are_drawn_between_states() {
+    let start = Instant::now();
+    let mut track = Track::new(start, motion(0.0, 3.0, 0.0));
+    track.push(start + Duration::from_millis(100), motion(10.0, -3.0, 2.0));
+    let halfway = track.sample(start + Duration::from_millis(50), Some(CLOCK));
+    assert!((halfway.coords.0 - 5.0).abs() < 1e-3);
+    assert!((halfway.velocity - 1.0).abs() < 1e-3);
+    // The short way round, through pi rather than through 0
+    assert!((halfway.angle.abs() - PI).abs() < 1e-3);
+  }
+
+  #[test]
+  fn late_states_are_put_in_order() {
+    let start = Instant::now();
+    let mut track = Track::new(start, motion(0.0, 0.0, 0.0));
+    track.push(start + Duration::from_millis(200), motion(20.0, 0.0, 0.0));
+    track.push(start + Duration::from_millis(100), motion(10.0, 0.0, 0.0));
+    let sample = track.sample(start + Duration::from_millis(150), Some(CLOCK));
+    assert!((sample.coords.0 - 15.0).abs() < 1e-3);
+  }
+
+  #[test]
+  fn ships_keep_going_for_a_while() {
+    let start = Instant::now();
+    let mut track = Track::new(start, motion(0.0, PI / 2.0, 5.0));
+    let later = track.sample(start + Duration::from_millis(250), Some(CLOCK));
+    assert!((later.coords.0 - 5.0).abs() < 1e-3);
+    let much_later = track.sample(start + Duration::from_secs(10), Some(CLOCK));
+    assert!((much_later.coords.0 - 10.0).abs() < 1e-3);
+    let unknown = track.sample(start + Duration::from_secs(10), None);
+    assert_eq!(unknown.coords.0, 0.0);
+  }
+
+  #[test]
+  fn jitter_adds_delay() {
+    let start = Instant::now();
+    let tick = Duration::from_secs(1) / 60;
+    let mut steady = Timeline::new();
+    let mut jittery = Timeline::new();
+    for i in 0..120 {
+      let arrival = start + tick * i;
+      steady.arrive(i, arrival);
+      jittery.arrive(i, arrival + Duration::from_millis(u64::from(i % 3) * 20));
+    }
+    assert!(steady.delay() < Duration::from_millis(25));
+    assert!(jittery.delay() > steady.delay() + Duration::from_millis(20));
+    assert!(jittery.delay() <= Duration::from_secs_f32(MAX_DELAY));
+  }
+
+  #[test]
+  fn prediction_matches_the_server() {
+    let start = Instant::now();
+    let mut server = motion(0.0, 0.0, 0.0);
+    let mut prediction = Prediction::new(hull(), CLOCK, server, Machinery::default(), start);
+    let mut machinery = Machinery::default();
+    hull().run(
+      &mut server,
+      &mut machinery,
+      throttle(1.0),
+      0.5,
+      CLOCK.delta_t(),
+      60,
+    );
+    // A little over, so float rounding can't leave the last tick unsimulated
+    prediction.advance(start + Duration::from_millis(1001), 1.0, 0.5);
+    let predicted = prediction.drawn();
+    assert!((predicted.coords.0 - server.coords.0).abs() < 1e-2);
+    assert!((predicted.coords.1 - server.coords.1).abs() < 1e-2);
+    assert!(predicted.velocity > 0.0);
+  }
+
+  #[test]
+  fn corrections_are_eased_in() {
+    let start = Instant::now();
+    let mut prediction = Prediction::new(
+      hull(),
+      CLOCK,
+      motion(0.0, 0.0, 0.0),
+      Machinery::default(),
+      start,
+    );
+    prediction.correct(motion(10.0, 0.0, 0.0), Duration::ZERO, 0.0, 0.0);
+    assert_eq!(prediction.drawn().coords.0, 0.0);
+    prediction.advance(start + Duration::from_secs(1), 0.0, 0.0);
+    assert!((prediction.drawn().coords.0 - 10.0).abs() < 0.1);
+    // Too far out to slide across
+    prediction.correct(motion(1000.0, 0.0, 0.0), Duration::ZERO, 0.0, 0.0);
+    assert_eq!(prediction.drawn().coords.0, 1000.0);
+  }
+
+  #[test]
+  fn corrections_keep_ships_swinging() {
+    let start = Instant::now();
+    let mut prediction = Prediction::new(
+      hull(),
+      CLOCK,
+      motion(0.0, 0.0, 5.0),
+      Machinery::default(),
+      start,
+    );
+    // Still swinging from a turn the server has seen, with the rudder already amidships
+    let swinging = Motion {
+      yaw_rate: 0.05,
+      ..motion(0.0, 0.0, 5.0)
+    };
+    prediction.correct(swinging, Duration::from_millis(500), 0.0, 0.0);
+    prediction.advance(start + Duration::from_secs(1), 0.0, 0.0);
+    assert!(prediction.drawn().angle > 0.0);
+  }
+
+  #[test]
+  fn stopped_ships_are_carried_by_the_current() {
+    let start = Instant::now();
+    let mut prediction = Prediction::new(
+      hull(),
+      CLOCK,
+      motion(0.0, 0.0, 0.0),
+      Machinery::default(),
+      start,
+    );
+    prediction.current = (1.0, -0.5);
+    prediction.advance(start + Duration::from_secs(1), 0.0, 0.0);
+    let (x, y) = prediction.drawn().coords;
+    // A second's worth of ticks, give or take the one the fixed step may still be holding
+    let carried = CLOCK.acceleration;
+    let tick = CLOCK.delta_t();
+    assert!((x - carried).abs() <= tick && (y + carried / 2.0).abs() <= tick / 2.0);
+  }
+}
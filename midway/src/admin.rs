@@ -0,0 +1,316 @@
+//! Admin commands for operating a running server
+use crate::client::ClientData;
+use crate::config::Config;
+use crate::stats::{get_stats, ShipType};
+use crate::{Ship, KRAKEN_NAME};
+use enum_iterator::all;
+use protocol::{Capability, ServerMessage};
+use std::collections::{HashMap, HashSet};
+use std::io::stdin;
+use std::net::IpAddr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+const HELP: &str = "Commands:
+  list                          Show every ship in the water, and spectators
+  kick <name>                   Disconnect a player
+  ban <ip>                      Disconnect and refuse an address
+  say <text>                    Send a message to every player
+  mute <name>                   Stop a player's address from chatting
+  unmute <name|ip>              Let a muted address chat again
+  spawn-kraken <x> <y> <scale>  Release a kraken, scale 1 is the smallest
+  setship <name> <class>        Change the class of a player's ship
+  heal [name]                   Repair one player, or everyone
+  shutdown                      Stop the server";
+
+pub enum AdminCommand {
+  Help,
+  List,
+  Kick(String),
+  Ban(IpAddr),
+  Say(String),
+  Mute(String),
+  Unmute(String),
+  SpawnKraken(f32, f32, f32),
+  SetShip(String, ShipType),
+  Heal(Option<String>),
+  Shutdown,
+}
+
+pub struct AdminRequest {
+  pub command: AdminCommand,
+  pub reply: Sender<String>,
+}
+
+fn parse_number(word: Option<&str>, name: &str) -> Result<f32, String> {
+  let word = word.ok_or_else(|| format!("Missing {name}"))?;
+  match word.parse::<f32>() {
+    Ok(value) if value.is_finite() => Ok(value),
+    _ => Err(format!("Invalid {name} {word}")),
+  }
+}
+
+impl AdminCommand {
+  pub fn parse(line: &str) -> Result<Self, String> {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+      Some("help") => Self::Help,
+      Some("list") => Self::List,
+      Some("kick") => Self::Kick(words.next().ok_or("Usage: kick <name>")?.to_owned()),
+      Some("ban") => {
+        let address = words.next().ok_or("Usage: ban <ip>")?;
+        Self::Ban(
+          address
+            .parse()
+            .map_err(|_| format!("Invalid ip address {address}"))?,
+        )
+      }
+      Some("say") => {
+        let text = words.collect::<Vec<_>>().join(" ");
+        if text.is_empty() {
+          return Err("Usage: say <text>".to_owned());
+        }
+        return Ok(Self::Say(text));
+      }
+      Some("mute") => Self::Mute(words.next().ok_or("Usage: mute <name>")?.to_owned()),
+      Some("unmute") => Self::Unmute(words.next().ok_or("Usage: unmute <name|ip>")?.to_owned()),
+      Some("spawn-kraken") => {
+        let x = parse_number(words.next(), "x")?;
+        let y = parse_number(words.next(), "y")?;
+        let scale = parse_number(words.next(), "scale")?;
+        if scale < 1.0 {
+          return Err("The scale must be at least 1".to_owned());
+        }
+        Self::SpawnKraken(x, y, scale)
+      }
+      Some("setship") => {
+        let name = words.next().ok_or("Usage: setship <name> <class>")?;
+        let class = words.next().ok_or("Usage: setship <name> <class>")?;
+        let Some(class) = ShipType::from_name(class) else {
+          let classes: Vec<_> = all::<ShipType>().map(ShipType::name).collect();
+          return Err(format!(
+            "Unknown class {class}, expected one of {}",
+            classes.join(", ")
+          ));
+        };
+        Self::SetShip(name.to_owned(), class)
+      }
+      Some("heal") => Self::Heal(words.next().map(str::to_owned)),
+      Some("shutdown") => Self::Shutdown,
+      Some(word) => return Err(format!("Unknown command {word}, try help")),
+      None => return Err("Empty command".to_owned()),
+    };
+    if words.next().is_some() {
+      return Err("Too many arguments".to_owned());
+    }
+    Ok(command)
+  }
+}
+
+/// Reads admin commands from stdin until it is closed
+pub fn process_console(tx: &Sender<AdminRequest>) {
+  for line in stdin().lines() {
+    let Ok(line) = line else {
+      return;
+    };
+    if line.trim().is_empty() {
+      continue;
+    }
+    match AdminCommand::parse(&line) {
+      Ok(command) => {
+        let (reply, rx) = channel();
+        if tx.send(AdminRequest { command, reply }).is_err() {
+          return;
+        }
+        if let Ok(response) = rx.recv() {
+          println!("{response}");
+        }
+      }
+      Err(error) => println!("{error}"),
+    }
+  }
+}
+
+fn say(connections: &HashMap<String, ClientData>, text: &str) {
+  for connection in connections.values() {
+    if connection.supports(Capability::Say) {
+      connection.tx.send(ServerMessage::Say(text.to_owned()));
+    }
+  }
+}
+
+fn kick(connections: &mut HashMap<String, ClientData>, name: &str, reason: &str) {
+  if let Some(connection) = connections.remove(name) {
+    // Dropping the connection closes it once the message is sent
+    connection.notice(reason);
+    if connection.ship.is_some() {
+      for connection in connections.values_mut() {
+        connection.snapshots.sink(name);
+      }
+    }
+  }
+}
+
+fn describe(name: &str, address: &str, ship: &Ship) -> String {
+  let class = ship.class.map_or(KRAKEN_NAME, ShipType::name);
+  let health = ship.stats.health.max(0.0);
+  let max_health = ship.stats.mass.get_value(false);
+  let (x, y) = ship.coords;
+  let state = if ship.sunk {
+    " sunk"
+  } else if ship.submerged {
+    " submerged"
+  } else {
+    ""
+  };
+  format!("{name} {address} {class} {health:.0}/{max_health:.0} ({x:.0}, {y:.0}){state}")
+}
+
+fn run_command(
+  config: &Config,
+  connections: &mut HashMap<String, ClientData>,
+  kraken: &mut Option<Ship>,
+  bans: &mut HashSet<IpAddr>,
+  mutes: &mut HashSet<IpAddr>,
+  command: AdminCommand,
+) -> String {
+  match command {
+    AdminCommand::Help => HELP.to_owned(),
+    AdminCommand::List => {
+      let mut lines: Vec<_> = connections
+        .iter()
+        .map(|(name, connection)| {
+          // Ships waiting for their player to reconnect
+          let address = match connection.detached {
+            Some(_) => "disconnected",
+            None => &connection.address,
+          };
+          let mut line = match &connection.ship {
+            Some(ship) => describe(name, address, ship),
+            None => format!("{name} {address} spectating"),
+          };
+          if let Some(team) = connection.team.filter(|_| config.teams > 1) {
+            line.push_str(&format!(" team {team}"));
+          }
+          if connection.ip.is_some_and(|ip| mutes.contains(&ip)) {
+            line.push_str(" muted");
+          }
+          line
+        })
+        .collect();
+      lines.sort();
+      if let Some(kraken) = kraken {
+        lines.push(describe(KRAKEN_NAME, "-", kraken));
+      }
+      if lines.is_empty() {
+        "No ships in the water".to_owned()
+      } else {
+        lines.join("\n")
+      }
+    }
+    AdminCommand::Kick(name) => {
+      if connections.contains_key(&name) {
+        kick(connections, &name, "You have been kicked");
+        format!("Kicked {name}")
+      } else {
+        format!("No player called {name}")
+      }
+    }
+    AdminCommand::Ban(ip) => {
+      bans.insert(ip);
+      let banned: Vec<_> = connections
+        .iter()
+        .filter(|(_, connection)| connection.ip == Some(ip))
+        .map(|(name, _)| name.clone())
+        .collect();
+      for name in &banned {
+        kick(connections, name, "You have been banned");
+      }
+      if banned.is_empty() {
+        format!("Banned {ip}")
+      } else {
+        format!("Banned {ip}, kicked {}", banned.join(", "))
+      }
+    }
+    AdminCommand::Say(text) => {
+      say(connections, &text);
+      format!("Said {text}")
+    }
+    AdminCommand::Mute(name) => match connections.get(&name).map(|x| x.ip) {
+      Some(Some(ip)) => {
+        mutes.insert(ip);
+        format!("Muted {name} ({ip})")
+      }
+      Some(None) => format!("The address of {name} is unknown"),
+      None => format!("No player called {name}"),
+    },
+    AdminCommand::Unmute(name) => {
+      // Muted players may have left, so their address works too
+      let ip = name
+        .parse()
+        .ok()
+        .or_else(|| connections.get(&name).and_then(|connection| connection.ip));
+      match ip {
+        Some(ip) if mutes.remove(&ip) => format!("Unmuted {ip}"),
+        Some(ip) => format!("{ip} isn't muted"),
+        None => format!("No player called {name}"),
+      }
+    }
+    AdminCommand::SpawnKraken(x, y, scale) => {
+      *kraken = Some(Ship::kraken((x, y), scale, config));
+      format!("Released a kraken at ({x}, {y})")
+    }
+    AdminCommand::SetShip(name, class) => match connections.get_mut(&name).map(|x| &mut x.ship) {
+      Some(Some(ship)) => {
+        ship.stats = get_stats(class);
+        ship.class = Some(class);
+        ship.submerged = false;
+        format!("{name} is now a {}", class.name())
+      }
+      Some(None) => format!("{name} is spectating"),
+      None => format!("No player called {name}"),
+    },
+    AdminCommand::Heal(Some(name)) => match connections.get_mut(&name).map(|x| &mut x.ship) {
+      Some(Some(ship)) if !ship.sunk => {
+        ship.repair();
+        format!("Healed {name}")
+      }
+      Some(Some(_)) => format!("{name} has already sunk"),
+      Some(None) => format!("{name} is spectating"),
+      None => format!("No player called {name}"),
+    },
+    AdminCommand::Heal(None) => {
+      for ship in connections.values_mut().filter_map(|x| x.ship.as_mut()) {
+        if !ship.sunk {
+          ship.repair();
+        }
+      }
+      "Healed everyone".to_owned()
+    }
+    AdminCommand::Shutdown => {
+      for connection in connections.values() {
+        connection.notice("Server is shutting down");
+      }
+      "Shutting down".to_owned()
+    }
+  }
+}
+
+/// Runs queued admin commands, returns false once the server should shut down
+pub fn process_commands(
+  rx: &Receiver<AdminRequest>,
+  config: &Config,
+  connections: &mut HashMap<String, ClientData>,
+  kraken: &mut Option<Ship>,
+  bans: &mut HashSet<IpAddr>,
+  mutes: &mut HashSet<IpAddr>,
+) -> bool {
+  for AdminRequest { command, reply } in rx.try_iter() {
+    let shutdown = matches!(command, AdminCommand::Shutdown);
+    let response = run_command(config, connections, kraken, bans, mutes, command);
+    reply.send(response).ok();
+    if shutdown {
+      return false;
+    }
+  }
+  true
+}
@@ -0,0 +1,146 @@
+//! Text chat between players, checked here before anyone else sees it
+use crate::client::ClientData;
+use crate::input::TokenBucket;
+use protocol::{Capability, ChatScope, ErrorCode, ServerMessage, MAX_CHAT_LENGTH};
+use std::collections::HashMap;
+use std::time::Instant;
+
+// Enough for a quick exchange, not enough to fill everyone's screen
+const CHAT_RATE: f32 = 0.5;
+const CHAT_BURST: f32 = 5.0;
+
+pub const fn chat_limit(now: Instant) -> TokenBucket {
+  TokenBucket::new(CHAT_RATE, CHAT_BURST, now)
+}
+
+/// Checks a chat message before it is sent, returning why it can't be
+pub fn check(
+  connection: &mut ClientData,
+  text: &str,
+  now: Instant,
+) -> Result<(), (ErrorCode, String)> {
+  if text.is_empty() {
+    Err((
+      ErrorCode::InvalidMessage,
+      "Chat messages can't be empty".to_owned(),
+    ))
+  } else if text.chars().count() > MAX_CHAT_LENGTH {
+    Err((
+      ErrorCode::InvalidMessage,
+      format!("Chat messages can't be longer than {MAX_CHAT_LENGTH} characters"),
+    ))
+  } else if text.contains(char::is_control) {
+    Err((
+      ErrorCode::InvalidMessage,
+      "Chat messages can only contain printable text".to_owned(),
+    ))
+  } else if !connection.chat.take(now) {
+    Err((
+      ErrorCode::RateLimited,
+      "You are sending chat messages too quickly".to_owned(),
+    ))
+  } else {
+    Ok(())
+  }
+}
+
+fn send(connection: &ClientData, from: &str, scope: &ChatScope, text: &str) {
+  if connection.supports(Capability::Chat) {
+    let message = ServerMessage::Chat(scope.clone(), from.to_owned(), text.to_owned());
+    connection.tx.send(message);
+  } else if connection.supports(Capability::Say) {
+    // Older clients can only show it as a message from the server
+    let text = match scope {
+      ChatScope::Global => format!("{from}: {text}"),
+      ChatScope::Team => format!("{from} to the team: {text}"),
+      ChatScope::Whisper(_) => format!("{from} whispers: {text}"),
+    };
+    connection.tx.send(ServerMessage::Say(text));
+  }
+}
+
+/// Sends a chat message to everyone in its scope, the sender included
+pub fn deliver(
+  connections: &HashMap<String, ClientData>,
+  from: &str,
+  scope: &ChatScope,
+  text: &str,
+) -> Result<(), String> {
+  let sender = &connections[from];
+  let recipients: Vec<_> = match scope {
+    ChatScope::Global => connections.values().collect(),
+    ChatScope::Team => connections
+      .values()
+      .filter(|connection| connection.team == sender.team)
+      .collect(),
+    ChatScope::Whisper(name) if name == from => vec![sender],
+    ChatScope::Whisper(name) => {
+      let target = connections
+        .get(name)
+        .ok_or_else(|| format!("There is nobody called {name}"))?;
+      vec![target, sender]
+    }
+  };
+  for connection in recipients {
+    send(connection, from, scope, text);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::outbox::{outbox, Outgoing};
+  use std::sync::mpsc::channel;
+
+  fn players(teams: &[(&str, u32)]) -> (HashMap<String, ClientData>, Vec<Outgoing>) {
+    let mut connections = HashMap::new();
+    let mut outgoing = Vec::new();
+    for (name, team) in teams {
+      let (tx, rx) = outbox();
+      let mut connection = ClientData::new(tx, channel().1, None, None, vec![Capability::Chat]);
+      connection.team = Some(*team);
+      connections.insert((*name).to_owned(), connection);
+      outgoing.push(rx);
+    }
+    (connections, outgoing)
+  }
+
+  fn received(outgoing: &[Outgoing]) -> Vec<usize> {
+    outgoing
+      .iter()
+      .map(|rx| std::iter::from_fn(|| rx.try_recv().ok()).count())
+      .collect()
+  }
+
+  #[test]
+  fn scopes_pick_the_recipients() {
+    let (connections, outgoing) = players(&[("Yorktown", 1), ("Hornet", 1), ("Akagi", 2)]);
+    deliver(&connections, "Yorktown", &ChatScope::Global, "Hello").unwrap();
+    assert_eq!(received(&outgoing), [1, 1, 1]);
+    deliver(&connections, "Yorktown", &ChatScope::Team, "Hello").unwrap();
+    assert_eq!(received(&outgoing), [1, 1, 0]);
+    let whisper = ChatScope::Whisper("Akagi".to_owned());
+    deliver(&connections, "Yorktown", &whisper, "Hello").unwrap();
+    assert_eq!(received(&outgoing), [1, 0, 1]);
+    let nobody = ChatScope::Whisper("Kaga".to_owned());
+    assert!(deliver(&connections, "Yorktown", &nobody, "Hello").is_err());
+    assert_eq!(received(&outgoing), [0, 0, 0]);
+  }
+
+  #[test]
+  fn bad_and_excessive_chat_is_refused() {
+    let (mut connections, _outgoing) = players(&[("Yorktown", 1)]);
+    let connection = connections.get_mut("Yorktown").unwrap();
+    let now = Instant::now();
+    let long = "a".repeat(MAX_CHAT_LENGTH + 1);
+    assert!(check(connection, &long, now).is_err());
+    assert!(check(connection, "", now).is_err());
+    assert!(check(connection, "\u{1b}[2J", now).is_err());
+    for _ in 0..CHAT_BURST as usize {
+      assert!(check(connection, "Hello", now).is_ok());
+    }
+    let (code, _) = check(connection, "Hello", now).unwrap_err();
+    assert_eq!(code, ErrorCode::RateLimited);
+  }
+}
@@ -1,103 +1,488 @@
-use crate::Ship;
+use crate::chat::chat_limit;
+use crate::input::{self, InputFilter, TokenBucket};
+use crate::outbox::{outbox, Outbox, Outgoing};
+use crate::snapshot::SnapshotHistory;
+use crate::{Ship, KRAKEN_NAME};
+use physics::{Hull, Machinery};
+use protocol::binary::{decode_client, read_frame, write_frame, ServerEncoder};
+use protocol::{Capability, ClientMessage, ErrorCode, ParseError, ServerMessage, PROTOCOL_VERSION};
+use rand::{thread_rng, Rng};
+use std::fmt::{Display, Formatter};
 use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread::spawn;
+use std::time::{Duration, Instant};
 
-const PORT: u16 = 25565;
+// Version 1 clients send their ship name without saying hello first
+const MIN_PROTOCOL_VERSION: u32 = 1;
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a write can block before the client is assumed to be gone
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub enum ClientMessage {
-  Sail(f32, f32),
-  Anchor,
-  Smoke,
-  Action(usize),
+pub const MAX_NAME_LENGTH: usize = 20;
+// Names of ships that aren't controlled by players
+const RESERVED_NAMES: &[&str] = &[KRAKEN_NAME];
+
+// Lines that can't be parsed are passed on too, so the client can be told what was wrong
+pub type Incoming = Result<ClientMessage, ParseError>;
+
+/// What a client asked to join as
+pub enum Request {
+  Ship(String),
+  // The session token of a ship that is already in the water
+  Resume(u64),
+  Spectate,
+}
+
+impl Display for Request {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Ship(name) => write!(f, "{name}"),
+      Self::Resume(_) => write!(f, "a resumed ship"),
+      Self::Spectate => write!(f, "a spectator"),
+    }
+  }
+}
+
+pub struct Joining {
+  // Messages to the client, sent by a thread that owns its connection
+  pub tx: Outbox,
+  pub rx: Receiver<Incoming>,
+  pub peer: Option<SocketAddr>,
+  pub request: Request,
+  pub capabilities: Vec<Capability>,
 }
 
 pub struct ClientData {
-  pub tx: Sender<String>,
-  pub rx: Receiver<ClientMessage>,
-  pub ship: Ship,
+  pub tx: Outbox,
+  pub rx: Receiver<Incoming>,
+  // None for spectators, who only watch
+  pub ship: Option<Ship>,
+  pub address: String,
+  pub ip: Option<IpAddr>,
+  pub capabilities: Vec<Capability>,
+  pub snapshots: SnapshotHistory,
+  // Where to send datagrams, once the client has sent its token from there
+  pub udp: Option<SocketAddr>,
+  pub token: u64,
+  pub sequence: u32,
+  // Lets the player take the ship back after losing the connection
+  pub session: u64,
+  // Ticks left before the ship of a player who lost the connection is removed
+  pub detached: Option<u32>,
+  // None for spectators, who chat among themselves
+  pub team: Option<u32>,
+  pub chat: TokenBucket,
+  // The hull, machinery, turn and current last sent to a client that predicts its own ship
+  pub hull: Option<Hull>,
+  pub machinery: Option<Machinery>,
+  pub turn: Option<(f32, f32)>,
+  pub current: Option<(f32, f32)>,
 }
 
 impl ClientData {
-  pub fn new(mut stream: TcpStream, rx: Receiver<ClientMessage>, ship: Ship) -> Self {
-    let (tx, rx_2) = channel::<String>();
-    spawn(move || {
-      for message in rx_2 {
-        stream.write_all(message.as_bytes()).ok();
-      }
-    });
-    Self { tx, rx, ship }
+  pub fn new(
+    tx: Outbox,
+    rx: Receiver<Incoming>,
+    peer: Option<SocketAddr>,
+    ship: Option<Ship>,
+    capabilities: Vec<Capability>,
+  ) -> Self {
+    Self {
+      tx,
+      rx,
+      ship,
+      address: peer.map_or("unknown".to_owned(), |x| x.to_string()),
+      ip: peer.map(|x| x.ip()),
+      snapshots: SnapshotHistory::new(&capabilities),
+      capabilities,
+      udp: None,
+      token: thread_rng().gen(),
+      sequence: 0,
+      session: thread_rng().gen(),
+      detached: None,
+      team: None,
+      chat: chat_limit(Instant::now()),
+      hull: None,
+      machinery: None,
+      turn: None,
+      current: None,
+    }
+  }
+
+  /// Cuts off the client but leaves its ship in the water for a while, so it can be resumed
+  pub fn detach(&mut self, grace: u32) {
+    // Nothing reads from these, so anything sent to the client in the meantime is dropped
+    (self.tx, _) = outbox();
+    (_, self.rx) = channel();
+    self.udp = None;
+    self.detached = Some(grace);
+    if let Some(ship) = &mut self.ship {
+      ship.power = 0.0;
+      ship.helm = 0.0;
+    }
+  }
+
+  pub fn supports(&self, capability: Capability) -> bool {
+    self.capabilities.contains(&capability)
+  }
+
+  /// Whether state can be sent as datagrams, which only works for clients that say what arrived
+  ///
+  /// Snapshots would otherwise be assumed to arrive, and the client sent changes to ones it lost.
+  pub fn datagrams(&self) -> bool {
+    self.supports(Capability::Udp) && self.supports(Capability::Snapshot)
+  }
+
+  /// Shows the player a notice, or says it to clients from before notices
+  pub fn notice(&self, text: &str) {
+    if self.supports(Capability::Notice) {
+      self.tx.send(ServerMessage::Notice(text.to_owned()));
+    } else if self.supports(Capability::Say) {
+      self.tx.send(ServerMessage::Say(text.to_owned()));
+    }
+  }
+
+  /// Tells the client why something it sent was dropped
+  pub fn error(&self, error: &ParseError) {
+    self.refuse(error.into(), error.to_string());
+  }
+
+  /// Tells the client why a valid message was dropped anyway
+  pub fn refuse(&self, code: ErrorCode, reason: String) {
+    if self.supports(Capability::Notice) {
+      self.tx.send(ServerMessage::Error(code, reason));
+    }
   }
 }
 
-pub fn process_joining(tx: &Sender<(TcpStream, Receiver<ClientMessage>, String)>) {
-  let listener = TcpListener::bind(format!("0.0.0.0:{PORT}"))
-    .unwrap_or_else(|_| panic!("Failed to bind to port {PORT}"));
+fn validate_name(name: &str) -> Result<(), String> {
+  if name.is_empty() {
+    Err("Ship names can't be empty".to_owned())
+  } else if name.len() > MAX_NAME_LENGTH {
+    Err(format!(
+      "Ship names can't be longer than {MAX_NAME_LENGTH} characters"
+    ))
+  } else if name.contains(char::is_whitespace) {
+    Err("Ship names can't contain spaces".to_owned())
+  } else if !name
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+  {
+    Err("Ship names can only contain letters, numbers, - and _".to_owned())
+  } else if RESERVED_NAMES
+    .iter()
+    .any(|reserved| reserved.eq_ignore_ascii_case(name))
+  {
+    Err(format!("The name {name} is reserved"))
+  } else {
+    Ok(())
+  }
+}
 
-  for stream in listener.incoming().flatten() {
-    let address = stream
-      .peer_addr()
-      .map(|x| x.to_string())
-      .unwrap_or("unknown".to_owned());
-    let stream_clone = stream.try_clone().expect("try-clone broke");
-    let mut stream = BufReader::new(stream);
-    let mut buf = String::new();
-    let name = if let Ok(chars) = stream.read_line(&mut buf) {
-      if chars == 0 {
-        println!("{address} failed to connect");
-        continue;
-      }
-      let mut words = buf.split_whitespace();
-      if let Some("ship") = words.next() {
-        if let Some(name) = words.next() {
-          name
-        } else {
-          println!("Invalid input");
-          continue;
-        }
-      } else {
-        println!("Invalid input");
-        continue;
-      }
+/// Sends messages to a TCP client until it is dropped
+fn write_client(mut stream: TcpStream, outgoing: &Outgoing, binary: bool) {
+  // A client that stops reading would otherwise hold this thread forever
+  stream.set_write_timeout(Some(WRITE_TIMEOUT)).ok();
+  let mut encoder = ServerEncoder::default();
+  while let Some(message) = outgoing.recv() {
+    let written = if binary {
+      write_frame(&mut stream, &encoder.encode(&message))
     } else {
-      println!("Invalid input");
-      continue;
+      stream.write_all(format!("{message}\n").as_bytes())
     };
-    let (tx2, rx) = channel();
-    spawn(move || process_client(stream, &tx2));
-    if tx.send((stream_clone, rx, name.to_owned())).is_ok() {
-      println!("{address} connected as {name}");
-    } else {
-      // The server has crashed or something
-      return;
+    if written.is_err() {
+      break;
     }
   }
+  // The client has been dropped, so hang up once everything is sent
+  stream.shutdown(Shutdown::Both).ok();
+}
+
+/// Checks a client's hello, returning the replies that finish negotiating the protocol
+pub fn greet(version: u32, server_name: &str) -> Result<[ServerMessage; 2], String> {
+  if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+    return Err(format!(
+      "This server speaks protocol version {PROTOCOL_VERSION} but your client speaks version {version}, please use a matching version of Enterprise"
+    ));
+  }
+  Ok([
+    ServerMessage::Hello(PROTOCOL_VERSION, Capability::ALL.to_vec()),
+    ServerMessage::Server(server_name.to_owned()),
+  ])
+}
+
+/// Checks the message that should say which ship the client wants
+pub fn join_request(message: Result<ClientMessage, ParseError>) -> Result<Request, String> {
+  match message {
+    Ok(ClientMessage::Ship(name)) => {
+      validate_name(&name)?;
+      Ok(Request::Ship(name))
+    }
+    Ok(ClientMessage::Resume(session)) => Ok(Request::Resume(session)),
+    Ok(ClientMessage::Spectate) => Ok(Request::Spectate),
+    Err(error) => Err(error.to_string()),
+    Ok(_) => Err("Expected a ship name".to_owned()),
+  }
+}
+
+fn read_line(stream: &mut BufReader<TcpStream>, buf: &mut String) -> Result<(), Option<String>> {
+  buf.clear();
+  match input::read_line(stream, buf) {
+    Ok(0) => Err(None),
+    Ok(_) => Ok(()),
+    Err(reason) => Err(reason.map(str::to_owned)),
+  }
 }
 
-fn process_client(mut stream: BufReader<TcpStream>, tx: &Sender<ClientMessage>) -> Option<()> {
+/// Negotiates the protocol and reads which ship to join as, filling in the capabilities as it goes
+fn handshake(
+  stream: &mut BufReader<TcpStream>,
+  writer: &mut TcpStream,
+  server_name: &str,
+  capabilities: &mut Vec<Capability>,
+) -> Result<Request, Option<String>> {
   let mut buf = String::new();
-  while let Ok(chars) = stream.read_line(&mut buf) {
-    if chars == 0 {
-      None?;
+  read_line(stream, &mut buf)?;
+  let mut message = buf.parse();
+  if let Ok(ClientMessage::Hello(version, client_capabilities)) = message {
+    let replies = greet(version, server_name)?;
+    *capabilities = client_capabilities;
+    for reply in replies {
+      writer
+        .write_all(format!("{reply}\n").as_bytes())
+        .map_err(|_| None)?;
     }
-    let mut words = buf.split_whitespace();
-    match words.next() {
-      Some("sail") => {
-        let power = words.next().and_then(|w| w.parse().ok())?;
-        let helm = words.next().and_then(|w| w.parse().ok())?;
-        tx.send(ClientMessage::Sail(power, helm)).ok()?;
+    if capabilities.contains(&Capability::Binary) {
+      let mut frame = Vec::new();
+      read_frame(stream, &mut frame).map_err(|_| None)?;
+      message = decode_client(&frame);
+    } else {
+      read_line(stream, &mut buf)?;
+      message = buf.parse();
+    }
+  }
+  // Clients from before the handshake start here, and get no optional messages
+  Ok(join_request(message)?)
+}
+
+pub fn process_joining(tx: &Sender<Joining>, listener: &TcpListener, server_name: &str) {
+  for stream in listener.incoming().flatten() {
+    // A client that is slow to say hello mustn't hold up everyone joining after it
+    let (tx, server_name) = (tx.clone(), server_name.to_owned());
+    spawn(move || join(stream, &tx, &server_name));
+  }
+}
+
+/// Shakes hands with a new client and passes it on to the game
+fn join(stream: TcpStream, tx: &Sender<Joining>, server_name: &str) {
+  let peer = stream.peer_addr().ok();
+  let address = peer.map_or("unknown".to_owned(), |x| x.to_string());
+  let Ok(mut stream_clone) = stream.try_clone() else {
+    println!("{address} failed to connect");
+    return;
+  };
+  stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+  let mut stream = BufReader::new(stream);
+  let mut capabilities = Vec::new();
+  let handshake = handshake(
+    &mut stream,
+    &mut stream_clone,
+    server_name,
+    &mut capabilities,
+  );
+  let binary = capabilities.contains(&Capability::Binary);
+  let (writer, outgoing) = outbox();
+  let request = match handshake {
+    Ok(request) => request,
+    Err(Some(reason)) => {
+      println!("{address} failed to join: {reason}");
+      writer.send(ServerMessage::Reject(reason));
+      drop(writer);
+      write_client(stream_clone, &outgoing, binary);
+      return;
+    }
+    Err(None) => {
+      println!("{address} failed to connect");
+      return;
+    }
+  };
+  spawn(move || write_client(stream_clone, &outgoing, binary));
+  stream.get_ref().set_read_timeout(None).ok();
+  let (tx2, rx) = channel();
+  let notices = capabilities.contains(&Capability::Notice);
+  let (reader_address, reader_writer) = (address.clone(), writer.clone());
+  spawn(move || {
+    if let Some(reason) = process_client(stream, &tx2, binary) {
+      println!("Disconnected {reader_address}: {reason}");
+      if notices {
+        let error = ServerMessage::Error(ErrorCode::RateLimited, reason.to_owned());
+        reader_writer.send(error);
       }
-      Some("anchor") => tx.send(ClientMessage::Anchor).ok()?,
-      Some("smoke") => tx.send(ClientMessage::Smoke).ok()?,
-      Some("action") => {
-        let action = words.next().and_then(|w| w.parse().ok())?;
-        tx.send(ClientMessage::Action(action)).ok()?;
+    }
+  });
+  println!("{address} connected as {request}");
+  let joining = Joining {
+    tx: writer,
+    rx,
+    peer,
+    request,
+    capabilities,
+  };
+  // Only fails if the server has crashed or something
+  tx.send(joining).ok();
+}
+
+/// Reads messages until the client leaves, or returns why it had to be cut off
+fn process_client(
+  mut stream: impl BufRead,
+  tx: &Sender<Incoming>,
+  binary: bool,
+) -> Option<&'static str> {
+  let mut filter = InputFilter::new(Instant::now());
+  let mut forward = |message| match filter.check(message, Instant::now()) {
+    Ok(message) => tx.send(message).ok().ok_or(None),
+    Err(reason) => Err(Some(reason)),
+  };
+  if binary {
+    let mut frame = Vec::new();
+    // Frames that can't be decoded still have a length, so only a broken frame ends the connection
+    while read_frame(&mut stream, &mut frame).is_ok() {
+      if let Err(reason) = forward(decode_client(&frame)) {
+        return reason;
       }
-      Some(word) => println!("Bad message {word} from client"),
-      None => println!("Empty message from client"),
     }
+    return None;
+  }
+  let mut buf = String::new();
+  loop {
     buf.clear();
+    match input::read_line(&mut stream, &mut buf) {
+      Ok(0) => return None,
+      Ok(_) => {
+        if let Err(reason) = forward(buf.parse()) {
+          return reason;
+        }
+      }
+      Err(reason) => return reason,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::input::MAX_LINE_LENGTH;
+  use protocol::binary::{encode_client, write_frame};
+
+  // Runs a whole connection's worth of input, returning what reached the game and why it ended
+  fn run(input: &[u8], binary: bool) -> (Vec<Incoming>, Option<&'static str>) {
+    let (tx, rx) = channel();
+    let reason = process_client(input, &tx, binary);
+    drop(tx);
+    (rx.iter().collect(), reason)
+  }
+
+  #[test]
+  fn inputs_are_clamped() {
+    let (messages, reason) = run(b"sail 1000 50\nsail -3 -0.25\n", false);
+    assert_eq!(
+      messages,
+      [
+        Ok(ClientMessage::Sail(1.0, 1.0)),
+        Ok(ClientMessage::Sail(-0.5, -0.25)),
+      ]
+    );
+    assert_eq!(reason, None);
+  }
+
+  #[test]
+  fn non_finite_inputs_are_rejected() {
+    let (messages, _) = run(b"sail NaN 0\nsail 1 inf\nsail -inf 0\n", false);
+    assert_eq!(
+      messages,
+      [
+        Err(ParseError::InvalidField("sail", "power", "NaN".to_owned())),
+        Err(ParseError::InvalidField("sail", "helm", "inf".to_owned())),
+        Err(ParseError::InvalidField("sail", "power", "-inf".to_owned())),
+      ]
+    );
+  }
+
+  #[test]
+  fn bad_lines_are_reported_without_disconnecting() {
+    let (messages, reason) = run(b"action 0\nsail fast 0\n\nfly away\nanchor\n", false);
+    assert_eq!(
+      messages,
+      [
+        Err(ParseError::InvalidField("action", "action", "0".to_owned())),
+        Err(ParseError::InvalidField("sail", "power", "fast".to_owned())),
+        Err(ParseError::Empty),
+        Err(ParseError::UnknownMessage("fly".to_owned())),
+        Ok(ClientMessage::Anchor),
+      ]
+    );
+    assert_eq!(reason, None);
+  }
+
+  #[test]
+  fn floods_are_disconnected() {
+    let input = "anchor\n".repeat(10_000);
+    let (messages, reason) = run(input.as_bytes(), false);
+    assert!(messages.len() < 10_000);
+    assert_eq!(reason, Some("Too many messages"));
+  }
+
+  #[test]
+  fn streams_of_garbage_are_disconnected() {
+    let input = "garbage\n".repeat(100);
+    let (messages, reason) = run(input.as_bytes(), false);
+    assert!(messages.len() < 100);
+    assert!(messages.iter().all(Result::is_err));
+    assert_eq!(reason, Some("Too many bad messages"));
+  }
+
+  #[test]
+  fn datagrams_need_acknowledgements() {
+    let client = |capabilities| {
+      let (tx, _) = outbox();
+      let (_, rx) = channel();
+      ClientData::new(tx, rx, None, None, capabilities)
+    };
+    assert!(!client(vec![Capability::Udp]).datagrams());
+    assert!(client(vec![Capability::Udp, Capability::Snapshot]).datagrams());
+  }
+
+  #[test]
+  fn endless_lines_are_disconnected() {
+    let mut input = b"anchor\n".to_vec();
+    input.resize(MAX_LINE_LENGTH * 4, b'a');
+    let (messages, reason) = run(&input, false);
+    assert_eq!(messages, [Ok(ClientMessage::Anchor)]);
+    assert_eq!(reason, Some("Line too long"));
+  }
+
+  #[test]
+  fn hostile_frames() {
+    let mut input = Vec::new();
+    write_frame(&mut input, &encode_client(&ClientMessage::Sail(3.0, -3.0))).unwrap();
+    // Unknown tag, then a sail that is cut short
+    write_frame(&mut input, &[0xff]).unwrap();
+    write_frame(&mut input, &[1, 0]).unwrap();
+    write_frame(&mut input, &encode_client(&ClientMessage::Anchor)).unwrap();
+    // A frame claiming to be longer than the rest of the stream
+    input.extend_from_slice(&[0xff, 0xff, 1]);
+    let (messages, reason) = run(&input, true);
+    assert_eq!(
+      messages,
+      [
+        Ok(ClientMessage::Sail(1.0, -1.0)),
+        Err(ParseError::UnknownTag(0xff)),
+        Err(ParseError::Truncated),
+        Ok(ClientMessage::Anchor),
+      ]
+    );
+    assert_eq!(reason, None);
   }
-  None
 }
@@ -0,0 +1,796 @@
+//! Server configuration, loaded from a TOML file and overridden by command line flags
+use crate::current::{Current, CurrentGrid};
+use crate::stats::{ShipType, DEFAULT_WEIGHTS};
+use enum_iterator::all;
+use protocol::Colour;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::read_to_string;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+
+const DEFAULT_CONFIG_PATH: &str = "midway.toml";
+
+pub const USAGE: &str = "Usage: midway [OPTIONS]
+
+Options are read from midway.toml in the working directory if it exists,
+command line flags take priority over the config file.
+
+  --config <path>               Load the config from this file
+  --name <name>                 Name shown to players [default: Midway]
+  --motd <text>                 Message shown to players when they join
+  --bind <address>              Address to listen on, IPv4 or IPv6 [default: 0.0.0.0]
+  --port <port>                 Port to listen on [default: 25565]
+  --persistent                  Keep running when the last player leaves
+  --duplicate-names <mode>      Either reject or rename players with a taken name [default: rename]
+  --teams <count>               Number of teams players are split into for chat [default: 1]
+  --tps <ticks>                 Ticks per second [default: 60]
+  --acceleration <factor>       Time acceleration factor [default: 4]
+  --respawn-cooldown <ticks>    Ticks before a sunk ship respawns [default: 120]
+  --reconnect-grace <seconds>   How long a disconnected player's ship waits for them [default: 30]
+  --colour <hex>                Colour of player ships [default: 999]
+  --gun-accuracy <fraction>     Spread of gunfire in a calm [default: 0.01]
+  --wind <speed>                Average wind speed in m/s, 0 for a flat calm [default: 6]
+  --interest-radius <metres>    Only send players ships this close [default: unlimited]
+  --spectators-see-submerged    Show spectators submerged ships too
+  --radius <metres>             Radius of the map [default: 2000]
+  --border <ocean|land|none>    What lies beyond the map radius [default: ocean]
+  --kraken-spawn-chance <rate>  Ocean border kraken spawn rate [default: 0.01]
+  --mine-spawn-chance <rate>    Ocean border mine rate [default: 0.0001]
+  --mine-damage <damage>        Maximum damage of a mine [default: 2000]
+  --border-scale <metres>       Distance over which the ocean border ramps up [default: 500]
+  --border-intensity <speed>    Strength of the ocean border push [default: 18]
+  --border-dps <damage>         Damage per second beyond the ocean border [default: 5]
+  --current <kind>              Ocean current: none, uniform, gyre or grid [default: none]
+  --current-speed <speed>       Speed of the current in m/s, where a gyre is fastest [default: 1]
+  --current-direction <degrees> Heading a uniform current flows towards [default: 90]
+  --current-radius <metres>     Where a gyre is fastest [default: the map radius]
+  --current-grid <path>         TOML file holding the velocities of a grid current
+  --weight <class>=<weight>     Spawn weight of a ship class, may be repeated
+  --websocket-port <port>       Also accept WebSocket clients on this port
+  --rcon-port <port>            Enable remote admin on this port, with rcon.password from the config
+  --help                        Print this message";
+
+#[derive(Clone, Copy)]
+pub enum BorderType {
+  Ocean(OceanData),
+  Land,
+}
+
+#[derive(Clone, Copy)]
+pub struct OceanData {
+  pub kraken_spawn_chance: f32,
+  pub mine_spawn_chance: f32,
+  pub mine_damage: f32,
+  pub scale: f32,
+  pub intensity: f32,
+  pub dps: f32,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateNames {
+  Reject,
+  Rename,
+}
+
+impl FromStr for DuplicateNames {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "reject" => Ok(Self::Reject),
+      "rename" => Ok(Self::Rename),
+      _ => Err(()),
+    }
+  }
+}
+
+pub struct RconConfig {
+  pub address: SocketAddr,
+  pub password: String,
+}
+
+pub struct Config {
+  pub name: String,
+  pub motd: Option<String>,
+  pub address: SocketAddr,
+  pub persistent: bool,
+  pub duplicate_names: DuplicateNames,
+  pub teams: u32,
+  pub tps: u32,
+  pub time_acceleration_factor: f32,
+  pub respawn_cooldown: u32,
+  // Seconds, 0 removes ships as soon as their player disconnects
+  pub reconnect_grace: f32,
+  pub colour: Colour,
+  pub gun_accuracy: f32,
+  // Metres per second the wind blows at on average, which also raises the sea
+  pub wind: f32,
+  // Never smaller than a ship's gun range
+  pub interest_radius: Option<f32>,
+  pub spectators_see_submerged: bool,
+  pub map_radius: Option<(f32, BorderType)>,
+  pub current: Current,
+  pub spawn_weights: Vec<usize>,
+  pub websocket: Option<SocketAddr>,
+  pub rcon: Option<RconConfig>,
+}
+
+pub enum ConfigError {
+  Help,
+  Read(String, io::Error),
+  Parse(String, toml::de::Error),
+  ReadGrid(String, io::Error),
+  ParseGrid(String, toml::de::Error),
+  UnknownFlag(String),
+  MissingValue(String),
+  InvalidValue(String, String),
+  Invalid(&'static str, String),
+}
+
+impl Display for ConfigError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Help => write!(f, "{USAGE}"),
+      Self::Read(path, error) => write!(f, "Could not read config file {path}: {error}"),
+      Self::Parse(path, error) => write!(f, "Could not parse config file {path}: {error}"),
+      Self::ReadGrid(path, error) => write!(f, "Could not read current grid {path}: {error}"),
+      Self::ParseGrid(path, error) => write!(f, "Could not parse current grid {path}: {error}"),
+      Self::UnknownFlag(flag) => write!(f, "Unknown flag {flag}, see --help"),
+      Self::MissingValue(flag) => write!(f, "Missing value for {flag}"),
+      Self::InvalidValue(flag, value) => write!(f, "Invalid value {value:?} for {flag}"),
+      Self::Invalid(field, reason) => write!(f, "Invalid {field}: {reason}"),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BorderKind {
+  Ocean,
+  Land,
+  None,
+}
+
+impl FromStr for BorderKind {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "ocean" => Ok(Self::Ocean),
+      "land" => Ok(Self::Land),
+      "none" => Ok(Self::None),
+      _ => Err(()),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CurrentKind {
+  None,
+  Uniform,
+  Gyre,
+  Grid,
+}
+
+impl FromStr for CurrentKind {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "none" => Ok(Self::None),
+      "uniform" => Ok(Self::Uniform),
+      "gyre" => Ok(Self::Gyre),
+      "grid" => Ok(Self::Grid),
+      _ => Err(()),
+    }
+  }
+}
+
+// Every field is optional so the file and the command line can be layered
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+  name: Option<String>,
+  motd: Option<String>,
+  bind: Option<IpAddr>,
+  port: Option<u16>,
+  persistent: Option<bool>,
+  duplicate_names: Option<DuplicateNames>,
+  teams: Option<u32>,
+  tps: Option<u32>,
+  time_acceleration_factor: Option<f32>,
+  respawn_cooldown: Option<u32>,
+  reconnect_grace: Option<f32>,
+  colour: Option<String>,
+  gun_accuracy: Option<f32>,
+  wind: Option<f32>,
+  interest_radius: Option<f32>,
+  spectators_see_submerged: Option<bool>,
+  map: MapFile,
+  current: CurrentFile,
+  spawn_weights: HashMap<String, usize>,
+  websocket_port: Option<u16>,
+  rcon: RconFile,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RconFile {
+  bind: Option<IpAddr>,
+  port: Option<u16>,
+  // Never a flag, as anyone on the machine can see those
+  password: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CurrentFile {
+  kind: Option<CurrentKind>,
+  speed: Option<f32>,
+  // Degrees clockwise from north
+  direction: Option<f32>,
+  radius: Option<f32>,
+  grid: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MapFile {
+  radius: Option<f32>,
+  border: Option<BorderKind>,
+  kraken_spawn_chance: Option<f32>,
+  mine_spawn_chance: Option<f32>,
+  mine_damage: Option<f32>,
+  scale: Option<f32>,
+  intensity: Option<f32>,
+  dps: Option<f32>,
+}
+
+impl ConfigFile {
+  fn merge(self, overrides: Self) -> Self {
+    let mut spawn_weights = self.spawn_weights;
+    spawn_weights.extend(overrides.spawn_weights);
+    Self {
+      name: overrides.name.or(self.name),
+      motd: overrides.motd.or(self.motd),
+      bind: overrides.bind.or(self.bind),
+      port: overrides.port.or(self.port),
+      persistent: overrides.persistent.or(self.persistent),
+      duplicate_names: overrides.duplicate_names.or(self.duplicate_names),
+      teams: overrides.teams.or(self.teams),
+      tps: overrides.tps.or(self.tps),
+      time_acceleration_factor: overrides
+        .time_acceleration_factor
+        .or(self.time_acceleration_factor),
+      respawn_cooldown: overrides.respawn_cooldown.or(self.respawn_cooldown),
+      reconnect_grace: overrides.reconnect_grace.or(self.reconnect_grace),
+      colour: overrides.colour.or(self.colour),
+      gun_accuracy: overrides.gun_accuracy.or(self.gun_accuracy),
+      wind: overrides.wind.or(self.wind),
+      interest_radius: overrides.interest_radius.or(self.interest_radius),
+      spectators_see_submerged: overrides
+        .spectators_see_submerged
+        .or(self.spectators_see_submerged),
+      map: self.map.merge(overrides.map),
+      current: CurrentFile {
+        kind: overrides.current.kind.or(self.current.kind),
+        speed: overrides.current.speed.or(self.current.speed),
+        direction: overrides.current.direction.or(self.current.direction),
+        radius: overrides.current.radius.or(self.current.radius),
+        grid: overrides.current.grid.or(self.current.grid),
+      },
+      spawn_weights,
+      websocket_port: overrides.websocket_port.or(self.websocket_port),
+      rcon: RconFile {
+        bind: overrides.rcon.bind.or(self.rcon.bind),
+        port: overrides.rcon.port.or(self.rcon.port),
+        password: self.rcon.password,
+      },
+    }
+  }
+}
+
+impl MapFile {
+  fn merge(self, overrides: Self) -> Self {
+    Self {
+      radius: overrides.radius.or(self.radius),
+      border: overrides.border.or(self.border),
+      kraken_spawn_chance: overrides.kraken_spawn_chance.or(self.kraken_spawn_chance),
+      mine_spawn_chance: overrides.mine_spawn_chance.or(self.mine_spawn_chance),
+      mine_damage: overrides.mine_damage.or(self.mine_damage),
+      scale: overrides.scale.or(self.scale),
+      intensity: overrides.intensity.or(self.intensity),
+      dps: overrides.dps.or(self.dps),
+    }
+  }
+}
+
+fn value<T: FromStr>(
+  flag: &str,
+  args: &mut impl Iterator<Item = String>,
+) -> Result<Option<T>, ConfigError> {
+  let value = args
+    .next()
+    .ok_or_else(|| ConfigError::MissingValue(flag.to_owned()))?;
+  match value.parse() {
+    Ok(value) => Ok(Some(value)),
+    Err(_) => Err(ConfigError::InvalidValue(flag.to_owned(), value)),
+  }
+}
+
+fn parse_args(
+  mut args: impl Iterator<Item = String>,
+) -> Result<(Option<String>, ConfigFile), ConfigError> {
+  let mut path = None;
+  let mut overrides = ConfigFile::default();
+  while let Some(flag) = args.next() {
+    let args = &mut args;
+    match flag.as_str() {
+      "--help" | "-h" => return Err(ConfigError::Help),
+      "--config" => path = value(&flag, args)?,
+      "--name" => overrides.name = value(&flag, args)?,
+      "--motd" => overrides.motd = value(&flag, args)?,
+      "--bind" => overrides.bind = value(&flag, args)?,
+      "--port" => overrides.port = value(&flag, args)?,
+      "--persistent" => overrides.persistent = Some(true),
+      "--duplicate-names" => overrides.duplicate_names = value(&flag, args)?,
+      "--teams" => overrides.teams = value(&flag, args)?,
+      "--tps" => overrides.tps = value(&flag, args)?,
+      "--acceleration" => overrides.time_acceleration_factor = value(&flag, args)?,
+      "--respawn-cooldown" => overrides.respawn_cooldown = value(&flag, args)?,
+      "--reconnect-grace" => overrides.reconnect_grace = value(&flag, args)?,
+      "--colour" => overrides.colour = value(&flag, args)?,
+      "--gun-accuracy" => overrides.gun_accuracy = value(&flag, args)?,
+      "--wind" => overrides.wind = value(&flag, args)?,
+      "--interest-radius" => overrides.interest_radius = value(&flag, args)?,
+      "--spectators-see-submerged" => overrides.spectators_see_submerged = Some(true),
+      "--radius" => overrides.map.radius = value(&flag, args)?,
+      "--border" => overrides.map.border = value(&flag, args)?,
+      "--kraken-spawn-chance" => overrides.map.kraken_spawn_chance = value(&flag, args)?,
+      "--mine-spawn-chance" => overrides.map.mine_spawn_chance = value(&flag, args)?,
+      "--mine-damage" => overrides.map.mine_damage = value(&flag, args)?,
+      "--border-scale" => overrides.map.scale = value(&flag, args)?,
+      "--border-intensity" => overrides.map.intensity = value(&flag, args)?,
+      "--border-dps" => overrides.map.dps = value(&flag, args)?,
+      "--current" => overrides.current.kind = value(&flag, args)?,
+      "--current-speed" => overrides.current.speed = value(&flag, args)?,
+      "--current-direction" => overrides.current.direction = value(&flag, args)?,
+      "--current-radius" => overrides.current.radius = value(&flag, args)?,
+      "--current-grid" => overrides.current.grid = value(&flag, args)?,
+      "--websocket-port" => overrides.websocket_port = value(&flag, args)?,
+      "--rcon-port" => overrides.rcon.port = value(&flag, args)?,
+      "--weight" => {
+        let weight = args
+          .next()
+          .ok_or_else(|| ConfigError::MissingValue(flag.clone()))?;
+        let Some((class, amount)) = weight.split_once('=') else {
+          return Err(ConfigError::InvalidValue(flag, weight));
+        };
+        let Ok(amount) = amount.parse() else {
+          return Err(ConfigError::InvalidValue(flag, weight));
+        };
+        overrides.spawn_weights.insert(class.to_owned(), amount);
+      }
+      _ => return Err(ConfigError::UnknownFlag(flag)),
+    }
+  }
+  Ok((path, overrides))
+}
+
+fn read_file(path: &str) -> Result<ConfigFile, ConfigError> {
+  let contents = read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+  toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+}
+
+fn read_grid(path: &str) -> Result<CurrentGrid, ConfigError> {
+  let contents = read_to_string(path).map_err(|e| ConfigError::ReadGrid(path.to_owned(), e))?;
+  let grid: CurrentGrid =
+    toml::from_str(&contents).map_err(|e| ConfigError::ParseGrid(path.to_owned(), e))?;
+  grid
+    .check()
+    .map_err(|reason| ConfigError::Invalid("current.grid", format!("{path}: {reason}")))?;
+  Ok(grid)
+}
+
+fn check(field: &'static str, value: f32, valid: bool, expected: &str) -> Result<f32, ConfigError> {
+  if value.is_finite() && valid {
+    Ok(value)
+  } else {
+    Err(ConfigError::Invalid(
+      field,
+      format!("{value} must be {expected}"),
+    ))
+  }
+}
+
+fn chance(field: &'static str, value: f32) -> Result<f32, ConfigError> {
+  check(
+    field,
+    value,
+    (0.0..=1.0).contains(&value),
+    "between 0 and 1",
+  )
+}
+
+fn non_negative(field: &'static str, value: f32) -> Result<f32, ConfigError> {
+  check(field, value, value >= 0.0, "at least 0")
+}
+
+fn positive(field: &'static str, value: f32) -> Result<f32, ConfigError> {
+  check(field, value, value > 0.0, "greater than 0")
+}
+
+impl Config {
+  /// Reads the config file and applies the command line flags on top
+  pub fn load(args: impl Iterator<Item = String>) -> Result<Self, ConfigError> {
+    let (path, overrides) = parse_args(args)?;
+    let file = match path {
+      Some(path) => read_file(&path)?,
+      None if Path::new(DEFAULT_CONFIG_PATH).exists() => read_file(DEFAULT_CONFIG_PATH)?,
+      None => ConfigFile::default(),
+    };
+    Self::validate(file.merge(overrides))
+  }
+
+  fn validate(file: ConfigFile) -> Result<Self, ConfigError> {
+    let name = file.name.unwrap_or_else(|| "Midway".to_owned());
+    if name.trim().is_empty() || name.contains(char::is_control) {
+      return Err(ConfigError::Invalid(
+        "name",
+        format!("{name:?} must be a single line of text"),
+      ));
+    }
+    let motd = file.motd.filter(|motd| !motd.trim().is_empty());
+    if let Some(motd) = &motd {
+      if motd.contains(char::is_control) {
+        return Err(ConfigError::Invalid(
+          "motd",
+          format!("{motd:?} must be a single line of text"),
+        ));
+      }
+    }
+    let bind = file.bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let port = file.port.unwrap_or(25565);
+    let teams = file.teams.unwrap_or(1);
+    if !(1..=100).contains(&teams) {
+      return Err(ConfigError::Invalid(
+        "teams",
+        format!("{teams} must be between 1 and 100"),
+      ));
+    }
+    let tps = file.tps.unwrap_or(60);
+    if !(1..=1000).contains(&tps) {
+      return Err(ConfigError::Invalid(
+        "tps",
+        format!("{tps} must be between 1 and 1000"),
+      ));
+    }
+    let time_acceleration_factor = positive(
+      "time_acceleration_factor",
+      file.time_acceleration_factor.unwrap_or(4.0),
+    )?;
+    let colour = file.colour.unwrap_or_else(|| "999".to_owned());
+    let Ok(colour) = colour.parse() else {
+      return Err(ConfigError::Invalid(
+        "colour",
+        format!("{colour} must be a hex colour such as 999 or 336699"),
+      ));
+    };
+    // Gunfire is sampled from -accuracy..accuracy, which can't be empty
+    let gun_accuracy = file.gun_accuracy.unwrap_or(0.01);
+    let gun_accuracy = check(
+      "gun_accuracy",
+      gun_accuracy,
+      gun_accuracy > 0.0 && gun_accuracy < 1.0,
+      "between 0 and 1 exclusive",
+    )?;
+    let interest_radius = file
+      .interest_radius
+      .map(|radius| positive("interest_radius", radius))
+      .transpose()?;
+    let map = file.map;
+    let radius = positive("map.radius", map.radius.unwrap_or(2000.0))?;
+    let map_radius = match map.border.unwrap_or(BorderKind::Ocean) {
+      BorderKind::Ocean => Some((
+        radius,
+        BorderType::Ocean(OceanData {
+          kraken_spawn_chance: chance(
+            "map.kraken_spawn_chance",
+            map.kraken_spawn_chance.unwrap_or(0.01),
+          )?,
+          mine_spawn_chance: chance(
+            "map.mine_spawn_chance",
+            map.mine_spawn_chance.unwrap_or(0.0001),
+          )?,
+          mine_damage: non_negative("map.mine_damage", map.mine_damage.unwrap_or(2000.0))?,
+          scale: positive("map.scale", map.scale.unwrap_or(500.0))?,
+          intensity: non_negative("map.intensity", map.intensity.unwrap_or(18.0))?,
+          dps: non_negative("map.dps", map.dps.unwrap_or(5.0))?,
+        }),
+      )),
+      BorderKind::Land => Some((radius, BorderType::Land)),
+      BorderKind::None => None,
+    };
+    let current = file.current;
+    let speed = non_negative("current.speed", current.speed.unwrap_or(1.0))?;
+    let current = match current.kind.unwrap_or(CurrentKind::None) {
+      CurrentKind::None => Current::Still,
+      CurrentKind::Uniform => {
+        let direction = current.direction.unwrap_or(90.0);
+        let direction = check("current.direction", direction, true, "a number")?.to_radians();
+        Current::Uniform((speed * direction.sin(), -speed * direction.cos()))
+      }
+      CurrentKind::Gyre => Current::Gyre {
+        speed,
+        radius: positive("current.radius", current.radius.unwrap_or(radius))?,
+      },
+      CurrentKind::Grid => {
+        let Some(path) = current.grid else {
+          return Err(ConfigError::Invalid(
+            "current.grid",
+            "a file is required for a grid current".to_owned(),
+          ));
+        };
+        Current::Grid(read_grid(&path)?)
+      }
+    };
+    let mut spawn_weights = DEFAULT_WEIGHTS.to_vec();
+    for (class, weight) in file.spawn_weights {
+      let Some(ship) = ShipType::from_name(&class) else {
+        let classes: Vec<_> = all::<ShipType>().map(ShipType::name).collect();
+        return Err(ConfigError::Invalid(
+          "spawn_weights",
+          format!(
+            "unknown ship class {class}, expected one of {}",
+            classes.join(", ")
+          ),
+        ));
+      };
+      spawn_weights[ship as usize] = weight;
+    }
+    if spawn_weights.iter().all(|weight| *weight == 0) {
+      return Err(ConfigError::Invalid(
+        "spawn_weights",
+        "at least one ship class must have a non-zero weight".to_owned(),
+      ));
+    }
+    let websocket = match file.websocket_port {
+      Some(websocket_port) if websocket_port == port => {
+        return Err(ConfigError::Invalid(
+          "websocket_port",
+          format!("{websocket_port} is already the game port"),
+        ))
+      }
+      websocket_port => websocket_port.map(|websocket_port| SocketAddr::new(bind, websocket_port)),
+    };
+    let rcon = match (file.rcon.port, file.rcon.password) {
+      (None, _) => None,
+      (Some(_), None) => {
+        return Err(ConfigError::Invalid(
+          "rcon.password",
+          "a password is required to enable remote admin".to_owned(),
+        ))
+      }
+      (Some(_), Some(password)) if password.is_empty() => {
+        return Err(ConfigError::Invalid(
+          "rcon.password",
+          "the password can't be empty".to_owned(),
+        ))
+      }
+      (Some(rcon_port), Some(_)) if rcon_port == port => {
+        return Err(ConfigError::Invalid(
+          "rcon.port",
+          format!("{rcon_port} is already the game port"),
+        ))
+      }
+      (Some(rcon_port), Some(_)) if Some(rcon_port) == file.websocket_port => {
+        return Err(ConfigError::Invalid(
+          "rcon.port",
+          format!("{rcon_port} is already the WebSocket port"),
+        ))
+      }
+      (Some(rcon_port), Some(password)) => Some(RconConfig {
+        // Only reachable from this machine unless it is deliberately exposed
+        address: SocketAddr::new(
+          file.rcon.bind.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+          rcon_port,
+        ),
+        password,
+      }),
+    };
+    Ok(Self {
+      name,
+      motd,
+      address: SocketAddr::new(bind, port),
+      persistent: file.persistent.unwrap_or(false),
+      duplicate_names: file.duplicate_names.unwrap_or(DuplicateNames::Rename),
+      teams,
+      tps,
+      time_acceleration_factor,
+      respawn_cooldown: file.respawn_cooldown.unwrap_or(120),
+      reconnect_grace: non_negative("reconnect_grace", file.reconnect_grace.unwrap_or(30.0))?,
+      colour,
+      gun_accuracy,
+      wind: non_negative("wind", file.wind.unwrap_or(6.0))?,
+      interest_radius,
+      spectators_see_submerged: file.spectators_see_submerged.unwrap_or(false),
+      map_radius,
+      current,
+      spawn_weights,
+      websocket,
+      rcon,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Layers flags over a config file the way load does, without touching the disk
+  fn load(file: &str, args: &[&str]) -> Result<Config, ConfigError> {
+    let file: ConfigFile =
+      toml::from_str(file).map_err(|e| ConfigError::Parse("test".to_owned(), e))?;
+    let (_, overrides) = parse_args(args.iter().map(|arg| (*arg).to_owned()))?;
+    Config::validate(file.merge(overrides))
+  }
+
+  fn valid(file: &str, args: &[&str]) -> Config {
+    load(file, args).unwrap_or_else(|error| panic!("{error}"))
+  }
+
+  fn invalid(file: &str, args: &[&str]) -> ConfigError {
+    match load(file, args) {
+      Ok(_) => panic!("{file:?} {args:?} was accepted"),
+      Err(error) => error,
+    }
+  }
+
+  fn rejects(file: &str, field: &str) -> bool {
+    matches!(invalid(file, &[]), ConfigError::Invalid(invalid, _) if invalid == field)
+  }
+
+  #[test]
+  fn everything_has_a_default() {
+    let config = valid("", &[]);
+    assert_eq!(config.name, "Midway");
+    assert_eq!(config.address, "0.0.0.0:25565".parse().unwrap());
+    assert_eq!((config.tps, config.teams), (60, 1));
+    assert_eq!(config.spawn_weights, DEFAULT_WEIGHTS);
+    assert!(matches!(
+      config.map_radius,
+      Some((2000.0, BorderType::Ocean(_)))
+    ));
+    assert!(matches!(config.current, Current::Still));
+    assert!(config.websocket.is_none() && config.rcon.is_none());
+  }
+
+  #[test]
+  fn flags_override_the_file() {
+    let file = r#"
+      name = "Coral Sea"
+      port = 1942
+      tps = 30
+      [map]
+      radius = 3000.0
+      scale = 250.0
+      [spawn_weights]
+      escort = 0
+      destroyer = 5
+    "#;
+    let args = [
+      "--port",
+      "1943",
+      "--radius",
+      "4000",
+      "--border-intensity",
+      "9",
+      "--weight",
+      "destroyer=7",
+    ];
+    let config = valid(file, &args);
+    assert_eq!(config.name, "Coral Sea");
+    assert_eq!(config.address.port(), 1943);
+    assert_eq!(config.tps, 30);
+    let Some((radius, BorderType::Ocean(ocean))) = config.map_radius else {
+      panic!("the border should still be ocean");
+    };
+    assert_eq!(radius, 4000.0);
+    assert_eq!((ocean.scale, ocean.intensity, ocean.dps), (250.0, 9.0, 5.0));
+    // Weights from both are kept, the flag winning where they disagree
+    assert_eq!(config.spawn_weights[ShipType::Escort as usize], 0);
+    assert_eq!(config.spawn_weights[ShipType::Destroyer as usize], 7);
+    assert_eq!(config.spawn_weights[ShipType::PTBoat as usize], 10);
+  }
+
+  #[test]
+  fn bad_flags_are_rejected() {
+    assert!(matches!(
+      invalid("", &["--fast"]),
+      ConfigError::UnknownFlag(_)
+    ));
+    assert!(matches!(
+      invalid("", &["--port"]),
+      ConfigError::MissingValue(_)
+    ));
+    assert!(matches!(
+      invalid("", &["--port", "70000"]),
+      ConfigError::InvalidValue(..)
+    ));
+    assert!(matches!(
+      invalid("", &["--weight", "destroyer"]),
+      ConfigError::InvalidValue(..)
+    ));
+    assert!(matches!(invalid("speed = 30", &[]), ConfigError::Parse(..)));
+  }
+
+  #[test]
+  fn values_are_range_checked() {
+    assert!(chance("chance", 0.0).is_ok() && chance("chance", 1.0).is_ok());
+    assert!(chance("chance", 1.5).is_err() && chance("chance", -0.1).is_err());
+    assert!(non_negative("value", 0.0).is_ok() && non_negative("value", -1.0).is_err());
+    assert!(positive("value", 0.1).is_ok() && positive("value", 0.0).is_err());
+    for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+      assert!(check("value", value, true, "a number").is_err());
+    }
+    assert!(rejects(
+      "[map]\nkraken_spawn_chance = 2.0",
+      "map.kraken_spawn_chance"
+    ));
+    assert!(rejects("[map]\nradius = 0.0", "map.radius"));
+    assert!(rejects("gun_accuracy = 1.0", "gun_accuracy"));
+    assert!(rejects("teams = 0", "teams"));
+    assert!(rejects("name = \"Two\\nlines\"", "name"));
+    assert!(rejects("colour = \"blue\"", "colour"));
+    assert!(rejects("[spawn_weights]\nrowboat = 1", "spawn_weights"));
+    assert!(rejects("[current]\nkind = \"grid\"", "current.grid"));
+  }
+
+  #[test]
+  fn ports_cannot_clash() {
+    assert!(rejects(
+      "port = 2000\nwebsocket_port = 2000",
+      "websocket_port"
+    ));
+    let rcon = "[rcon]\npassword = \"change me\"\nport = ";
+    assert!(rejects(&format!("port = 2000\n{rcon}2000"), "rcon.port"));
+    assert!(rejects(
+      &format!("websocket_port = 2001\n{rcon}2001"),
+      "rcon.port"
+    ));
+    let config = valid(
+      &format!("port = 2000\nwebsocket_port = 2001\n{rcon}2002"),
+      &[],
+    );
+    assert_eq!(config.websocket, Some("0.0.0.0:2001".parse().unwrap()));
+  }
+
+  #[test]
+  fn remote_admin_needs_a_password_and_stays_local() {
+    assert!(rejects("[rcon]\nport = 25575", "rcon.password"));
+    assert!(matches!(
+      invalid("", &["--rcon-port", "25575", "--rcon-password", "secret"]),
+      ConfigError::UnknownFlag(_)
+    ));
+    assert!(rejects(
+      "[rcon]\nport = 25575\npassword = \"\"",
+      "rcon.password"
+    ));
+    let rcon = |file: &str| {
+      valid(file, &[])
+        .rcon
+        .expect("remote admin should be enabled")
+    };
+    let config = rcon("bind = \"0.0.0.0\"\n[rcon]\nport = 25575\npassword = \"change me\"");
+    assert_eq!(config.address, "127.0.0.1:25575".parse().unwrap());
+    assert_eq!(config.password, "change me");
+    let config = rcon("[rcon]\nbind = \"0.0.0.0\"\nport = 25575\npassword = \"change me\"");
+    assert_eq!(config.address, "0.0.0.0:25575".parse().unwrap());
+  }
+}
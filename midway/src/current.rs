@@ -0,0 +1,143 @@
+//! Ocean currents, which carry ships and everything they leave in the water along with them
+use serde::Deserialize;
+
+/// A grid of current velocities, loaded from a TOML file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CurrentGrid {
+  // Where the first velocity of the first row is, in metres
+  pub origin: (f32, f32),
+  // Metres between neighbouring velocities
+  pub spacing: f32,
+  // Rows run south and each row runs east, with velocities the same way round as ship coordinates
+  pub velocities: Vec<Vec<(f32, f32)>>,
+}
+
+impl CurrentGrid {
+  /// Says what is wrong with the grid, if anything
+  pub fn check(&self) -> Result<(), String> {
+    let columns = self.velocities.first().map_or(0, Vec::len);
+    if columns == 0 {
+      return Err("velocities must have at least one row and column".to_owned());
+    }
+    if self.velocities.iter().any(|row| row.len() != columns) {
+      return Err("every row of velocities must be the same length".to_owned());
+    }
+    if !(self.spacing.is_finite() && self.spacing > 0.0) {
+      return Err(format!("spacing {} must be greater than 0", self.spacing));
+    }
+    let finite = |(x, y): (f32, f32)| x.is_finite() && y.is_finite();
+    if !finite(self.origin) || !self.velocities.iter().flatten().all(|v| finite(*v)) {
+      return Err("origin and velocities must be numbers".to_owned());
+    }
+    Ok(())
+  }
+
+  // Bilinear between the four nearest velocities, still water outside the grid
+  fn at(&self, (x, y): (f32, f32)) -> (f32, f32) {
+    let column = (x - self.origin.0) / self.spacing;
+    let row = (y - self.origin.1) / self.spacing;
+    let rows = self.velocities.len();
+    let columns = self.velocities[0].len();
+    if !(0.0..=(columns - 1) as f32).contains(&column) || !(0.0..=(rows - 1) as f32).contains(&row)
+    {
+      return (0.0, 0.0);
+    }
+    let (left, top) = (column as usize, row as usize);
+    let (right, bottom) = ((left + 1).min(columns - 1), (top + 1).min(rows - 1));
+    let (across, down) = (column.fract(), row.fract());
+    let lerp =
+      |a: (f32, f32), b: (f32, f32), t: f32| (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+    let upper = lerp(
+      self.velocities[top][left],
+      self.velocities[top][right],
+      across,
+    );
+    let lower = lerp(
+      self.velocities[bottom][left],
+      self.velocities[bottom][right],
+      across,
+    );
+    lerp(upper, lower, down)
+  }
+}
+
+pub enum Current {
+  Still,
+  // Metres per second, the same way round as ship coordinates
+  Uniform((f32, f32)),
+  // Clockwise around the origin, fastest at the radius and slowing towards the middle and beyond
+  Gyre { speed: f32, radius: f32 },
+  Grid(CurrentGrid),
+}
+
+impl Current {
+  /// Velocity of the water at a point, in metres per second
+  pub fn at(&self, coords: (f32, f32)) -> (f32, f32) {
+    match self {
+      Self::Still => (0.0, 0.0),
+      Self::Uniform(velocity) => *velocity,
+      Self::Gyre { speed, radius } => {
+        let (x, y) = coords;
+        let distance = x.hypot(y);
+        if distance == 0.0 {
+          return (0.0, 0.0);
+        }
+        let speed = if distance < *radius {
+          speed * distance / radius
+        } else {
+          speed * radius / distance
+        };
+        // A quarter turn clockwise from pointing away from the origin, y being south
+        (-y / distance * speed, x / distance * speed)
+      }
+      Self::Grid(grid) => grid.at(coords),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_close((x, y): (f32, f32), (expected_x, expected_y): (f32, f32)) {
+    assert!(
+      (x - expected_x).abs() < 1e-4 && (y - expected_y).abs() < 1e-4,
+      "({x}, {y}) isn't ({expected_x}, {expected_y})"
+    );
+  }
+
+  #[test]
+  fn gyres_turn_clockwise() {
+    let gyre = Current::Gyre {
+      speed: 2.0,
+      radius: 1000.0,
+    };
+    // North of the middle the water heads east, and east of it south
+    assert_close(gyre.at((0.0, -1000.0)), (2.0, 0.0));
+    assert_close(gyre.at((1000.0, 0.0)), (0.0, 2.0));
+    assert_close(gyre.at((500.0, 0.0)), (0.0, 1.0));
+    assert_close(gyre.at((2000.0, 0.0)), (0.0, 1.0));
+    assert_close(gyre.at((0.0, 0.0)), (0.0, 0.0));
+  }
+
+  #[test]
+  fn grids_are_interpolated() {
+    let grid = CurrentGrid {
+      origin: (-100.0, -100.0),
+      spacing: 100.0,
+      velocities: vec![vec![(0.0, 0.0), (1.0, 0.0)], vec![(0.0, 1.0), (1.0, 1.0)]],
+    };
+    assert!(grid.check().is_ok());
+    assert_close(grid.at((-50.0, -100.0)), (0.5, 0.0));
+    assert_close(grid.at((-50.0, -50.0)), (0.5, 0.5));
+    assert_close(grid.at((0.0, 0.0)), (1.0, 1.0));
+    // Still water off the edges
+    assert_close(grid.at((50.0, 0.0)), (0.0, 0.0));
+    let ragged = CurrentGrid {
+      velocities: vec![vec![(0.0, 0.0), (1.0, 0.0)], vec![(0.0, 1.0)]],
+      ..grid
+    };
+    assert!(ragged.check().is_err());
+  }
+}
@@ -0,0 +1,80 @@
+//! Splashes, smoke and wakes made during a tick, sent to every client along with the snapshot
+use crate::client::ClientData;
+use protocol::{Capability, ServerMessage, Splash, Wake};
+
+pub struct Effects {
+  drifting: Vec<ServerMessage>,
+  // For clients that don't know effects can move, which see them hang where they were made
+  still: Vec<ServerMessage>,
+}
+
+// The same effect, staying put
+fn without_drift(effect: &ServerMessage) -> ServerMessage {
+  match effect {
+    ServerMessage::Splash(splash) => ServerMessage::Splash(Splash {
+      drift: (0.0, 0.0),
+      ..splash.clone()
+    }),
+    ServerMessage::Wake(wake) => ServerMessage::Wake(Wake {
+      drift: (0.0, 0.0),
+      ..wake.clone()
+    }),
+    effect => effect.clone(),
+  }
+}
+
+impl Effects {
+  /// Takes every effect of the tick at once, so none can be left out of the still ones
+  pub fn new(effects: Vec<ServerMessage>) -> Self {
+    Self {
+      still: effects.iter().map(without_drift).collect(),
+      drifting: effects,
+    }
+  }
+
+  pub fn for_client(&self, connection: &ClientData) -> &[ServerMessage] {
+    if connection.supports(Capability::Weather) {
+      &self.drifting
+    } else {
+      &self.still
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::outbox::outbox;
+  use std::sync::mpsc::channel;
+
+  fn client(capabilities: Vec<Capability>) -> ClientData {
+    let (tx, _) = outbox();
+    let (_, rx) = channel();
+    ClientData::new(tx, rx, None, None, capabilities)
+  }
+
+  #[test]
+  fn clients_without_weather_get_every_effect_still() {
+    let wake = Wake {
+      x: 10.0,
+      y: 20.0,
+      size: 18.0,
+      angle: 0.5,
+      duration: 7.5,
+      growth: 4.0,
+      drift: (2.0, 3.0),
+    };
+    let effects = Effects::new(vec![ServerMessage::Wake(wake.clone())]);
+    assert_eq!(
+      effects.for_client(&client(vec![Capability::Weather])),
+      [ServerMessage::Wake(wake.clone())]
+    );
+    assert_eq!(
+      effects.for_client(&client(Vec::new())),
+      [ServerMessage::Wake(Wake {
+        drift: (0.0, 0.0),
+        ..wake
+      })]
+    );
+  }
+}
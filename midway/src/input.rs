@@ -0,0 +1,137 @@
+//! Checks everything a client sends before the game sees it
+use crate::client::Incoming;
+use protocol::{ClientMessage, ParseError};
+use std::io::{BufRead, Read};
+use std::time::Instant;
+
+// Enterprise sends a sail every frame and an ack every tick, so leave room for fast monitors
+const MESSAGE_RATE: f32 = 500.0;
+const MESSAGE_BURST: f32 = 1000.0;
+// Well behaved clients shouldn't send bad messages at all
+const ERROR_RATE: f32 = 2.0;
+const ERROR_BURST: f32 = 20.0;
+
+// Bytes in the longest text line anything is read, well over a chat message of wide characters
+pub const MAX_LINE_LENGTH: usize = 4096;
+
+const MIN_POWER: f32 = -0.5;
+const MAX_POWER: f32 = 1.0;
+
+pub struct TokenBucket {
+  tokens: f32,
+  capacity: f32,
+  // Tokens per second
+  rate: f32,
+  last: Instant,
+}
+
+impl TokenBucket {
+  pub const fn new(rate: f32, capacity: f32, now: Instant) -> Self {
+    Self {
+      tokens: capacity,
+      capacity,
+      rate,
+      last: now,
+    }
+  }
+
+  pub fn take(&mut self, now: Instant) -> bool {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f32();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+    self.last = now;
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+/// Reads a line like `BufRead::read_line`, but won't buffer more than MAX_LINE_LENGTH of it
+///
+/// Errors with why the sender should be cut off, or None if the connection broke.
+pub fn read_line(
+  reader: &mut impl BufRead,
+  buf: &mut String,
+) -> Result<usize, Option<&'static str>> {
+  let limit = MAX_LINE_LENGTH as u64;
+  let chars = reader.take(limit).read_line(buf).map_err(|_| None)?;
+  if chars == MAX_LINE_LENGTH && !buf.ends_with('\n') {
+    return Err(Some("Line too long"));
+  }
+  Ok(chars)
+}
+
+fn finite(message: &'static str, field: &'static str, value: f32) -> Result<f32, ParseError> {
+  if value.is_finite() {
+    Ok(value)
+  } else {
+    Err(ParseError::InvalidField(message, field, value.to_string()))
+  }
+}
+
+/// Rejects values no honest client sends, and clamps the rest into range
+fn validate(message: ClientMessage) -> Incoming {
+  match message {
+    ClientMessage::Sail(power, helm) => {
+      let power = finite("sail", "power", power)?;
+      let helm = finite("sail", "helm", helm)?;
+      Ok(ClientMessage::Sail(
+        power.clamp(MIN_POWER, MAX_POWER),
+        helm.clamp(-1.0, 1.0),
+      ))
+    }
+    // Actions are numbered from 1, whether the ship has that many is checked in the game
+    ClientMessage::Action(0) => Err(ParseError::InvalidField("action", "action", "0".to_owned())),
+    message => Ok(message),
+  }
+}
+
+/// Per client rate limits, along with validation of each message
+pub struct InputFilter {
+  messages: TokenBucket,
+  errors: TokenBucket,
+}
+
+impl InputFilter {
+  pub const fn new(now: Instant) -> Self {
+    Self {
+      messages: TokenBucket::new(MESSAGE_RATE, MESSAGE_BURST, now),
+      errors: TokenBucket::new(ERROR_RATE, ERROR_BURST, now),
+    }
+  }
+
+  /// Returns the message to pass on, or why the client should be disconnected
+  pub fn check(&mut self, message: Incoming, now: Instant) -> Result<Incoming, &'static str> {
+    if !self.messages.take(now) {
+      return Err("Too many messages");
+    }
+    let message = message.and_then(validate);
+    if message.is_err() && !self.errors.take(now) {
+      return Err("Too many bad messages");
+    }
+    Ok(message)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn buckets_refill_over_time() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(10.0, 2.0, start);
+    assert!(bucket.take(start));
+    assert!(bucket.take(start));
+    assert!(!bucket.take(start));
+    assert!(bucket.take(start + Duration::from_millis(100)));
+    // Never more than the capacity, however long the client waits
+    let later = start + Duration::from_secs(60);
+    assert!(bucket.take(later));
+    assert!(bucket.take(later));
+    assert!(!bucket.take(later));
+  }
+}
@@ -1,72 +1,67 @@
 //! Server for WW2 naval combat simulator
-use crate::stats::{get_random_ship, Action, ShipStats, Variable};
-use client::{process_joining, ClientData, ClientMessage};
+use crate::admin::{process_commands, process_console};
+use crate::chat::{check, deliver};
+use crate::config::DuplicateNames;
+use crate::config::{BorderType, Config, ConfigError};
+use crate::effects::Effects;
+use crate::stats::{get_random_type, get_stats, Action, ShipStats, ShipType, Variable};
+use client::{process_joining, ClientData, Joining, Request, MAX_NAME_LENGTH};
+use physics::{throttle, Footprint, Hull, Machinery, Motion};
+use protocol::{
+  Capability, ClientMessage, Colour, ErrorCode, ParseError, ServerMessage, ShipState, Splash, Wake,
+};
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use rcon::process_rcon;
+use snapshot::visible;
+use std::collections::{HashMap, HashSet};
+use std::env::args;
 use std::f32::consts::PI;
-use std::io::Write;
-use std::net::TcpStream;
-use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::net::{IpAddr, TcpListener, UdpSocket};
+use std::process::exit;
+use std::sync::mpsc::{channel, RecvTimeoutError, TryRecvError};
 use std::thread::{sleep, spawn};
 use std::time::{Duration, Instant};
+use udp::{process_udp, send_state};
+use weather::Weather;
+use websocket::process_websocket_joining;
 
+mod admin;
+mod chat;
 mod client;
+mod config;
+mod current;
+mod effects;
+mod input;
+mod outbox;
+mod rcon;
+mod snapshot;
 mod stats;
+mod udp;
+mod weather;
+mod websocket;
 
-const TIME_ACCELERATION_FACTOR: f32 = 4.0;
-const TPS: u32 = 60;
-const RESPAWN_COOLDOWN: u32 = 120;
-
-const COLOUR: &str = "999";
-
-const MAP_RADIUS: Option<(f32, BorderType)> = Some((
-  2000.0,
-  BorderType::Ocean(OceanData {
-    kraken_spawn_chance: 0.01,
-    mine_spawn_chance: 0.0001,
-    mine_damage: 2000.0,
-    scale: 500.0,
-    intensity: 18.0,
-    dps: 5.0,
-  }),
-));
 const KRAKEN_NAME: &str = "Kraken";
+const RED: Colour = Colour::new(255, 0, 0, 255);
+const WHITE: Colour = Colour::new(255, 255, 255, 255);
+const SMOKE: Colour = Colour::new(0, 0, 0, 0x99);
 
-const WATER_VISCOSITY: f32 = 0.000_001;
-const GRAVITY: f32 = 9.81;
-const GUN_ACCURACY: f32 = 0.01;
-
-#[allow(unused)]
-enum BorderType {
-  Ocean(OceanData),
-  Land,
-}
-
-struct OceanData {
-  kraken_spawn_chance: f32,
-  mine_spawn_chance: f32,
-  mine_damage: f32,
-  scale: f32,
-  intensity: f32,
-  dps: f32,
-}
-
-// Works on negative numbers too
-fn cube_root(x: f32) -> f32 {
-  let result = x.abs().powf(1.0 / 3.0);
-  result.copysign(x)
-}
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Clone)]
 struct Ship {
   coords: (f32, f32),
   velocity: f32,
   angle: f32,
+  yaw_rate: f32,
+  drift: f32,
+  // Orders from the player, which the rudder and engine follow
   helm: f32,
   power: f32,
+  machinery: Machinery,
   stats: ShipStats,
+  // None for the kraken
+  class: Option<ShipType>,
   sunk: bool,
   submerged: bool,
   smoke: bool,
@@ -74,65 +69,125 @@ struct Ship {
 }
 
 impl Ship {
-  fn new() -> Self {
+  fn new(config: &Config) -> Self {
     let mut rng = thread_rng();
     let angle = rng.gen_range(0.0..(2.0 * PI));
     let distance = rng.gen_range(0.0..1000.0);
     let x = distance * angle.cos();
     let y = distance * angle.sin();
-    let stats = get_random_ship();
+    let class = get_random_type(&config.spawn_weights);
     Self {
       coords: (x, y),
       velocity: 0.0,
       angle: 0.0,
+      yaw_rate: 0.0,
+      drift: 0.0,
+      helm: 0.0,
+      power: 0.0,
+      machinery: Machinery::default(),
+      stats: get_stats(class),
+      class: Some(class),
+      sunk: false,
+      submerged: false,
+      smoke: false,
+      respawn_cooldown: config.respawn_cooldown,
+    }
+  }
+
+  fn kraken(coords: (f32, f32), scale_factor: f32, config: &Config) -> Self {
+    let scale_factor_sqrt = scale_factor.sqrt();
+    let size = 60.0 * scale_factor_sqrt;
+    let stats = ShipStats::new(
+      8,
+      size,
+      size,
+      3000.0 * scale_factor,
+      0.0,
+      0.0,
+      1000.0,
+      0.0,
+      0.0,
+      2.2,
+      0.0,
+      0.0,
+      100.0 * scale_factor_sqrt,
+      100.0 * scale_factor_sqrt,
+      0.5..1.5,
+      Vec::new(),
+    );
+    Self {
+      coords,
+      velocity: 0.0,
+      angle: 0.0,
+      yaw_rate: 0.0,
+      drift: 0.0,
       helm: 0.0,
       power: 0.0,
+      machinery: Machinery::default(),
       stats,
+      class: None,
       sunk: false,
       submerged: false,
       smoke: false,
-      respawn_cooldown: RESPAWN_COOLDOWN,
+      respawn_cooldown: config.respawn_cooldown,
     }
   }
 
-  fn step(&mut self, delta_t: f32) {
+  fn step(&mut self, delta_t: f32, weather: &Weather) {
     self.stats.cooldown -= delta_t;
-    self.angle += delta_t * self.helm * self.velocity * 2.0 / self.stats.turning_circle;
-    let reynolds_number = self.stats.length * self.velocity.abs() / WATER_VISCOSITY;
-    let c_f = 0.075 / (reynolds_number.log10() - 2.0).powi(2);
-    let mut c_total = c_f * (1.0 + self.stats.k);
-    if !self.submerged {
-      let froude_number = self.velocity / (GRAVITY * self.stats.length).sqrt();
-      let c_w = self.stats.froude_scale_factor * froude_number.powi(6);
-      c_total += c_w;
+    let mut motion = self.motion();
+    let hull = self.hull(weather);
+    hull.tick(
+      &mut motion,
+      &mut self.machinery,
+      self.power,
+      self.helm,
+      delta_t,
+    );
+    Motion {
+      coords: self.coords,
+      angle: self.angle,
+      velocity: self.velocity,
+      yaw_rate: self.yaw_rate,
+      drift: self.drift,
+    } = motion;
+  }
+
+  const fn motion(&self) -> Motion {
+    Motion {
+      coords: self.coords,
+      angle: self.angle,
+      velocity: self.velocity,
+      yaw_rate: self.yaw_rate,
+      drift: self.drift,
     }
-    let r_total = c_total * 0.5 * self.velocity * self.velocity.abs() * self.surface_area();
-    let net_power = self.current_power();
-    let q = net_power / self.stats.screw_area;
-    let s = q.powi(2) - self.velocity.powi(6) / 27.0;
-    let v_out = match s.total_cmp(&0.0) {
-      Ordering::Equal => 2.0 * cube_root(q),
-      Ordering::Greater => {
-        let s = s.sqrt();
-        cube_root(q + s) + cube_root(q - s)
-      }
-      Ordering::Less => {
-        let v_abs = self.velocity.abs();
-        let multiplier = (2.0 * v_abs / 3.0_f32.sqrt()).copysign(q);
-        let angle = (q.abs() * 27.0_f32.sqrt() / v_abs.powi(3)).acos();
-        multiplier * (angle / 3.0).cos()
-      }
-    };
-    let thrust = self.stats.screw_area * v_out * (v_out - self.velocity).abs();
-    let net_thrust = thrust - r_total;
-    self.velocity += net_thrust * delta_t / self.current_mass();
-    self.coords.0 += self.velocity * delta_t * self.angle.sin();
-    self.coords.1 -= self.velocity * delta_t * self.angle.cos();
+  }
+
+  /// Carries the ship bodily at a velocity, whatever it is doing through the water
+  fn drift(&mut self, (x, y): (f32, f32), delta_t: f32) {
+    self.coords.0 += x * delta_t;
+    self.coords.1 += y * delta_t;
+  }
+
+  /// Brings the ship to a dead stop, turning included
+  fn stop(&mut self) {
+    self.velocity = 0.0;
+    self.yaw_rate = 0.0;
+    self.drift = 0.0;
+  }
+
+  fn footprint(&self) -> Footprint {
+    Footprint::new(&self.motion(), self.stats.length, self.stats.beam)
+  }
+
+  /// How the ship moves right now, which depends on whether it is submerged
+  fn hull(&self, weather: &Weather) -> Hull {
+    self.stats.hull(self.submerged, weather.wave_height())
   }
 
   #[must_use]
-  fn energy(&self) -> f32 {
-    0.5 * self.current_mass() * self.velocity.powi(2)
+  fn energy(&self, weather: &Weather) -> f32 {
+    self.hull(weather).energy(self.velocity)
   }
 
   #[must_use]
@@ -156,6 +211,25 @@ impl Ship {
     self.sunk
   }
 
+  fn state(&self, name: &str, colour: Colour) -> ShipState {
+    let (x, y) = self.coords;
+    ShipState {
+      name: name.to_owned(),
+      x,
+      y,
+      angle: self.angle,
+      velocity: self.velocity,
+      size: self.stats.length,
+      texture: self.stats.texture,
+      colour,
+      health: (self.stats.health / self.current_mass()).max(0.0),
+    }
+  }
+
+  fn repair(&mut self) {
+    self.stats.health = self.stats.mass.get_value(false);
+  }
+
   #[must_use]
   fn random_location(&self) -> (f32, f32) {
     let mut rng = thread_rng();
@@ -165,31 +239,23 @@ impl Ship {
     let min_beam_offset = -max_beam_offset;
     let length = rng.gen_range(min_length_offset..max_length_offset);
     let beam = rng.gen_range(min_beam_offset..max_beam_offset);
-    let x = self.coords.0 + self.angle.sin() * length + self.angle.cos() * beam;
-    let y = self.coords.1 - self.angle.cos() * length + self.angle.sin() * beam;
-    (x, y)
+    self.footprint().point(length, beam)
   }
 
   #[must_use]
-  fn is_hit(&self, mut x: f32, mut y: f32) -> bool {
-    x -= self.coords.0;
-    y -= self.coords.1;
-    let distance = x.hypot(y);
-    let angle = x.atan2(y) - self.angle;
-    let x_offset = distance * angle.sin();
-    let y_offset = distance * angle.cos();
-    x_offset.abs() <= self.stats.beam / 2.0 && y_offset.abs() <= self.stats.length / 2.0
+  fn is_hit(&self, x: f32, y: f32) -> bool {
+    self.footprint().contains((x, y))
   }
 
   #[must_use]
-  fn shoot(&mut self, target: &mut Self) -> ShootingState {
+  fn shoot(&mut self, target: &mut Self, accuracy: f32) -> ShootingState {
     if self.stats.cooldown <= 0.0 {
       let target_location = target.random_location();
       let x_offset = target_location.0 - self.coords.0;
       let y_offset = target_location.1 - self.coords.1;
       let mut rng = thread_rng();
-      let distance = x_offset.hypot(y_offset) * (1.0 - rng.gen_range(-GUN_ACCURACY..GUN_ACCURACY));
-      let angle = x_offset.atan2(y_offset) + rng.gen_range(-GUN_ACCURACY..GUN_ACCURACY);
+      let distance = x_offset.hypot(y_offset) * (1.0 - rng.gen_range(-accuracy..accuracy));
+      let angle = x_offset.atan2(y_offset) + rng.gen_range(-accuracy..accuracy);
       let x_offset = distance * angle.sin();
       let y_offset = distance * angle.cos();
       let coords = (self.coords.0 + x_offset, self.coords.1 + y_offset);
@@ -210,16 +276,12 @@ impl Ship {
   }
 
   fn current_power(&self) -> f32 {
-    self.power * self.stats.power.get_value(self.submerged)
+    self.machinery.engine * self.stats.power.get_value(self.submerged)
   }
 
   fn current_mass(&self) -> f32 {
     self.stats.mass.get_value(self.submerged)
   }
-
-  fn surface_area(&self) -> f32 {
-    self.stats.surface_area.get_value(self.submerged)
-  }
 }
 
 enum ShootingState {
@@ -230,66 +292,329 @@ enum ShootingState {
 }
 
 fn handle_join(
+  config: &Config,
   connections: &mut HashMap<String, ClientData>,
-  mut stream: TcpStream,
-  rx: Receiver<ClientMessage>,
-  name: String,
+  bans: &HashSet<IpAddr>,
+  weather: &Weather,
+  joining: Joining,
 ) {
-  let address = stream
-    .peer_addr()
-    .map(|x| x.to_string())
-    .unwrap_or("unknown".to_owned());
-  println!("{address} joined as {name}");
-  let ship = Ship::new();
-  if let Some((radius, ..)) = MAP_RADIUS {
-    stream
-      .write_all(format!("radius {radius}\n").as_bytes())
-      .ok();
+  let Joining {
+    tx,
+    rx,
+    peer,
+    request,
+    capabilities,
+  } = joining;
+  if let Some(peer) = peer {
+    if bans.contains(&peer.ip()) {
+      println!("Refused banned address {peer}");
+      tx.send(ServerMessage::Reject(
+        "You are banned from this server".to_owned(),
+      ));
+      return;
+    }
+  }
+  let address = peer.map_or("unknown".to_owned(), |x| x.to_string());
+  let mut notices = Vec::new();
+  let (name, ship, team) = match request {
+    Request::Resume(session) => {
+      let resumed = connections
+        .iter()
+        .find(|(_, connection)| connection.ship.is_some() && connection.session == session)
+        .map(|(name, _)| name.clone());
+      let Some(name) = resumed else {
+        println!("{address} can't resume a ship: the session has expired");
+        tx.send(ServerMessage::Reject(
+          "Your ship is no longer in the water".to_owned(),
+        ));
+        return;
+      };
+      // Replaces the old connection too, in case it hasn't noticed that it dropped
+      let previous = connections.remove(&name).expect("Resumed ship vanished");
+      println!("{address} resumed {name}");
+      (name, previous.ship, previous.team)
+    }
+    Request::Ship(name) => {
+      let name = if connections.contains_key(&name) {
+        match config.duplicate_names {
+          DuplicateNames::Reject => {
+            println!("{address} can't join as {name}: the name is taken");
+            tx.send(ServerMessage::Reject(format!(
+              "There is already a ship called {name}"
+            )));
+            return;
+          }
+          DuplicateNames::Rename => {
+            let unique = unique_name(connections, &name);
+            notices.push(format!(
+              "There is already a ship called {name}, so you are {unique}"
+            ));
+            unique
+          }
+        }
+      } else {
+        name
+      };
+      println!("{address} joined as {name}");
+      let team = smallest_team(config.teams, connections);
+      if config.teams > 1 {
+        notices.push(format!("You are on team {team}"));
+      }
+      notices.extend(config.motd.clone());
+      (name, Some(Ship::new(config)), Some(team))
+    }
+    Request::Spectate => {
+      let name = spectator_name(connections);
+      println!("{address} is spectating as {name}");
+      notices.extend(config.motd.clone());
+      (name, None, None)
+    }
+  };
+  let mut client = ClientData::new(tx, rx, peer, ship, capabilities);
+  client.team = team;
+  // Spectators are welcomed too, so they know who they are in chat
+  if client.supports(Capability::Welcome) {
+    client.tx.send(ServerMessage::Welcome(name.clone()));
+  }
+  // but they have no ship to come back to
+  if client.ship.is_some() && client.supports(Capability::Session) {
+    client.tx.send(ServerMessage::Session(client.session));
+  }
+  if client.datagrams() {
+    client.tx.send(ServerMessage::Udp(client.token));
+  }
+  if client.supports(Capability::Predict) {
+    let clock = ServerMessage::Clock(config.tps, config.time_acceleration_factor);
+    client.tx.send(clock);
+  }
+  if let Some((radius, ..)) = config.map_radius {
+    client.tx.send(ServerMessage::Radius(radius));
+  }
+  if client.supports(Capability::Weather) {
+    client.tx.send(weather.message());
+  }
+  for notice in notices {
+    client.notice(&notice);
+  }
+  connections.insert(name, client);
+}
+
+/// Leaves the ship of a player who lost the connection in the water, if they can come back for it
+fn keep_ship(grace: u32, name: &str, connection: &mut ClientData) -> bool {
+  if grace == 0 || connection.ship.is_none() || !connection.supports(Capability::Session) {
+    return false;
   }
-  let client = ClientData::new(stream, rx, ship);
-  connections.entry(name).or_insert(client);
+  println!("Keeping {name}'s ship in the water for them to resume");
+  connection.detach(grace);
+  true
+}
+
+// Adds a number to the end of a name until it is free
+fn unique_name(connections: &HashMap<String, ClientData>, name: &str) -> String {
+  (2..)
+    .map(|i| {
+      let suffix = i.to_string();
+      // Names are ascii, so any byte index is a character boundary
+      let base = &name[..name.len().min(MAX_NAME_LENGTH - suffix.len())];
+      format!("{base}{suffix}")
+    })
+    .find(|candidate| !connections.contains_key(candidate))
+    .expect("Ran out of names")
+}
+
+// Puts new players on the team with the fewest players, teams are numbered from 1
+fn smallest_team(teams: u32, connections: &HashMap<String, ClientData>) -> u32 {
+  (1..=teams)
+    .min_by_key(|team| {
+      connections
+        .values()
+        .filter(|connection| connection.team == Some(*team))
+        .count()
+    })
+    .expect("No teams")
+}
+
+// Ship names can't contain #, so spectators never take a name a player might want
+fn spectator_name(connections: &HashMap<String, ClientData>) -> String {
+  (1..)
+    .map(|i| format!("spectator#{i}"))
+    .find(|candidate| !connections.contains_key(candidate))
+    .expect("Ran out of names")
 }
 
 fn main() {
+  let config = match Config::load(args().skip(1)) {
+    Ok(config) => config,
+    Err(ConfigError::Help) => {
+      println!("{}", ConfigError::Help);
+      return;
+    }
+    Err(error) => {
+      eprintln!("{error}");
+      exit(1);
+    }
+  };
+  let listener = match TcpListener::bind(config.address) {
+    Ok(listener) => listener,
+    Err(error) => {
+      eprintln!("Failed to bind to {}: {error}", config.address);
+      exit(1);
+    }
+  };
+  // State updates go out on the same port, for clients that ask for them
+  let socket = match UdpSocket::bind(config.address) {
+    Ok(socket) => socket,
+    Err(error) => {
+      eprintln!("Failed to bind UDP to {}: {error}", config.address);
+      exit(1);
+    }
+  };
+  println!("Listening on {}", config.address);
+  let (udp_tx, udp_rx) = channel();
+  let udp_socket = socket.try_clone().expect("try-clone broke");
+  spawn(move || process_udp(&udp_tx, &udp_socket));
   let (tx, rx) = channel();
-  spawn(move || process_joining(&tx));
+  let server_name = config.name.clone();
+  if let Some(address) = config.websocket {
+    let listener = match TcpListener::bind(address) {
+      Ok(listener) => listener,
+      Err(error) => {
+        eprintln!("Failed to bind WebSocket to {address}: {error}");
+        exit(1);
+      }
+    };
+    println!("WebSocket listening on {address}");
+    let tx = tx.clone();
+    let server_name = server_name.clone();
+    spawn(move || process_websocket_joining(&tx, &listener, &server_name));
+  }
+  spawn(move || process_joining(&tx, &listener, &server_name));
+  let (admin_tx, admin_rx) = channel();
+  if let Some(rcon) = &config.rcon {
+    let listener = match TcpListener::bind(rcon.address) {
+      Ok(listener) => listener,
+      Err(error) => {
+        eprintln!("Failed to bind remote admin to {}: {error}", rcon.address);
+        exit(1);
+      }
+    };
+    println!("Remote admin listening on {}", rcon.address);
+    let admin_tx = admin_tx.clone();
+    let password = rcon.password.clone();
+    spawn(move || process_rcon(&admin_tx, &listener, &password));
+  }
+  spawn(move || process_console(&admin_tx));
   let mut connections = HashMap::new();
-  let (stream, rx_2, name) = rx.recv().expect("Could not start server");
-  handle_join(&mut connections, stream, rx_2, name);
-  let delay = Duration::from_secs(1) / TPS;
-  let delta_t = TIME_ACCELERATION_FACTOR / TPS as f32;
+  let mut bans = HashSet::new();
+  let mut mutes = HashSet::new();
+  let tps = config.tps;
+  let time_acceleration_factor = config.time_acceleration_factor;
+  let delay = Duration::from_secs(1) / tps;
+  let delta_t = time_acceleration_factor / tps as f32;
+  let reconnect_grace = (config.reconnect_grace * tps as f32).round() as u32;
   let mut kraken: Option<Ship> = None;
   let mut kraken_cooldown = 0.0;
+  let mut tick: u32 = 0;
+  let mut weather = Weather::new(config.wind);
   loop {
+    if connections.is_empty() {
+      // Nobody is playing, so wait for someone to join instead of ticking an empty ocean
+      if config.persistent {
+        println!("Waiting for players");
+      }
+      while connections.is_empty() {
+        match rx.recv_timeout(IDLE_POLL_INTERVAL) {
+          Ok(joining) => handle_join(&config, &mut connections, &bans, &weather, joining),
+          Err(RecvTimeoutError::Timeout) => (),
+          Err(RecvTimeoutError::Disconnected) => panic!("Stopped accepting connections"),
+        }
+        if !process_commands(
+          &admin_rx,
+          &config,
+          &mut connections,
+          &mut kraken,
+          &mut bans,
+          &mut mutes,
+        ) {
+          return;
+        }
+      }
+    }
     let start = Instant::now();
-    for _ in 0..TPS {
+    // Time spent on ticks rather than waiting for the next one
+    let mut busy = Duration::ZERO;
+    for _ in 0..tps {
       kraken_cooldown -= delta_t;
+      tick += 1;
+      weather.step(config.wind, delta_t);
       let start = Instant::now();
       // Process newly joining clients
-      for (stream, rx, name) in rx.try_iter() {
-        handle_join(&mut connections, stream, rx, name);
+      for joining in rx.try_iter() {
+        handle_join(&config, &mut connections, &bans, &weather, joining);
+      }
+      // Find out where to send datagrams, only from the address the client connected from
+      for (token, address) in udp_rx.try_iter() {
+        if let Some(connection) = connections
+          .values_mut()
+          .find(|connection| connection.token == token && connection.ip == Some(address.ip()))
+          .filter(|connection| connection.datagrams())
+        {
+          connection.udp = Some(address);
+        }
+      }
+      // Process admin commands
+      if !process_commands(
+        &admin_rx,
+        &config,
+        &mut connections,
+        &mut kraken,
+        &mut bans,
+        &mut mutes,
+      ) {
+        // Give the writer threads a moment to say goodbye
+        drop(connections);
+        sleep(Duration::from_millis(250));
+        return;
       }
       let mut disconnected = Vec::new();
       let mut sunk = Vec::new();
+      let mut chats = Vec::new();
       // get updates from clients
       for (name, connection) in &mut connections {
-        let ship = &mut connection.ship;
+        if let Some(grace) = &mut connection.detached {
+          if *grace == 0 {
+            println!("Nobody came back for {name}");
+            disconnected.push(name.clone());
+          } else {
+            *grace -= 1;
+          }
+          continue;
+        }
+        if connection.tx.overflowed() {
+          println!("Disconnected {name}: fell too far behind");
+          if !keep_ship(reconnect_grace, name, connection) {
+            disconnected.push(name.clone());
+          }
+          continue;
+        }
         loop {
-          match connection.rx.try_recv() {
-            Ok(ClientMessage::Sail(power, helm)) => {
-              ship.power = power * power.abs();
+          let message = connection.rx.try_recv();
+          match (message, connection.ship.as_mut()) {
+            (Ok(Ok(ClientMessage::Sail(power, helm))), Some(ship)) => {
+              ship.power = throttle(power);
               ship.helm = helm;
             }
-            Ok(ClientMessage::Anchor) => {
+            (Ok(Ok(ClientMessage::Anchor)), Some(ship)) => {
               if ship.velocity.abs() < 0.5 {
-                ship.velocity = 0.0;
+                ship.stop();
               }
             }
-            Ok(ClientMessage::Smoke) => {
+            (Ok(Ok(ClientMessage::Smoke)), Some(ship)) => {
               ship.smoke = !ship.smoke;
             }
-            Ok(ClientMessage::Action(action)) => {
-              if let Some(action) = ship.stats.actions.get(action - 1) {
+            (Ok(Ok(ClientMessage::Action(action))), Some(ship)) => {
+              let index = action.checked_sub(1);
+              if let Some(action) = index.and_then(|index| ship.stats.actions.get(index)) {
                 match *action {
                   Action::Submerge => {
                     ship.submerged = if ship.submerged {
@@ -302,33 +627,91 @@ fn main() {
                     }
                   }
                 }
+              } else {
+                let error = ParseError::InvalidField("action", "action", action.to_string());
+                connection.error(&error);
+              }
+            }
+            // Spectators have nothing to steer
+            (
+              Ok(Ok(
+                ClientMessage::Sail(..)
+                | ClientMessage::Anchor
+                | ClientMessage::Smoke
+                | ClientMessage::Action(_),
+              )),
+              None,
+            ) => (),
+            (Ok(Ok(ClientMessage::Ack(tick))), _) => connection.snapshots.ack(tick),
+            // Answered here rather than as it arrives, so the time includes waiting for a tick
+            (Ok(Ok(ClientMessage::Ping(timestamp))), _) => {
+              connection.tx.send(ServerMessage::Pong(timestamp));
+            }
+            (Ok(Ok(ClientMessage::Chat(scope, text))), _) => {
+              if connection.ip.is_some_and(|ip| mutes.contains(&ip)) {
+                connection.refuse(ErrorCode::Muted, "You have been muted".to_owned());
+              } else {
+                match check(connection, &text, Instant::now()) {
+                  Ok(()) => chats.push((name.clone(), scope, text)),
+                  Err((code, reason)) => connection.refuse(code, reason),
+                }
               }
             }
-            Err(TryRecvError::Empty) => break,
-            Err(TryRecvError::Disconnected) => {
+            // Only valid during the handshake
+            (
+              Ok(Ok(
+                ClientMessage::Hello(..)
+                | ClientMessage::Ship(_)
+                | ClientMessage::Resume(_)
+                | ClientMessage::Spectate,
+              )),
+              _,
+            ) => (),
+            (Ok(Err(error)), _) => {
+              println!("Bad message from {name}: {error}");
+              connection.error(&error);
+            }
+            (Err(TryRecvError::Empty), _) => break,
+            (Err(TryRecvError::Disconnected), _) => {
               println!("{name} has disconnected");
-              disconnected.push(name.clone());
+              if !keep_ship(reconnect_grace, name, connection) {
+                disconnected.push(name.clone());
+              }
               break;
             }
           }
         }
       }
-      for name in &disconnected {
-        connections.remove(name);
+      // Sent before anyone leaves, so a player's last words still get through
+      for (from, scope, text) in chats {
+        match deliver(&connections, &from, &scope, &text) {
+          Ok(()) => println!("[{scope}] {from}: {text}"),
+          Err(reason) => connections[&from].refuse(ErrorCode::InvalidMessage, reason),
+        }
       }
+      // Only ships need to be taken out of the water
+      disconnected.retain(|name| {
+        let connection = connections.remove(name).expect("Missing connection");
+        connection.ship.is_some()
+      });
       for name in disconnected {
         for connection in connections.values_mut() {
-          connection.tx.send(format!("sunk {name}\n")).ok();
+          connection.snapshots.sink(&name);
         }
       }
       let mut splashes = Vec::new();
       let mut wakes = Vec::new();
+      // Kept apart from the splashes since it blows away on the wind
+      let mut smoke = Vec::new();
       let mut kraken_targets = Vec::new();
+      let gun_accuracy = weather.gun_accuracy(config.gun_accuracy);
       for (name, connection) in &mut connections {
-        let ship = &mut connection.ship;
+        let Some(ship) = &mut connection.ship else {
+          continue;
+        };
         if ship.sunk {
           if ship.respawn_cooldown == 0 {
-            *ship = Ship::new();
+            *ship = Ship::new(&config);
           } else {
             ship.respawn_cooldown -= 1;
           }
@@ -338,37 +721,35 @@ fn main() {
         if let Some(ref mut kraken) = kraken {
           let distance = kraken.distance(ship);
           if distance < kraken.stats.gun_range {
-            ship.velocity = 0.0;
+            ship.stop();
             mobile = false;
             kraken_targets.push(name.clone());
           }
           if !ship.submerged && distance < ship.stats.gun_range {
-            match ship.shoot(kraken) {
+            match ship.shoot(kraken, gun_accuracy) {
               ShootingState::Sunk(location, damage) | ShootingState::Hit(location, damage) => {
                 let size = damage.powf(1.0 / 3.0) * 3.0;
-                splashes.push((location.0, location.1, size, 1.0, 0, "f00"));
+                splashes.push((location.0, location.1, size, 1.0, 0, RED));
                 let location = ship.random_location();
-                splashes.push((location.0, location.1, size, 1.0, 1, "fff"));
+                splashes.push((location.0, location.1, size, 1.0, 1, WHITE));
               }
               ShootingState::Miss(location, damage) => {
                 let size = damage.powf(1.0 / 3.0) * 3.0;
-                splashes.push((location.0, location.1, size, 1.0, 0, "fff"));
+                splashes.push((location.0, location.1, size, 1.0, 0, WHITE));
                 let location = ship.random_location();
-                splashes.push((location.0, location.1, size, 1.0, 1, "fff"));
+                splashes.push((location.0, location.1, size, 1.0, 1, WHITE));
               }
               ShootingState::NotFired => (),
             }
           }
         }
         let mut rng = thread_rng();
-        if ship.smoke && rng.gen_bool(f64::from(delta_t * ship.power.abs())) {
-          splashes.push((
+        if ship.smoke && rng.gen_bool(f64::from(delta_t * ship.machinery.engine.abs())) {
+          smoke.push((
             ship.coords.0,
             ship.coords.1,
             ship.current_power().abs().sqrt() * rng.gen_range(0.5..1.5),
             rng.gen_range(30.0..180.0),
-            2,
-            "0009",
           ));
         }
         if !ship.submerged {
@@ -383,12 +764,16 @@ fn main() {
               ship.stats.beam * 1.5,
               ship.angle,
               rng.gen_range(20.0..60.0),
-              ship.velocity.abs() * TIME_ACCELERATION_FACTOR / 3.0,
+              ship.velocity.abs() * time_acceleration_factor / 3.0,
+              config.current.at(ship.coords),
             ));
           }
         }
-        ship.step(delta_t);
-        if let Some((radius, border)) = MAP_RADIUS {
+        ship.step(delta_t, &weather);
+        if mobile {
+          ship.drift(config.current.at(ship.coords), delta_t);
+        }
+        if let Some((radius, border)) = config.map_radius {
           let ship_distance = ship.distance_from_origin();
           if ship_distance > radius {
             match border {
@@ -399,8 +784,8 @@ fn main() {
                   continue;
                 } else if mobile {
                   let scale_factor = data.intensity * scale_factor / ship_distance;
-                  ship.coords.0 -= ship.coords.0 * scale_factor * delta_t;
-                  ship.coords.1 -= ship.coords.1 * scale_factor * delta_t;
+                  let push = (-ship.coords.0 * scale_factor, -ship.coords.1 * scale_factor);
+                  ship.drift(push, delta_t);
                 }
                 let mut mine_chance =
                   data.mine_spawn_chance * ship.velocity.abs() * ship.stats.beam * delta_t;
@@ -416,7 +801,7 @@ fn main() {
                     damage.powf(1.0 / 3.0) * 3.0,
                     1.0,
                     0,
-                    "f00",
+                    RED,
                   ));
                   if ship.damage(damage) {
                     sunk.push(name.clone());
@@ -433,48 +818,20 @@ fn main() {
                   let distance = rng.gen_range(40.0..80.0) * scale_factor_sqrt;
                   let x = ship.coords.0 + distance * angle.cos();
                   let y = ship.coords.1 + distance * angle.sin();
-                  let size = 60.0 * scale_factor_sqrt;
-                  let stats = ShipStats::new(
-                    8,
-                    size,
-                    size,
-                    3000.0 * scale_factor,
-                    0.0,
-                    0.0,
-                    1000.0,
-                    0.0,
-                    0.0,
-                    2.2,
-                    100.0 * scale_factor_sqrt,
-                    100.0 * scale_factor_sqrt,
-                    0.5..1.5,
-                    Vec::new(),
-                  );
-                  let kraken_ship = Ship {
-                    coords: (x, y),
-                    velocity: 0.0,
-                    angle: 0.0,
-                    helm: 0.0,
-                    power: 0.0,
-                    stats,
-                    sunk: false,
-                    submerged: false,
-                    smoke: false,
-                    respawn_cooldown: RESPAWN_COOLDOWN,
-                  };
+                  let kraken_ship = Ship::kraken((x, y), scale_factor, &config);
                   if kraken_ship.distance_from_origin() > radius {
                     kraken = Some(kraken_ship);
-                    ship.velocity = 0.0;
+                    ship.stop();
                     kraken_targets.push(name.clone());
                   }
                 }
               }
               BorderType::Land => {
-                let energy = ship.energy();
+                let energy = ship.energy(&weather);
                 if ship.damage(energy / 1000.0) {
                   sunk.push(name.clone());
                 }
-                ship.velocity = 0.0;
+                ship.stop();
                 ship.stats.power = Variable::Surface(0.0);
               }
             }
@@ -482,41 +839,72 @@ fn main() {
         }
       }
       for name in sunk {
-        let message = format!("sunk {name}\n");
         for connection in connections.values_mut() {
-          connection.tx.send(message.clone()).ok();
+          connection.snapshots.sink(&name);
         }
       }
+      // Sent with the snapshot at the end of the tick
+      let mut effects = Vec::new();
       for (x, y, size, duration, sprite, colour) in splashes {
-        let duration = duration / TIME_ACCELERATION_FACTOR;
-        let message = format!("splash {x} {y} {size} {duration} {sprite} #{colour}\n");
-        for connection in connections.values_mut() {
-          connection.tx.send(message.clone()).ok();
-        }
+        effects.push(ServerMessage::Splash(Splash {
+          x,
+          y,
+          size,
+          duration: duration / time_acceleration_factor,
+          sprite,
+          colour,
+          drift: (0.0, 0.0),
+        }));
       }
-      for (x, y, size, angle, duration, growth) in wakes {
-        let duration = duration / TIME_ACCELERATION_FACTOR;
-        let message = format!("wake {x} {y} {size} {angle} {duration} {growth}\n");
-        for connection in connections.values_mut() {
-          connection.tx.send(message.clone()).ok();
-        }
+      let (wind_x, wind_y) = weather.wind();
+      for (x, y, size, duration) in smoke {
+        // Lying low over the water, it is carried by the current as well as the wind
+        let (current_x, current_y) = config.current.at((x, y));
+        effects.push(ServerMessage::Splash(Splash {
+          x,
+          y,
+          size,
+          duration: duration / time_acceleration_factor,
+          sprite: 2,
+          colour: SMOKE,
+          drift: (
+            (wind_x + current_x) * time_acceleration_factor,
+            (wind_y + current_y) * time_acceleration_factor,
+          ),
+        }));
       }
+      for (x, y, size, angle, duration, growth, (drift_x, drift_y)) in wakes {
+        effects.push(ServerMessage::Wake(Wake {
+          x,
+          y,
+          size,
+          angle,
+          duration: duration / time_acceleration_factor,
+          growth,
+          drift: (
+            drift_x * time_acceleration_factor,
+            drift_y * time_acceleration_factor,
+          ),
+        }));
+      }
+      let effects = Effects::new(effects);
       if let Some(ref mut kraken_ship) = kraken {
         kraken_ship.stats.cooldown -= delta_t;
         if kraken_ship.sunk {
           kraken_cooldown = kraken_ship.current_mass() / 50.0;
           kraken = None;
-          let message = format!("sunk {KRAKEN_NAME}\n");
           for connection in connections.values_mut() {
-            connection.tx.send(message.clone()).ok();
+            connection.snapshots.sink(KRAKEN_NAME);
           }
         } else if let Some(target) = kraken_targets.choose(&mut thread_rng()) {
-          let target_ship = &mut connections.get_mut(target).expect("Missing target").ship;
-          match kraken_ship.shoot(target_ship) {
+          let target_ship = connections
+            .get_mut(target)
+            .and_then(|connection| connection.ship.as_mut())
+            .expect("Missing target");
+          match kraken_ship.shoot(target_ship, gun_accuracy) {
             ShootingState::Sunk(..) => {
-              let message = format!("sunk {target}\n");
               for connection in connections.values_mut() {
-                connection.tx.send(message.clone()).ok();
+                connection.snapshots.sink(target);
               }
             }
             ShootingState::Hit(..) | ShootingState::Miss(..) | ShootingState::NotFired => (),
@@ -524,48 +912,107 @@ fn main() {
         } else {
           kraken_cooldown = (kraken_ship.current_mass() - kraken_ship.stats.health) / 100.0;
           kraken = None;
-          let message = format!("sunk {KRAKEN_NAME}\n");
           for connection in connections.values_mut() {
-            connection.tx.send(message.clone()).ok();
+            connection.snapshots.sink(KRAKEN_NAME);
           }
         }
       }
+      // Submerged ships can only be seen by their own captain, and spectators if configured
       let mut ships = Vec::new();
       for (name, connection) in &connections {
-        ships.push((name.clone(), connection.ship.clone()));
+        let Some(ship) = &connection.ship else {
+          continue;
+        };
+        ships.push((
+          ship.state(name, config.colour),
+          ship.submerged && !ship.sunk,
+        ));
       }
       if let Some(ref kraken) = kraken {
-        ships.push((KRAKEN_NAME.to_string(), kraken.clone()));
+        ships.push((kraken.state(KRAKEN_NAME, config.colour), false));
       }
-      for (name, ship) in ships {
-        let (x, y) = ship.coords;
-        let angle = ship.angle;
-        let velocity = ship.velocity;
-        let texture = ship.stats.texture;
-        let size = ship.stats.length;
-        let mut health = ship.stats.health / ship.current_mass();
-        if health < 0.0 {
-          health = 0.0;
+      for (name, connection) in &mut connections {
+        if connection.detached.is_some() {
+          continue;
         }
-        let message =
-          format!("ship {name} {x} {y} {angle} {velocity} {size} {texture} #{COLOUR} {health}\n");
-        if ship.submerged && !ship.sunk {
-          connections[&name].tx.send(message).ok();
-        } else {
-          for connection2 in connections.values() {
-            connection2.tx.send(message.clone()).ok();
+        if let Some(ship) = &connection.ship {
+          let hull = ship.hull(&weather);
+          if connection.supports(Capability::Predict) && connection.hull != Some(hull) {
+            connection.tx.send(ServerMessage::Hull(hull));
+            connection.hull = Some(hull);
+          }
+        }
+        // Players can always see as far as they can shoot, and spectators see everything
+        let range = connection.ship.as_ref().and_then(|own| {
+          let radius = config.interest_radius?;
+          Some((own.coords, radius.max(own.stats.gun_range)))
+        });
+        let see_hidden = connection.ship.is_none() && config.spectators_see_submerged;
+        let world = visible(&ships, name, range, see_hidden);
+        let mut snapshot = connection.snapshots.delta(tick, world);
+        // Goes before the ships, so the client has it when its own ship is corrected. If it
+        // gets lost the client's own simulation gets to the same place once the orders are met.
+        let header = usize::from(matches!(
+          snapshot.first(),
+          Some(ServerMessage::Snapshot(..))
+        ));
+        let machinery = connection.ship.as_ref().map(|ship| ship.machinery);
+        if connection.supports(Capability::Predict) && machinery != connection.machinery {
+          if let Some(machinery) = machinery {
+            snapshot.insert(header, ServerMessage::Machinery(machinery));
           }
+          connection.machinery = machinery;
         }
+        let turn = connection
+          .ship
+          .as_ref()
+          .map(|ship| (ship.yaw_rate, ship.drift));
+        if connection.supports(Capability::Predict) && turn != connection.turn {
+          if let Some((yaw_rate, drift)) = turn {
+            snapshot.insert(header, ServerMessage::Turn(yaw_rate, drift));
+          }
+          connection.turn = turn;
+        }
+        // To the centimetre per second, or it would be sent every tick in a gyre
+        let current = connection.ship.as_ref().map(|ship| {
+          let (x, y) = config.current.at(ship.coords);
+          ((x * 100.0).round() / 100.0, (y * 100.0).round() / 100.0)
+        });
+        if connection.supports(Capability::Predict) && current != connection.current {
+          if let Some((x, y)) = current {
+            snapshot.insert(header, ServerMessage::Current(x, y));
+          }
+          connection.current = current;
+        }
+        let effects = effects.for_client(connection);
+        send_state(&socket, connection, snapshot, effects);
       }
       if connections.is_empty() {
+        if config.persistent {
+          break;
+        }
         return;
       }
       let elapsed = start.elapsed();
+      busy += elapsed;
       if delay > elapsed {
         sleep(delay - elapsed);
       }
     }
-    let extra = (start.elapsed() - Duration::from_secs(1)).as_millis();
+    let load = busy.as_secs_f32() / start.elapsed().as_secs_f32();
+    for connection in connections.values() {
+      if connection.supports(Capability::Ping) {
+        connection.tx.send(ServerMessage::Status(tick, tps, load));
+      }
+      // Changes too slowly to be worth sending every tick
+      if connection.supports(Capability::Weather) {
+        connection.tx.send(weather.message());
+      }
+    }
+    let extra = start
+      .elapsed()
+      .saturating_sub(Duration::from_secs(1))
+      .as_millis();
     if extra > 100 {
       println!("Can't keep up, is the server overloaded? {extra} ms behind");
     }
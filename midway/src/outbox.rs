@@ -0,0 +1,309 @@
+//! Bounded queue of messages waiting to be written to a client
+//!
+//! Only the newest state of a ship matters, so a ship update replaces one for the same ship that
+//! is still waiting. Anything else that piles up means the client isn't keeping up, and once the
+//! queue is full it stops accepting messages so the client can be disconnected.
+use protocol::{ServerMessage, ShipState};
+use std::collections::{HashMap, VecDeque};
+#[cfg(test)]
+use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+pub const MAX_QUEUED: usize = 2048;
+
+struct State {
+  messages: VecDeque<ServerMessage>,
+  // How many messages have been taken off the front, so positions in the queue stay put
+  taken: usize,
+  // Where the queued state of each ship is, while a newer one can still replace it
+  ships: HashMap<String, usize>,
+  senders: usize,
+  overflowed: bool,
+  // Nothing is writing messages any more, so they are thrown away
+  closed: bool,
+}
+
+impl State {
+  fn push(&mut self, message: ServerMessage) {
+    if self.messages.len() >= MAX_QUEUED {
+      // Whatever is left would only be sent to a client that is about to be disconnected
+      self.overflowed = true;
+      self.clear();
+      return;
+    }
+    let position = self.taken + self.messages.len();
+    match &message {
+      // An appearing ship still has to be announced, just with the newer state
+      ServerMessage::Ship(ship) | ServerMessage::Appear(ship) => {
+        self.ships.insert(ship.name.clone(), position);
+      }
+      ServerMessage::Sunk(name) | ServerMessage::Disappear(name) => {
+        self.ships.remove(name);
+      }
+      // The client forgets every ship here, so an update from before can't stand in for a newer one
+      ServerMessage::Snapshot(_, None) => self.ships.clear(),
+      _ => (),
+    }
+    self.messages.push_back(message);
+  }
+
+  fn pop(&mut self) -> Option<ServerMessage> {
+    let message = self.messages.pop_front()?;
+    if let ServerMessage::Ship(ship) | ServerMessage::Appear(ship) = &message {
+      if self.ships.get(&ship.name) == Some(&self.taken) {
+        self.ships.remove(&ship.name);
+      }
+    }
+    self.taken += 1;
+    Some(message)
+  }
+
+  // The queued state of this ship that a newer one makes redundant, if there is one
+  fn superseded(&mut self, name: &str) -> Option<&mut ShipState> {
+    let index = self.ships.get(name)? - self.taken;
+    match &mut self.messages[index] {
+      ServerMessage::Ship(ship) | ServerMessage::Appear(ship) => Some(ship),
+      _ => None,
+    }
+  }
+
+  fn clear(&mut self) {
+    self.messages.clear();
+    self.ships.clear();
+  }
+}
+
+struct Shared {
+  state: Mutex<State>,
+  ready: Condvar,
+}
+
+impl Shared {
+  fn lock(&self) -> MutexGuard<'_, State> {
+    // Nothing panics while holding the lock, and the queue is still usable if something did
+    self.state.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+/// Queues messages for a client, like the sending half of a channel
+pub struct Outbox {
+  shared: Arc<Shared>,
+}
+
+/// Takes queued messages for the thread that writes them
+pub struct Outgoing {
+  shared: Arc<Shared>,
+}
+
+pub fn outbox() -> (Outbox, Outgoing) {
+  let shared = Arc::new(Shared {
+    state: Mutex::new(State {
+      messages: VecDeque::new(),
+      taken: 0,
+      ships: HashMap::new(),
+      senders: 1,
+      overflowed: false,
+      closed: false,
+    }),
+    ready: Condvar::new(),
+  });
+  (
+    Outbox {
+      shared: shared.clone(),
+    },
+    Outgoing { shared },
+  )
+}
+
+impl Outbox {
+  pub fn send(&self, message: ServerMessage) {
+    let mut state = self.shared.lock();
+    if state.overflowed || state.closed {
+      return;
+    }
+    if let ServerMessage::Ship(ship) = message {
+      match state.superseded(&ship.name) {
+        Some(queued) => *queued = ship,
+        None => state.push(ServerMessage::Ship(ship)),
+      }
+    } else {
+      state.push(message);
+    }
+    self.shared.ready.notify_one();
+  }
+
+  /// Whether the client fell so far behind that messages were dropped
+  pub fn overflowed(&self) -> bool {
+    self.shared.lock().overflowed
+  }
+}
+
+impl Clone for Outbox {
+  fn clone(&self) -> Self {
+    self.shared.lock().senders += 1;
+    Self {
+      shared: self.shared.clone(),
+    }
+  }
+}
+
+impl Drop for Outbox {
+  fn drop(&mut self) {
+    self.shared.lock().senders -= 1;
+    self.shared.ready.notify_one();
+  }
+}
+
+impl Outgoing {
+  /// Waits for the next message, or returns None once there will be no more
+  pub fn recv(&self) -> Option<ServerMessage> {
+    let mut state = self.shared.lock();
+    loop {
+      if let Some(message) = state.pop() {
+        return Some(message);
+      }
+      if state.senders == 0 || state.overflowed {
+        return None;
+      }
+      state = self
+        .shared
+        .ready
+        .wait(state)
+        .unwrap_or_else(PoisonError::into_inner);
+    }
+  }
+
+  // Only the tests need to look without waiting
+  #[cfg(test)]
+  pub fn try_recv(&self) -> Result<ServerMessage, TryRecvError> {
+    let mut state = self.shared.lock();
+    match state.pop() {
+      Some(message) => Ok(message),
+      None if state.senders == 0 || state.overflowed => Err(TryRecvError::Disconnected),
+      None => Err(TryRecvError::Empty),
+    }
+  }
+}
+
+impl Drop for Outgoing {
+  fn drop(&mut self) {
+    let mut state = self.shared.lock();
+    state.closed = true;
+    state.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use protocol::Colour;
+
+  fn ship(name: &str, x: f32) -> ShipState {
+    ShipState {
+      name: name.to_owned(),
+      x,
+      y: 0.0,
+      angle: 0.0,
+      velocity: 0.0,
+      size: 93.3,
+      texture: 1,
+      colour: Colour::new(0x99, 0x99, 0x99, 255),
+      health: 1.0,
+    }
+  }
+
+  fn drain(outgoing: &Outgoing) -> Vec<ServerMessage> {
+    std::iter::from_fn(|| outgoing.try_recv().ok()).collect()
+  }
+
+  #[test]
+  fn newer_states_replace_queued_ones() {
+    let (outbox, outgoing) = outbox();
+    outbox.send(ServerMessage::Appear(ship("Yorktown", 1.0)));
+    outbox.send(ServerMessage::Ship(ship("Kraken", 1.0)));
+    outbox.send(ServerMessage::Ship(ship("Yorktown", 2.0)));
+    outbox.send(ServerMessage::Ship(ship("Kraken", 2.0)));
+    assert_eq!(
+      drain(&outgoing),
+      [
+        ServerMessage::Appear(ship("Yorktown", 2.0)),
+        ServerMessage::Ship(ship("Kraken", 2.0)),
+      ]
+    );
+  }
+
+  #[test]
+  fn removals_and_resyncs_are_not_skipped() {
+    let (outbox, outgoing) = outbox();
+    outbox.send(ServerMessage::Ship(ship("Kraken", 1.0)));
+    outbox.send(ServerMessage::Sunk("Kraken".to_owned()));
+    outbox.send(ServerMessage::Ship(ship("Kraken", 2.0)));
+    outbox.send(ServerMessage::Snapshot(2, None));
+    outbox.send(ServerMessage::Ship(ship("Kraken", 3.0)));
+    assert_eq!(
+      drain(&outgoing),
+      [
+        ServerMessage::Ship(ship("Kraken", 1.0)),
+        ServerMessage::Sunk("Kraken".to_owned()),
+        ServerMessage::Ship(ship("Kraken", 2.0)),
+        ServerMessage::Snapshot(2, None),
+        ServerMessage::Ship(ship("Kraken", 3.0)),
+      ]
+    );
+  }
+
+  #[test]
+  fn sent_states_are_not_replaced() {
+    let (outbox, outgoing) = outbox();
+    outbox.send(ServerMessage::Ship(ship("Yorktown", 1.0)));
+    outbox.send(ServerMessage::Ship(ship("Kraken", 1.0)));
+    assert_eq!(
+      outgoing.recv(),
+      Some(ServerMessage::Ship(ship("Yorktown", 1.0)))
+    );
+    outbox.send(ServerMessage::Ship(ship("Yorktown", 2.0)));
+    outbox.send(ServerMessage::Ship(ship("Kraken", 2.0)));
+    outbox.send(ServerMessage::Ship(ship("Yorktown", 3.0)));
+    assert_eq!(
+      drain(&outgoing),
+      [
+        ServerMessage::Ship(ship("Kraken", 2.0)),
+        ServerMessage::Ship(ship("Yorktown", 3.0)),
+      ]
+    );
+  }
+
+  #[test]
+  fn full_queues_overflow() {
+    let (outbox, outgoing) = outbox();
+    for i in 0..MAX_QUEUED {
+      outbox.send(ServerMessage::Snapshot(i as u32, Some(0)));
+    }
+    assert!(!outbox.overflowed());
+    outbox.send(ServerMessage::Say("One too many".to_owned()));
+    assert!(outbox.overflowed());
+    assert_eq!(outgoing.recv(), None);
+  }
+
+  #[test]
+  fn messages_to_nowhere_are_dropped() {
+    let (outbox, outgoing) = outbox();
+    drop(outgoing);
+    for i in 0..=MAX_QUEUED {
+      outbox.send(ServerMessage::Snapshot(i as u32, Some(0)));
+    }
+    assert!(!outbox.overflowed());
+  }
+
+  #[test]
+  fn queued_messages_are_sent_after_the_client_is_dropped() {
+    let (outbox, outgoing) = outbox();
+    outbox.send(ServerMessage::Reject("Go away".to_owned()));
+    drop(outbox);
+    assert_eq!(
+      outgoing.recv(),
+      Some(ServerMessage::Reject("Go away".to_owned()))
+    );
+    assert_eq!(outgoing.recv(), None);
+  }
+}
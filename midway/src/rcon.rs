@@ -0,0 +1,170 @@
+//! Remote admin over a separate, password protected port
+use crate::admin::{AdminCommand, AdminRequest};
+use crate::input::read_line;
+use std::collections::HashMap;
+use std::io::{BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+// Connections that never log in would otherwise hold a thread forever
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+// Slows down password guessing, however many connections it is spread over
+const LOGIN_FAILURE_DELAY: Duration = Duration::from_secs(2);
+
+// When each address that got the password wrong may try again
+type Lockouts = Mutex<HashMap<IpAddr, Instant>>;
+
+// Compares every byte so the time taken doesn't leak how much of the password matched
+fn password_matches(attempt: &str, password: &str) -> bool {
+  let attempt = attempt.as_bytes();
+  let password = password.as_bytes();
+  let mut difference = attempt.len() ^ password.len();
+  for (i, byte) in password.iter().enumerate() {
+    difference |= usize::from(attempt.get(i).unwrap_or(&0) ^ byte);
+  }
+  difference == 0
+}
+
+/// Checks a login line, refusing every attempt from an address that got it wrong too recently
+fn log_in(lockouts: &Lockouts, ip: IpAddr, line: &str, password: &str, now: Instant) -> bool {
+  // Everything after the command is the password, spaces and all
+  let attempt = line.trim_end_matches(['\r', '\n']).strip_prefix("login ");
+  let mut lockouts = lockouts.lock().unwrap_or_else(PoisonError::into_inner);
+  lockouts.retain(|_, until| *until > now);
+  let authenticated = !lockouts.contains_key(&ip)
+    && attempt.is_some_and(|attempt| password_matches(attempt, password));
+  if !authenticated {
+    lockouts.insert(ip, now + LOGIN_FAILURE_DELAY);
+  }
+  authenticated
+}
+
+pub fn process_rcon(tx: &Sender<AdminRequest>, listener: &TcpListener, password: &str) {
+  let password: Arc<str> = Arc::from(password);
+  let lockouts = Arc::new(Lockouts::default());
+  for stream in listener.incoming().flatten() {
+    let tx = tx.clone();
+    let password = password.clone();
+    let lockouts = lockouts.clone();
+    spawn(move || handle_rcon(stream, &tx, &password, &lockouts));
+  }
+}
+
+fn handle_rcon(
+  stream: TcpStream,
+  tx: &Sender<AdminRequest>,
+  password: &str,
+  lockouts: &Lockouts,
+) -> Option<()> {
+  let peer = stream.peer_addr().ok()?;
+  let address = peer.to_string();
+  stream.set_read_timeout(Some(LOGIN_TIMEOUT)).ok()?;
+  let mut writer = stream.try_clone().ok()?;
+  let mut stream = BufReader::new(stream);
+  let mut buf = String::new();
+  read_line(&mut stream, &mut buf).ok()?;
+  if !log_in(lockouts, peer.ip(), &buf, password, Instant::now()) {
+    println!("[rcon] {address} failed to log in");
+    writer.write_all(b"Login failed\n\n").ok();
+    return None;
+  }
+  println!("[rcon] {address} logged in");
+  // Admins can take as long as they like between commands
+  stream.get_ref().set_read_timeout(None).ok()?;
+  writer.write_all(b"Logged in\n\n").ok()?;
+  loop {
+    buf.clear();
+    if read_line(&mut stream, &mut buf).ok()? == 0 {
+      println!("[rcon] {address} logged out");
+      return Some(());
+    }
+    let line = buf.trim();
+    if line.is_empty() {
+      continue;
+    }
+    println!("[rcon] {address}: {line}");
+    // Every response ends with a blank line so scripts know when to stop reading
+    let response = match AdminCommand::parse(line) {
+      Ok(command) => {
+        let (reply, rx) = channel();
+        tx.send(AdminRequest { command, reply }).ok()?;
+        rx.recv().ok()?
+      }
+      Err(error) => error,
+    };
+    writer
+      .write_all(format!("{response}\n\n").as_bytes())
+      .ok()?;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::Ipv4Addr;
+
+  const PASSWORD: &str = "change me";
+
+  #[test]
+  fn passwords_can_have_spaces() {
+    let lockouts = Lockouts::default();
+    let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    assert!(log_in(
+      &lockouts,
+      ip,
+      "login change me\r\n",
+      PASSWORD,
+      Instant::now()
+    ));
+  }
+
+  #[test]
+  fn failures_lock_the_address_out() {
+    let lockouts = Lockouts::default();
+    let guesser = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    let admin = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    let start = Instant::now();
+    assert!(!log_in(
+      &lockouts,
+      guesser,
+      "login change\n",
+      PASSWORD,
+      start
+    ));
+    // Even the right password is refused until the delay is up
+    assert!(!log_in(
+      &lockouts,
+      guesser,
+      "login change me\n",
+      PASSWORD,
+      start
+    ));
+    assert!(log_in(
+      &lockouts,
+      admin,
+      "login change me\n",
+      PASSWORD,
+      start
+    ));
+    // Each refusal starts the wait again
+    let soon = start + LOGIN_FAILURE_DELAY / 2;
+    assert!(!log_in(
+      &lockouts,
+      guesser,
+      "login change me\n",
+      PASSWORD,
+      soon
+    ));
+    let later = soon + LOGIN_FAILURE_DELAY * 2;
+    assert!(log_in(
+      &lockouts,
+      guesser,
+      "login change me\n",
+      PASSWORD,
+      later
+    ));
+  }
+}
@@ -0,0 +1,361 @@
+//! Per client history of the ships it has been sent, so each tick only sends what changed
+use protocol::{Capability, ServerMessage, ShipState};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Snapshots older than this are forgotten, and a client that hasn't acknowledged any of the
+// remaining ones gets a full resync
+const MAX_HISTORY: usize = 128;
+
+pub type World = HashMap<String, ShipState>;
+
+/// The ships a client sees: its own, and the others in range unless they are hidden from it
+pub fn visible(
+  ships: &[(ShipState, bool)],
+  own: &str,
+  range: Option<((f32, f32), f32)>,
+  see_hidden: bool,
+) -> World {
+  ships
+    .iter()
+    .filter(|(ship, hidden)| {
+      if ship.name == own {
+        return true;
+      }
+      let in_range = range.is_none_or(|((x, y), range)| (ship.x - x).hypot(ship.y - y) <= range);
+      (see_hidden || !hidden) && in_range
+    })
+    .map(|(ship, _)| (ship.name.clone(), ship.clone()))
+    .collect()
+}
+
+pub struct SnapshotHistory {
+  // Oldest first, starting with the last one the client acknowledged
+  sent: VecDeque<(u32, World)>,
+  acked: Option<u32>,
+  // Ships that sank, with the newest snapshot sent before, told with every snapshot until one
+  // after is acknowledged
+  sunk: HashMap<String, Option<u32>>,
+  // Clients that don't acknowledge snapshots are assumed to receive every one, in order
+  acknowledges: bool,
+  announces: bool,
+}
+
+impl SnapshotHistory {
+  pub fn new(capabilities: &[Capability]) -> Self {
+    Self {
+      sent: VecDeque::new(),
+      acked: None,
+      sunk: HashMap::new(),
+      acknowledges: capabilities.contains(&Capability::Snapshot),
+      announces: capabilities.contains(&Capability::Appear),
+    }
+  }
+
+  pub fn ack(&mut self, tick: u32) {
+    if self.acked.is_some_and(|acked| acked >= tick) {
+      return;
+    }
+    // Ignore acknowledgements of snapshots that were never sent or have been forgotten
+    let Some(index) = self.sent.iter().position(|(sent, _)| *sent == tick) else {
+      return;
+    };
+    self.sent.drain(..index);
+    self.acked = Some(tick);
+    self
+      .sunk
+      .retain(|_, before| before.is_some_and(|before| before >= tick));
+  }
+
+  /// Tells the client the ship sank with its snapshots, so one sent before can't bring it back
+  pub fn sink(&mut self, name: &str) {
+    let before = self.sent.back().map(|(sent, _)| *sent);
+    self.sunk.insert(name.to_owned(), before);
+  }
+
+  /// Builds the messages that bring the client up to date with the world it can see this tick
+  pub fn delta(&mut self, tick: u32, world: World) -> Vec<ServerMessage> {
+    // Acknowledging drops the snapshots before, so this is the oldest one kept unless it has
+    // since been forgotten
+    let base = self
+      .acked
+      .filter(|acked| self.sent.front().is_some_and(|(sent, _)| sent == acked));
+    let mut messages = Vec::new();
+    if self.acknowledges {
+      messages.push(ServerMessage::Snapshot(tick, base));
+    }
+    let empty = World::new();
+    // The client applies this to the newest snapshot it has, which could be the acknowledged
+    // one or any sent since, so it has to work from all of them. A full resync starts from
+    // nothing, and the client clears its ships.
+    let known: Vec<&World> = match base {
+      Some(_) => self.sent.iter().map(|(_, known)| known).collect(),
+      None => vec![&empty],
+    };
+    // First, as whatever is under the name afterwards is new to the client
+    for name in self.sunk.keys() {
+      if known.iter().any(|known| known.contains_key(name)) {
+        messages.push(ServerMessage::Sunk(name.clone()));
+      }
+    }
+    for (name, ship) in &world {
+      let sunk = self.sunk.contains_key(name);
+      if !sunk && known.iter().all(|known| known.get(name) == Some(ship)) {
+        continue;
+      }
+      messages.push(if self.announces && !known[0].contains_key(name) {
+        ServerMessage::Appear(ship.clone())
+      } else {
+        ServerMessage::Ship(ship.clone())
+      });
+    }
+    let mut gone = HashSet::new();
+    for name in known.iter().flat_map(|known| known.keys()) {
+      if !world.contains_key(name) && !self.sunk.contains_key(name) && gone.insert(name) {
+        messages.push(if self.announces {
+          ServerMessage::Disappear(name.clone())
+        } else {
+          // Older clients only know how to remove a ship when it sinks
+          ServerMessage::Sunk(name.clone())
+        });
+      }
+    }
+    self.sent.push_back((tick, world));
+    if self.sent.len() > MAX_HISTORY {
+      self.sent.pop_front();
+    }
+    if !self.acknowledges {
+      self.ack(tick);
+    }
+    messages
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use protocol::Colour;
+
+  fn ship(name: &str, x: f32) -> ShipState {
+    ShipState {
+      name: name.to_owned(),
+      x,
+      y: 0.0,
+      angle: 0.0,
+      velocity: 0.0,
+      size: 93.3,
+      texture: 1,
+      colour: Colour::new(0x99, 0x99, 0x99, 255),
+      health: 1.0,
+    }
+  }
+
+  fn world(ships: &[ShipState]) -> World {
+    ships
+      .iter()
+      .map(|ship| (ship.name.clone(), ship.clone()))
+      .collect()
+  }
+
+  // The snapshot header, then what happened to each ship in order of name
+  fn changes(messages: Vec<ServerMessage>) -> (Option<Option<u32>>, Vec<String>) {
+    let mut header = None;
+    let mut changes = Vec::new();
+    for message in messages {
+      match message {
+        ServerMessage::Snapshot(_, base) => header = Some(base),
+        ServerMessage::Ship(ship) => changes.push(format!("{} {}", ship.name, ship.x)),
+        ServerMessage::Appear(ship) => changes.push(format!("{} appears at {}", ship.name, ship.x)),
+        ServerMessage::Disappear(name) => changes.push(format!("{name} disappears")),
+        ServerMessage::Sunk(name) => changes.push(format!("{name} sinks")),
+        message => panic!("unexpected {message}"),
+      }
+    }
+    changes.sort();
+    (header, changes)
+  }
+
+  fn history() -> SnapshotHistory {
+    SnapshotHistory::new(&[Capability::Snapshot, Capability::Appear])
+  }
+
+  #[test]
+  fn deltas_start_from_the_acknowledged_snapshot() {
+    let mut history = history();
+    let yorktown = world(&[ship("Yorktown", 1.0)]);
+    assert_eq!(
+      changes(history.delta(1, yorktown.clone())),
+      (Some(None), vec!["Yorktown appears at 1".to_owned()])
+    );
+    // Until one is acknowledged each is a full resync
+    assert_eq!(
+      changes(history.delta(2, yorktown.clone())),
+      (Some(None), vec!["Yorktown appears at 1".to_owned()])
+    );
+    history.ack(1);
+    assert_eq!(
+      changes(history.delta(3, yorktown)),
+      (Some(Some(1)), Vec::new())
+    );
+    assert_eq!(
+      changes(history.delta(4, world(&[ship("Yorktown", 2.0)]))),
+      (Some(Some(1)), vec!["Yorktown 2".to_owned()])
+    );
+    // Old and unknown acknowledgements change nothing
+    history.ack(4);
+    history.ack(3);
+    history.ack(9);
+    assert_eq!(
+      changes(history.delta(5, world(&[ship("Yorktown", 2.0)]))),
+      (Some(Some(4)), Vec::new())
+    );
+  }
+
+  #[test]
+  fn forgotten_snapshots_lead_to_a_resync() {
+    let mut history = history();
+    let yorktown = world(&[ship("Yorktown", 1.0)]);
+    history.delta(0, yorktown.clone());
+    history.ack(0);
+    for tick in 1..=MAX_HISTORY as u32 {
+      let (header, _) = changes(history.delta(tick, yorktown.clone()));
+      assert_eq!(header, Some(Some(0)));
+    }
+    let tick = MAX_HISTORY as u32 + 1;
+    assert_eq!(
+      changes(history.delta(tick, yorktown.clone())),
+      (Some(None), vec!["Yorktown appears at 1".to_owned()])
+    );
+    history.ack(0);
+    history.ack(tick);
+    let (header, _) = changes(history.delta(tick + 1, yorktown));
+    assert_eq!(header, Some(Some(tick)));
+  }
+
+  #[test]
+  fn ships_sent_since_the_base_are_taken_away_again() {
+    let mut history = history();
+    history.delta(5, World::new());
+    history.ack(5);
+    history.delta(6, world(&[ship("Kraken", 1.0)]));
+    // The client may have applied 6, so it has to be told the kraken has gone
+    assert_eq!(
+      changes(history.delta(7, World::new())),
+      (Some(Some(5)), vec!["Kraken disappears".to_owned()])
+    );
+  }
+
+  #[test]
+  fn ships_removed_since_the_base_are_sent_again() {
+    let mut history = history();
+    let kraken = world(&[ship("Kraken", 1.0)]);
+    history.delta(5, kraken.clone());
+    history.ack(5);
+    history.delta(6, World::new());
+    // Unchanged since 5, but the client may have applied 6 and removed it
+    assert_eq!(
+      changes(history.delta(7, kraken)),
+      (Some(Some(5)), vec!["Kraken 1".to_owned()])
+    );
+  }
+
+  #[test]
+  fn older_clients_get_every_change_since_the_last_tick() {
+    let mut history = SnapshotHistory::new(&[]);
+    history.delta(1, world(&[ship("Yorktown", 1.0), ship("Kraken", 1.0)]));
+    assert_eq!(
+      changes(history.delta(2, world(&[ship("Yorktown", 2.0)]))),
+      (
+        None,
+        vec!["Kraken sinks".to_owned(), "Yorktown 2".to_owned()]
+      )
+    );
+    assert_eq!(
+      changes(history.delta(3, world(&[ship("Yorktown", 2.0)]))),
+      (None, Vec::new())
+    );
+  }
+
+  #[test]
+  fn ships_out_of_view_keep_disappearing_until_acknowledged() {
+    let mut history = history();
+    let range = Some(((0.0, 0.0), 1000.0));
+    let view = |kraken: f32| {
+      let ships = [
+        (ship("Yorktown", 0.0), false),
+        (ship("Kraken", kraken), false),
+        (ship("Nautilus", 10.0), true),
+      ];
+      visible(&ships, "Yorktown", range, false)
+    };
+    // The submerged one stays hidden throughout
+    assert_eq!(
+      changes(history.delta(1, view(500.0))),
+      (
+        Some(None),
+        vec![
+          "Kraken appears at 500".to_owned(),
+          "Yorktown appears at 0".to_owned()
+        ]
+      )
+    );
+    history.ack(1);
+    // Every delta until one without it is acknowledged, in case they are lost
+    for tick in 2..5 {
+      assert_eq!(
+        changes(history.delta(tick, view(1500.0 + tick as f32))),
+        (Some(Some(1)), vec!["Kraken disappears".to_owned()])
+      );
+    }
+    history.ack(3);
+    assert_eq!(
+      changes(history.delta(5, view(1600.0))),
+      (Some(Some(3)), Vec::new())
+    );
+    assert_eq!(
+      changes(history.delta(6, view(900.0))),
+      (Some(Some(3)), vec!["Kraken appears at 900".to_owned()])
+    );
+  }
+
+  #[test]
+  fn sinking_is_told_until_a_snapshot_after_is_acknowledged() {
+    let mut history = history();
+    history.delta(1, world(&[ship("Yorktown", 1.0), ship("Kraken", 1.0)]));
+    history.ack(1);
+    history.sink("Kraken");
+    // Told instead of it disappearing, as often as it takes
+    for tick in 2..4 {
+      assert_eq!(
+        changes(history.delta(tick, world(&[ship("Yorktown", 1.0)]))),
+        (Some(Some(1)), vec!["Kraken sinks".to_owned()])
+      );
+    }
+    history.ack(2);
+    assert_eq!(
+      changes(history.delta(4, world(&[ship("Yorktown", 1.0)]))),
+      (Some(Some(2)), Vec::new())
+    );
+  }
+
+  #[test]
+  fn wrecks_are_sent_again_after_sinking() {
+    let mut history = history();
+    let yorktown = world(&[ship("Yorktown", 1.0)]);
+    history.delta(1, yorktown.clone());
+    history.ack(1);
+    history.sink("Yorktown");
+    assert_eq!(
+      history.delta(2, yorktown.clone()),
+      [
+        ServerMessage::Snapshot(2, Some(1)),
+        ServerMessage::Sunk("Yorktown".to_owned()),
+        ServerMessage::Ship(ship("Yorktown", 1.0)),
+      ]
+    );
+    history.ack(2);
+    assert_eq!(
+      changes(history.delta(3, yorktown)),
+      (Some(Some(2)), Vec::new())
+    );
+  }
+}
@@ -1,8 +1,9 @@
 use enum_iterator::{all, Sequence};
+use physics::Hull;
 use random_pick::pick_from_slice;
 use std::ops::Range;
 
-const WEIGHTS: &[usize] = &[15, 25, 4, 3, 1, 1, 1, 1, 10, 10, 10];
+pub const DEFAULT_WEIGHTS: &[usize] = &[15, 25, 4, 3, 1, 1, 1, 1, 10, 10, 10];
 
 #[derive(Clone)]
 pub enum Variable<T> {
@@ -27,7 +28,7 @@ impl<T: Copy> Variable<T> {
 }
 
 #[derive(Clone, Copy, Sequence)]
-enum ShipType {
+pub enum ShipType {
   Escort,
   Destroyer,
   LightCruiser,
@@ -41,6 +42,28 @@ enum ShipType {
   UBoat,
 }
 
+impl ShipType {
+  pub const fn name(self) -> &'static str {
+    match self {
+      Self::Escort => "escort",
+      Self::Destroyer => "destroyer",
+      Self::LightCruiser => "light_cruiser",
+      Self::HeavyCruiser => "heavy_cruiser",
+      Self::BattleCruiser => "battlecruiser",
+      Self::SlowBattleship => "slow_battleship",
+      Self::FastBattleship => "fast_battleship",
+      Self::Bird => "bird",
+      Self::PTBoat => "pt_boat",
+      Self::Liberty => "liberty",
+      Self::UBoat => "u_boat",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    all::<Self>().find(|ship| ship.name() == name)
+  }
+}
+
 #[derive(Clone)]
 pub enum Action {
   Submerge,
@@ -59,6 +82,9 @@ pub struct ShipStats {
   pub screw_area: f32,
   pub froude_scale_factor: f32,
   pub turning_circle: f32,
+  // Seconds to put the rudder hard over from amidships, and to bring the engine up to full power
+  pub rudder_time: f32,
+  pub spool_time: f32,
   pub gun_damage: f32,
   pub gun_range: f32,
   pub gun_reload_time: Range<f32>,
@@ -67,6 +93,7 @@ pub struct ShipStats {
 }
 
 impl ShipStats {
+  #[allow(clippy::too_many_arguments)]
   pub const fn new(
     texture: usize,
     length: f32,
@@ -78,6 +105,8 @@ impl ShipStats {
     screw_area: f32,
     turning_circle: f32,
     froude_scale_factor: f32,
+    rudder_time: f32,
+    spool_time: f32,
     gun_damage: f32,
     gun_range: f32,
     gun_reload_time: Range<f32>,
@@ -95,6 +124,8 @@ impl ShipStats {
       screw_area,
       froude_scale_factor,
       turning_circle,
+      rudder_time,
+      spool_time,
       gun_damage,
       gun_range,
       gun_reload_time,
@@ -103,6 +134,7 @@ impl ShipStats {
     }
   }
 
+  #[allow(clippy::too_many_arguments)]
   pub const fn new_submersible(
     texture: usize,
     length: f32,
@@ -117,6 +149,8 @@ impl ShipStats {
     screw_area: f32,
     turning_circle: f32,
     froude_scale_factor: f32,
+    rudder_time: f32,
+    spool_time: f32,
     gun_damage: f32,
     gun_range: f32,
     gun_reload_time: Range<f32>,
@@ -134,6 +168,8 @@ impl ShipStats {
       screw_area,
       froude_scale_factor,
       turning_circle,
+      rudder_time,
+      spool_time,
       gun_damage,
       gun_range,
       gun_reload_time,
@@ -141,14 +177,32 @@ impl ShipStats {
       actions,
     }
   }
+
+  /// How a ship of this class moves, which depends on whether it is submerged and on the sea
+  pub fn hull(&self, submerged: bool, wave_height: f32) -> Hull {
+    Hull {
+      length: self.length,
+      mass: self.mass.get_value(submerged),
+      power: self.power.get_value(submerged),
+      surface_area: self.surface_area.get_value(submerged),
+      screw_area: self.screw_area,
+      k: self.k,
+      froude_scale_factor: self.froude_scale_factor,
+      turning_circle: self.turning_circle,
+      rudder_time: self.rudder_time,
+      spool_time: self.spool_time,
+      wave_height,
+      submerged,
+    }
+  }
 }
 
-fn get_random_type() -> ShipType {
-  *pick_from_slice(&all::<ShipType>().collect::<Vec<ShipType>>(), WEIGHTS)
+pub fn get_random_type(weights: &[usize]) -> ShipType {
+  *pick_from_slice(&all::<ShipType>().collect::<Vec<ShipType>>(), weights)
     .expect("Could not generate ship type")
 }
 
-fn get_stats(ship: ShipType) -> ShipStats {
+pub fn get_stats(ship: ShipType) -> ShipStats {
   match ship {
     ShipType::Escort => ShipStats::new(
       1,
@@ -161,6 +215,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       4.54,
       560.0, // TODO: acquire proper value
       1.97,
+      10.0,
+      20.0,
       27.0,
       13400.0,
       0.4..0.44,
@@ -177,6 +233,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       11.45, // Warning - based off AI generated answer
       560.0,
       0.295,
+      10.0,
+      30.0,
       125.0,
       16000.0,
       0.8..1.2,
@@ -193,6 +251,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       46.57,
       660.0,
       2.34,
+      14.0,
+      40.0,
       216.0,
       18288.0,
       0.5..0.625,
@@ -209,6 +269,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       27.53,
       660.0,
       2.52,
+      15.0,
+      45.0,
       512.0,
       27480.0,
       1.33..2.0,
@@ -225,6 +287,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       52.81,
       860.0,
       4.2,
+      20.0,
+      60.0,
       3375.0,
       30680.0,
       4.0..6.0,
@@ -241,6 +305,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       67.93,
       640.0,
       25.57,
+      28.0,
+      90.0,
       4096.0,
       31364.0,
       4.0..6.0,
@@ -257,6 +323,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       87.94,
       920.0,
       5.63,
+      25.0,
+      60.0,
       4096.0,
       38700.0,
       2.6..4.0,
@@ -273,6 +341,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       4.337, // Estimate based on draft
       500.0, // TODO: acquire proper value
       13.8,
+      8.0,
+      20.0,
       64.0,
       12660.0,
       5.0..6.0,
@@ -289,6 +359,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       0.6744, // Estimate based on draft
       395.0,  // Note: value from earlier model of PT boat
       0.00067,
+      3.0,
+      5.0,
       4.096,
       7160.0,
       0.6..0.75,
@@ -305,6 +377,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       14.186, // Estimate based on draft
       750.0,  // TODO: acquire proper value
       330.6,
+      15.0,
+      60.0,
       64.0,
       12660.0,
       5.0..6.0,
@@ -324,6 +398,8 @@ fn get_stats(ship: ShipType) -> ShipStats {
       1.62,
       270.0, // TODO: acquire proper value
       1.34,
+      10.0,
+      20.0,
       42.9,
       11950.0,
       3.0..5.0,
@@ -332,6 +408,54 @@ fn get_stats(ship: ShipType) -> ShipStats {
   }
 }
 
-pub fn get_random_ship() -> ShipStats {
-  get_stats(get_random_type())
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use physics::{Machinery, Motion};
+
+  const DELTA_T: f32 = 4.0 / 60.0;
+
+  #[test]
+  fn every_class_keeps_its_turning_circle() {
+    for class in all::<ShipType>() {
+      let stats = get_stats(class);
+      let submerged = [false, !matches!(stats.mass, Variable::Surface(_))];
+      for hull in submerged.map(|submerged| stats.hull(submerged, 0.0)) {
+        let diameter = hull.measure_turning_circle(DELTA_T);
+        assert!(
+          (diameter / stats.turning_circle - 1.0).abs() < 0.02,
+          "{} turns in {diameter} m",
+          class.name()
+        );
+      }
+    }
+  }
+
+  // Fraction of its top speed in calm water that a class keeps in waves this high
+  fn speed_kept(class: ShipType, wave_height: f32) -> f32 {
+    let top_speed = |hull: Hull| {
+      let mut motion = Motion::default();
+      hull.run(
+        &mut motion,
+        &mut Machinery::default(),
+        1.0,
+        0.0,
+        DELTA_T,
+        20_000,
+      );
+      motion.velocity
+    };
+    let stats = get_stats(class);
+    top_speed(stats.hull(false, wave_height)) / top_speed(stats.hull(false, 0.0))
+  }
+
+  #[test]
+  fn rough_seas_slow_small_hulls_most() {
+    let pt_boat = speed_kept(ShipType::PTBoat, 5.0);
+    let escort = speed_kept(ShipType::Escort, 5.0);
+    let battleship = speed_kept(ShipType::FastBattleship, 5.0);
+    assert!(pt_boat < escort);
+    assert!(escort < battleship);
+    assert!(battleship > 0.95);
+  }
 }
@@ -0,0 +1,67 @@
+//! Datagrams for state that is worthless once it arrives late
+use crate::client::ClientData;
+use protocol::binary::{Datagram, ServerEncoder};
+use protocol::ServerMessage;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::Sender;
+
+const TOKEN_LENGTH: usize = 8;
+
+/// Reads tokens from clients and tells the main loop where they came from
+pub fn process_udp(tx: &Sender<(u64, SocketAddr)>, socket: &UdpSocket) {
+  // Anything longer than a token is truncated, and then ignored
+  let mut buf = [0; TOKEN_LENGTH + 1];
+  loop {
+    // Some platforms report unreachable clients as errors, which isn't a problem for the socket
+    if let Ok((TOKEN_LENGTH, address)) = socket.recv_from(&mut buf) {
+      let mut token = [0; TOKEN_LENGTH];
+      token.copy_from_slice(&buf[..TOKEN_LENGTH]);
+      if tx.send((u64::from_be_bytes(token), address)).is_err() {
+        return;
+      }
+    }
+  }
+}
+
+fn next_datagram(connection: &mut ClientData) -> Datagram {
+  connection.sequence = connection.sequence.wrapping_add(1);
+  Datagram::new(connection.sequence)
+}
+
+/// Sends a tick's snapshot and effects, as datagrams once the client has sent its token
+pub fn send_state(
+  socket: &UdpSocket,
+  connection: &mut ClientData,
+  snapshot: Vec<ServerMessage>,
+  effects: &[ServerMessage],
+) {
+  let Some(address) = connection.udp else {
+    for message in snapshot.into_iter().chain(effects.iter().cloned()) {
+      connection.tx.send(message);
+    }
+    return;
+  };
+  let mut encoder = ServerEncoder::default();
+  let mut datagram = next_datagram(connection);
+  // A snapshot has to arrive whole, so one too big for a datagram goes the reliable way
+  if !snapshot
+    .iter()
+    .all(|message| datagram.push(&encoder.encode(message)))
+  {
+    datagram = Datagram::new(connection.sequence);
+    for message in snapshot {
+      connection.tx.send(message);
+    }
+  }
+  for effect in effects {
+    let payload = encoder.encode(effect);
+    if !datagram.push(&payload) {
+      socket.send_to(datagram.as_bytes(), address).ok();
+      datagram = next_datagram(connection);
+      datagram.push(&payload);
+    }
+  }
+  if !datagram.is_empty() {
+    socket.send_to(datagram.as_bytes(), address).ok();
+  }
+}
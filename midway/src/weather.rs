@@ -0,0 +1,142 @@
+//! Wind and sea, which wander about the configured average wind as the game goes on
+use protocol::ServerMessage;
+use rand::{thread_rng, Rng};
+use std::f32::consts::TAU;
+
+// Seconds of game time for the wind speed to forget what it was doing
+const WIND_TIME: f32 = 1800.0;
+// How far the wind speed typically strays from the average, as a fraction of it
+const GUSTINESS: f32 = 0.5;
+// Radians the wind typically backs or veers by in an hour of game time
+const WIND_SHIFT: f32 = 1.0;
+// Seconds of game time for the sea to build up to the wind, or die down after it
+const SEA_TIME: f32 = 1200.0;
+// Wind speed in metres per second for each step of sea state it raises once it has blown a while
+const WIND_PER_SEA_STATE: f32 = 2.5;
+// Wave height in metres for each Douglas sea state, from the middle of its range
+const WAVE_HEIGHTS: [f32; 10] = [0.0, 0.05, 0.3, 0.875, 1.875, 3.25, 5.0, 7.5, 11.5, 14.0];
+// Extra spread of gunfire per metre of wave height, from the ship rolling
+const ROLL_SPREAD: f32 = 0.15;
+// The furthest off gunfire can be as a fraction of the range, so no shell lands behind the gun
+const MAX_GUN_SPREAD: f32 = 0.9;
+
+fn settled_sea(wind_speed: f32) -> f32 {
+  (wind_speed / WIND_PER_SEA_STATE).min(9.0)
+}
+
+// Uniform noise with the variance of a Wiener process over delta_t
+fn noise(rng: &mut impl Rng, delta_t: f32) -> f32 {
+  rng.gen_range(-1.0..1.0) * (3.0 * delta_t).sqrt()
+}
+
+pub struct Weather {
+  // Heading the wind blows towards, in radians clockwise from north like a ship's
+  pub wind_direction: f32,
+  // Metres per second
+  pub wind_speed: f32,
+  // Sea state before it is rounded, which builds up slowly
+  sea: f32,
+}
+
+impl Weather {
+  /// Starts with the sea already worked up by the average wind
+  pub fn new(average_wind: f32) -> Self {
+    Self {
+      wind_direction: thread_rng().gen_range(0.0..TAU),
+      wind_speed: average_wind,
+      sea: settled_sea(average_wind),
+    }
+  }
+
+  pub fn step(&mut self, average_wind: f32, delta_t: f32) {
+    let mut rng = thread_rng();
+    // A random walk that keeps getting pulled back towards the average
+    let pull = (average_wind - self.wind_speed) * delta_t / WIND_TIME;
+    let gust = GUSTINESS * average_wind * (2.0 / WIND_TIME).sqrt() * noise(&mut rng, delta_t);
+    self.wind_speed = (self.wind_speed + pull + gust).max(0.0);
+    let shift = WIND_SHIFT / 3600.0_f32.sqrt() * noise(&mut rng, delta_t);
+    self.wind_direction = (self.wind_direction + shift).rem_euclid(TAU);
+    self.raise_sea(delta_t);
+  }
+
+  // Towards what the wind would settle it at if it kept blowing as it is
+  fn raise_sea(&mut self, delta_t: f32) {
+    let settled = settled_sea(self.wind_speed);
+    self.sea += (settled - self.sea) * (1.0 - (-delta_t / SEA_TIME).exp());
+  }
+
+  /// On the Douglas scale, from 0 for a flat calm to 9
+  pub fn sea_state(&self) -> u8 {
+    self.sea.round().clamp(0.0, 9.0) as u8
+  }
+
+  pub fn wave_height(&self) -> f32 {
+    WAVE_HEIGHTS[usize::from(self.sea_state())]
+  }
+
+  /// How far off gunfire can be in this sea, for guns that are off by `calm` in a calm
+  pub fn gun_accuracy(&self, calm: f32) -> f32 {
+    (calm * ROLL_SPREAD.mul_add(self.wave_height(), 1.0)).min(MAX_GUN_SPREAD)
+  }
+
+  pub fn message(&self) -> ServerMessage {
+    ServerMessage::Weather(self.wind_direction, self.wind_speed, self.sea_state())
+  }
+
+  /// In metres per second, the same way round as ship coordinates
+  pub fn wind(&self) -> (f32, f32) {
+    let (sin, cos) = self.wind_direction.sin_cos();
+    (self.wind_speed * sin, -self.wind_speed * cos)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn weather(wind_speed: f32, sea: f32) -> Weather {
+    Weather {
+      wind_direction: 0.0,
+      wind_speed,
+      sea,
+    }
+  }
+
+  #[test]
+  fn wind_is_pulled_back_to_the_average() {
+    // Without an average there are no gusts, so it dies away steadily
+    let mut weather = weather(20.0, 0.0);
+    for _ in 0..WIND_TIME as usize {
+      weather.step(0.0, 1.0);
+    }
+    let expected = 20.0 / std::f32::consts::E;
+    assert!((weather.wind_speed - expected).abs() < 0.01 * expected);
+  }
+
+  #[test]
+  fn sea_builds_up_over_time() {
+    let mut weather = weather(25.0, 0.0);
+    for _ in 0..SEA_TIME as usize {
+      weather.raise_sea(1.0);
+    }
+    // Most of the way to the highest sea state the wind can raise
+    assert_eq!(weather.sea_state(), 6);
+    for _ in 0..4 * SEA_TIME as usize {
+      weather.raise_sea(1.0);
+    }
+    assert_eq!(weather.sea_state(), 9);
+    assert_eq!(Weather::new(25.0).sea_state(), 9);
+  }
+
+  #[test]
+  fn sea_state_covers_the_whole_scale() {
+    assert_eq!(weather(0.0, -0.3).sea_state(), 0);
+    assert_eq!(weather(0.0, 0.0).wave_height(), 0.0);
+    assert_eq!(weather(0.0, 0.0).gun_accuracy(0.01), 0.01);
+    assert_eq!(weather(0.0, 12.0).sea_state(), 9);
+    assert_eq!(weather(0.0, 9.0).wave_height(), 14.0);
+    // Rough seas spread gunfire, but never past the limit
+    assert!(weather(0.0, 9.0).gun_accuracy(0.01) > 0.03);
+    assert_eq!(weather(0.0, 9.0).gun_accuracy(0.5), MAX_GUN_SPREAD);
+  }
+}
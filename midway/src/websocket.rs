@@ -0,0 +1,214 @@
+//! WebSocket listener for browsers and tools that can't open a plain TCP connection
+//!
+//! Each WebSocket message carries one protocol message: a line of text, or a binary frame
+//! without its length prefix. Clients end up as ordinary joining clients, so the game can't
+//! tell them apart from TCP ones.
+use crate::client::{
+  greet, join_request, Incoming, Joining, Request, HANDSHAKE_TIMEOUT, WRITE_TIMEOUT,
+};
+use crate::input::InputFilter;
+use crate::outbox::{outbox, Outgoing};
+use protocol::binary::{decode_client, ServerEncoder, MAX_FRAME_LENGTH};
+use protocol::{Capability, ClientMessage, ErrorCode, ServerMessage};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::spawn;
+use std::time::Instant;
+use tungstenite::protocol::{Role, WebSocketConfig};
+use tungstenite::{accept_with_config, Message, WebSocket};
+
+/// One end of a connection that a thread reads from while another writes to it
+///
+/// Either end can write, since reading answers pings and closes, so every write goes out whole
+/// under a lock to keep frames from the two ends apart.
+struct Half {
+  stream: TcpStream,
+  writer: Arc<Mutex<TcpStream>>,
+}
+
+impl Half {
+  fn split(stream: TcpStream) -> io::Result<(Self, Self)> {
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let other = Self {
+      stream: stream.try_clone()?,
+      writer: writer.clone(),
+    };
+    Ok((Self { stream, writer }, other))
+  }
+}
+
+impl Read for Half {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.stream.read(buf)
+  }
+}
+
+impl Write for Half {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+    writer.write_all(buf)?;
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+    writer.flush()
+  }
+}
+
+// Nothing a client sends is bigger than a binary frame, so don't buffer anything that is
+fn config() -> WebSocketConfig {
+  WebSocketConfig {
+    max_message_size: Some(MAX_FRAME_LENGTH),
+    max_frame_size: Some(MAX_FRAME_LENGTH),
+    ..WebSocketConfig::default()
+  }
+}
+
+fn encode(encoder: &mut ServerEncoder, message: &ServerMessage, binary: bool) -> Message {
+  if binary {
+    Message::Binary(encoder.encode(message))
+  } else {
+    Message::Text(message.to_string())
+  }
+}
+
+// Returns None if the connection is closed
+fn read_message(socket: &mut WebSocket<Half>) -> Option<Incoming> {
+  loop {
+    match socket.read().ok()? {
+      Message::Text(text) => return Some(text.parse()),
+      Message::Binary(data) => return Some(decode_client(&data)),
+      // Pings are answered by the socket itself
+      _ => (),
+    }
+  }
+}
+
+/// Negotiates the protocol and reads which ship to join as, filling in the capabilities as it goes
+fn handshake(
+  socket: &mut WebSocket<Half>,
+  server_name: &str,
+  capabilities: &mut Vec<Capability>,
+) -> Result<Request, Option<String>> {
+  let mut message = read_message(socket).ok_or(None)?;
+  if let Ok(ClientMessage::Hello(version, client_capabilities)) = message {
+    let replies = greet(version, server_name)?;
+    *capabilities = client_capabilities;
+    for reply in replies {
+      socket
+        .send(Message::Text(reply.to_string()))
+        .map_err(|_| None)?;
+    }
+    message = read_message(socket).ok_or(None)?;
+  }
+  Ok(join_request(message)?)
+}
+
+pub fn process_websocket_joining(tx: &Sender<Joining>, listener: &TcpListener, server_name: &str) {
+  for stream in listener.incoming().flatten() {
+    // A client that is slow to say hello mustn't hold up everyone joining after it
+    let (tx, server_name) = (tx.clone(), server_name.to_owned());
+    spawn(move || join(stream, &tx, &server_name));
+  }
+}
+
+/// Upgrades a new connection, shakes hands with the client and passes it on to the game
+fn join(stream: TcpStream, tx: &Sender<Joining>, server_name: &str) {
+  let peer = stream.peer_addr().ok();
+  let address = peer.map_or("unknown".to_owned(), |x| x.to_string());
+  stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+  // A client that stops reading would otherwise hold the writing thread forever
+  stream.set_write_timeout(Some(WRITE_TIMEOUT)).ok();
+  let Ok((reading, writing)) = Half::split(stream) else {
+    println!("{address} failed to connect over WebSocket");
+    return;
+  };
+  let Ok(mut socket) = accept_with_config(reading, Some(config())) else {
+    println!("{address} failed to connect over WebSocket");
+    return;
+  };
+  let mut capabilities = Vec::new();
+  let handshake = handshake(&mut socket, server_name, &mut capabilities);
+  let binary = capabilities.contains(&Capability::Binary);
+  let request = match handshake {
+    Ok(request) => request,
+    Err(Some(reason)) => {
+      println!("{address} failed to join: {reason}");
+      let message = ServerMessage::Reject(reason);
+      socket
+        .send(encode(&mut ServerEncoder::default(), &message, binary))
+        .ok();
+      socket.close(None).ok();
+      socket.flush().ok();
+      return;
+    }
+    Err(None) => {
+      println!("{address} failed to connect");
+      return;
+    }
+  };
+  socket.get_ref().stream.set_read_timeout(None).ok();
+  let (writer, outgoing) = outbox();
+  let writing = WebSocket::from_raw_socket(writing, Role::Server, None);
+  spawn(move || write_websocket(writing, &outgoing, binary));
+  let (tx2, rx) = channel();
+  let notices = capabilities.contains(&Capability::Notice);
+  let (reader_address, reader_writer) = (address.clone(), writer.clone());
+  spawn(move || {
+    if let Some(reason) = process_websocket(socket, &tx2) {
+      println!("Disconnected {reader_address}: {reason}");
+      if notices {
+        let error = ServerMessage::Error(ErrorCode::RateLimited, reason.to_owned());
+        reader_writer.send(error);
+      }
+    }
+  });
+  println!("{address} connected over WebSocket as {request}");
+  let joining = Joining {
+    tx: writer,
+    rx,
+    peer,
+    request,
+    capabilities,
+  };
+  // Only fails if the server has crashed or something
+  tx.send(joining).ok();
+}
+
+/// Sends messages to a WebSocket client until it is dropped
+fn write_websocket(mut socket: WebSocket<Half>, outgoing: &Outgoing, binary: bool) {
+  let mut encoder = ServerEncoder::default();
+  while let Some(message) = outgoing.recv() {
+    if socket.send(encode(&mut encoder, &message, binary)).is_err() {
+      break;
+    }
+  }
+  // The client has been dropped, so hang up once everything is sent
+  socket.close(None).ok();
+  socket.flush().ok();
+  socket.get_ref().stream.shutdown(Shutdown::Both).ok();
+}
+
+/// Reads messages until the client leaves, or returns why it had to be cut off
+fn process_websocket(mut socket: WebSocket<Half>, tx: &Sender<Incoming>) -> Option<&'static str> {
+  let mut filter = InputFilter::new(Instant::now());
+  let mut forward = |message| match filter.check(message, Instant::now()) {
+    Ok(message) => tx.send(message).ok().ok_or(None),
+    Err(reason) => Err(Some(reason)),
+  };
+  loop {
+    let result = match socket.read() {
+      Ok(Message::Text(text)) => text.lines().try_for_each(|line| forward(line.parse())),
+      Ok(Message::Binary(data)) => forward(decode_client(&data)),
+      // Pings and closes are answered by the socket itself
+      Ok(_) => Ok(()),
+      Err(_) => return None,
+    };
+    if let Err(reason) = result {
+      return reason;
+    }
+  }
+}
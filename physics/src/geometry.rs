@@ -0,0 +1,88 @@
+//! Where a ship is on the water, as a rectangle turned to its heading
+use crate::Motion;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Footprint {
+  pub coords: (f32, f32),
+  pub angle: f32,
+  pub length: f32,
+  pub beam: f32,
+}
+
+impl Footprint {
+  pub const fn new(motion: &Motion, length: f32, beam: f32) -> Self {
+    Self {
+      coords: motion.coords,
+      angle: motion.angle,
+      length,
+      beam,
+    }
+  }
+
+  /// The point some distance towards the bow and towards starboard from the middle of the ship
+  pub fn point(&self, ahead: f32, starboard: f32) -> (f32, f32) {
+    let (sin, cos) = self.angle.sin_cos();
+    (
+      self.coords.0 + sin * ahead + cos * starboard,
+      self.coords.1 - cos * ahead + sin * starboard,
+    )
+  }
+
+  /// How far a point is towards the bow and towards starboard, the reverse of [`Self::point`]
+  pub fn offset(&self, point: (f32, f32)) -> (f32, f32) {
+    let x = point.0 - self.coords.0;
+    let y = point.1 - self.coords.1;
+    let (sin, cos) = self.angle.sin_cos();
+    (sin * x - cos * y, cos * x + sin * y)
+  }
+
+  pub fn contains(&self, point: (f32, f32)) -> bool {
+    let (ahead, starboard) = self.offset(point);
+    ahead.abs() <= self.length / 2.0 && starboard.abs() <= self.beam / 2.0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::f32::consts::PI;
+
+  fn footprint(angle: f32) -> Footprint {
+    Footprint {
+      coords: (100.0, -50.0),
+      angle,
+      length: 100.0,
+      beam: 10.0,
+    }
+  }
+
+  #[test]
+  fn bows_point_along_the_heading() {
+    let north = footprint(0.0).point(50.0, 0.0);
+    assert!((north.0 - 100.0).abs() < 1e-3 && (north.1 + 100.0).abs() < 1e-3);
+    let east = footprint(PI / 2.0).point(50.0, 0.0);
+    assert!((east.0 - 150.0).abs() < 1e-3 && (east.1 + 50.0).abs() < 1e-3);
+  }
+
+  #[test]
+  fn offsets_undo_points() {
+    for angle in [0.0, 0.4, PI / 4.0, 2.0, -2.5] {
+      let ship = footprint(angle);
+      let (ahead, starboard) = ship.offset(ship.point(30.0, -4.0));
+      assert!((ahead - 30.0).abs() < 1e-3 && (starboard + 4.0).abs() < 1e-3);
+    }
+  }
+
+  #[test]
+  fn hits_follow_the_heading() {
+    for angle in [0.0, PI / 4.0, PI / 2.0, 3.0] {
+      let ship = footprint(angle);
+      assert!(ship.contains(ship.coords));
+      assert!(ship.contains(ship.point(49.0, 4.0)));
+      assert!(ship.contains(ship.point(-49.0, -4.0)));
+      // Long and thin, so this far abeam misses
+      assert!(!ship.contains(ship.point(0.0, 6.0)));
+      assert!(!ship.contains(ship.point(51.0, 0.0)));
+    }
+  }
+}
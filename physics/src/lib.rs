@@ -0,0 +1,595 @@
+//! How ships move through the water, shared so Enterprise can predict its own ship the same way
+//! Midway moves it
+//!
+//! Everything here is a pure function of its inputs, so the same ticks always give the same result.
+use std::cmp::Ordering;
+use std::f32::consts::TAU;
+
+mod geometry;
+
+pub use geometry::Footprint;
+
+const WATER_VISCOSITY: f32 = 0.000_001;
+const GRAVITY: f32 = 9.81;
+// The friction line is only meant for turbulent flow, and divides by zero at a Reynolds number of
+// 100, so slower ships get the friction coefficient of this one
+const MIN_REYNOLDS_NUMBER: f32 = 100_000.0;
+// How hard the water resists a hull turning, per metre per second of flow past it and length⁴,
+// which has an escort at 10 m/s settle into a turn in about 3 seconds and a battleship in 11
+const YAW_DAMPING: f32 = 0.0005;
+// Even a ship that is stopped has water around it that stops it spinning
+const MIN_FLOW: f32 = 1.0;
+// Drift angle in a steady turn, as a fraction of the length over the radius of the turn
+const SIDESLIP: f32 = 0.3;
+// Extra resistance in rough water, going with the square of the wave height over the length, so
+// a sea that tosses a small hull about barely slows a battleship
+const SEA_RESISTANCE: f32 = 4.0;
+
+// Works on negative numbers too
+fn cube_root(x: f32) -> f32 {
+  let result = x.abs().powf(1.0 / 3.0);
+  result.copysign(x)
+}
+
+/// Engine output for a throttle setting, squared so that small settings are finer
+pub fn throttle(setting: f32) -> f32 {
+  setting * setting.abs()
+}
+
+/// Speed of the water leaving the screw, given the thrust term q and the speed of the ship
+///
+/// This is the real root of v_out³ - v²·v_out = 2q, the largest one when there are three.
+fn outflow(q: f32, velocity: f32) -> f32 {
+  let s = q.powi(2) - velocity.powi(6) / 27.0;
+  match s.total_cmp(&0.0) {
+    Ordering::Equal => 2.0 * cube_root(q),
+    Ordering::Greater => {
+      // q - s can cancel out to almost nothing, so that root comes from the product of the two
+      // instead, which is v²/3
+      let larger = cube_root(q + s.sqrt().copysign(q));
+      larger + velocity.powi(2) / (3.0 * larger)
+    }
+    Ordering::Less => {
+      let v_abs = velocity.abs();
+      let multiplier = (2.0 * v_abs / 3.0_f32.sqrt()).copysign(q);
+      // Rounding can take this just past 1 when s is nearly 0
+      let angle = (q.abs() * 27.0_f32.sqrt() / v_abs.powi(3)).min(1.0).acos();
+      multiplier * (angle / 3.0).cos()
+    }
+  }
+}
+
+/// Runs the simulation in ticks of a fixed length, carrying over whatever time is left, so it
+/// doesn't matter how the time is split up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FixedStep {
+  // Seconds
+  pub tick_length: f32,
+  pending: f32,
+}
+
+impl FixedStep {
+  pub const fn new(tick_length: f32) -> Self {
+    Self {
+      tick_length,
+      pending: 0.0,
+    }
+  }
+
+  /// How many ticks are due after another elapsed seconds
+  pub fn ticks(&mut self, elapsed: f32) -> u32 {
+    self.pending += elapsed;
+    let ticks = (self.pending / self.tick_length).floor();
+    self.pending -= ticks * self.tick_length;
+    ticks as u32
+  }
+}
+
+/// What decides how a ship moves, with the submerged values already picked if it has them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hull {
+  pub length: f32,
+  pub mass: f32,
+  // At full throttle
+  pub power: f32,
+  pub surface_area: f32,
+  pub screw_area: f32,
+  // Form factor, the extra friction from the hull's shape
+  pub k: f32,
+  pub froude_scale_factor: f32,
+  pub turning_circle: f32,
+  // Seconds to put the rudder hard over from amidships, and to open the throttle from stopped
+  pub rudder_time: f32,
+  pub spool_time: f32,
+  // Of the sea the ship is in, in metres
+  pub wave_height: f32,
+  // Submerged hulls don't make waves, or feel them
+  pub submerged: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Motion {
+  pub coords: (f32, f32),
+  pub angle: f32,
+  // Ahead, along the heading
+  pub velocity: f32,
+  // Radians per second, positive to starboard
+  pub yaw_rate: f32,
+  // Sideways, positive to starboard, which the ship does when it slides out of a turn
+  pub drift: f32,
+}
+
+/// Where the rudder and engine actually are, which takes a while to catch up with the orders
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Machinery {
+  // Fraction of hard over, positive to starboard
+  pub rudder: f32,
+  // Output of the engine, on the same scale as [`throttle`]
+  pub engine: f32,
+}
+
+// Moves towards a target by no more than a fraction of the way from 0 to 1 in a time
+fn approach(from: f32, to: f32, delta_t: f32, time: f32) -> f32 {
+  if time <= 0.0 {
+    return to;
+  }
+  let change = delta_t / time;
+  from + (to - from).clamp(-change, change)
+}
+
+impl Machinery {
+  /// Turns the rudder and spools the engine towards their orders for a tick
+  pub fn follow(&mut self, hull: &Hull, throttle: f32, helm: f32, delta_t: f32) {
+    self.rudder = approach(self.rudder, helm, delta_t, hull.rudder_time);
+    self.engine = approach(self.engine, throttle, delta_t, hull.spool_time);
+  }
+}
+
+impl Hull {
+  /// Moves a ship on by delta_t seconds of game time, with the engine putting out engine on the
+  /// scale of [`throttle`] and the rudder where it is right now
+  pub fn step(&self, motion: &mut Motion, engine: f32, rudder: f32, delta_t: f32) {
+    // The rudder sets the yaw rate that puts the ship on its turning circle, but the hull has
+    // to be swung round to it, and keeps turning for a while afterwards
+    let speed = motion.velocity.hypot(motion.drift);
+    let settled_yaw_rate = rudder * 2.0 * speed.copysign(motion.velocity) / self.turning_circle;
+    // Ships slide out of a turn, more for a tighter one
+    let settled_drift = -SIDESLIP * self.length * motion.yaw_rate;
+    let inertia = self.mass * self.length.powi(2) / 12.0;
+    let damping = YAW_DAMPING * (speed + MIN_FLOW) * self.length.powi(4);
+    let response = 1.0 - (-delta_t * damping / inertia).exp();
+    motion.yaw_rate += (settled_yaw_rate - motion.yaw_rate) * response;
+    motion.drift += (settled_drift - motion.drift) * response;
+    motion.angle += motion.yaw_rate * delta_t;
+    let reynolds_number =
+      (self.length * motion.velocity.abs() / WATER_VISCOSITY).max(MIN_REYNOLDS_NUMBER);
+    let c_f = 0.075 / (reynolds_number.log10() - 2.0).powi(2);
+    let mut c_total = c_f * (1.0 + self.k);
+    if !self.submerged {
+      let froude_number = motion.velocity / (GRAVITY * self.length).sqrt();
+      let c_w = self.froude_scale_factor * froude_number.powi(6);
+      let c_sea = SEA_RESISTANCE * (self.wave_height / self.length).powi(2);
+      c_total += c_w + c_sea;
+    }
+    let r_total = c_total * 0.5 * motion.velocity * motion.velocity.abs() * self.surface_area;
+    let net_power = engine * self.power;
+    let q = net_power / self.screw_area;
+    let v_out = outflow(q, motion.velocity);
+    let thrust = self.screw_area * v_out * (v_out - motion.velocity).abs();
+    let net_thrust = thrust - r_total;
+    motion.velocity += net_thrust * delta_t / self.mass;
+    let (sin, cos) = motion.angle.sin_cos();
+    motion.coords.0 += motion.velocity.mul_add(sin, motion.drift * cos) * delta_t;
+    motion.coords.1 += motion.drift.mul_add(sin, -motion.velocity * cos) * delta_t;
+  }
+
+  /// A tick of the ship following its orders
+  pub fn tick(
+    &self,
+    motion: &mut Motion,
+    machinery: &mut Machinery,
+    throttle: f32,
+    helm: f32,
+    delta_t: f32,
+  ) {
+    machinery.follow(self, throttle, helm, delta_t);
+    self.step(motion, machinery.engine, machinery.rudder, delta_t);
+  }
+
+  /// Runs a number of ticks with the same orders
+  pub fn run(
+    &self,
+    motion: &mut Motion,
+    machinery: &mut Machinery,
+    throttle: f32,
+    helm: f32,
+    delta_t: f32,
+    ticks: u32,
+  ) {
+    for _ in 0..ticks {
+      self.tick(motion, machinery, throttle, helm, delta_t);
+    }
+  }
+
+  /// Measures the diameter of the circle the ship settles into at full power and full rudder
+  ///
+  /// For checking a hull against the turning circle it was given.
+  pub fn measure_turning_circle(&self, delta_t: f32) -> f32 {
+    let mut motion = Motion::default();
+    let mut machinery = Machinery::default();
+    // Twenty minutes of game time to get up to speed and settle into the turn
+    let ticks = (1200.0 / delta_t) as u32;
+    self.run(&mut motion, &mut machinery, 1.0, 1.0, delta_t, ticks);
+    let (start, angle) = (motion.coords, motion.angle);
+    let mut diameter: f32 = 0.0;
+    // Half way round is as far as it gets from the start
+    while motion.angle < angle + TAU {
+      self.tick(&mut motion, &mut machinery, 1.0, 1.0, delta_t);
+      let distance = (motion.coords.0 - start.0).hypot(motion.coords.1 - start.1);
+      diameter = diameter.max(distance);
+    }
+    diameter
+  }
+
+  /// Kinetic energy at a speed, in joules
+  pub fn energy(&self, velocity: f32) -> f32 {
+    0.5 * self.mass * velocity.powi(2)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DELTA_T: f32 = 4.0 / 60.0;
+
+  // A destroyer escort
+  const HULL: Hull = Hull {
+    length: 93.3,
+    mass: 1740.0,
+    power: 5933.0,
+    surface_area: 608.4,
+    screw_area: 4.54,
+    k: 0.066,
+    froude_scale_factor: 1.97,
+    turning_circle: 560.0,
+    rudder_time: 10.0,
+    spool_time: 20.0,
+    wave_height: 0.0,
+    submerged: false,
+  };
+
+  const STILL: Motion = Motion {
+    coords: (0.0, 0.0),
+    angle: 0.0,
+    velocity: 0.0,
+    yaw_rate: 0.0,
+    drift: 0.0,
+  };
+
+  // How far v_out is from solving the cubic, relative to the size of the terms
+  fn residual(q: f32, velocity: f32) -> f32 {
+    let v_out = outflow(q, velocity);
+    let error = v_out.powi(3) - velocity.powi(2) * v_out - 2.0 * q;
+    error.abs() / (q.abs() + velocity.abs().powi(3)).max(1.0)
+  }
+
+  #[test]
+  fn cube_roots_keep_their_sign() {
+    assert!((cube_root(27.0) - 3.0).abs() < 1e-5);
+    assert!((cube_root(-8.0) + 2.0).abs() < 1e-5);
+    assert_eq!(cube_root(0.0), 0.0);
+  }
+
+  #[test]
+  fn no_thrust_at_rest() {
+    // s is exactly 0
+    assert_eq!(outflow(0.0, 0.0), 0.0);
+  }
+
+  #[test]
+  fn one_real_root() {
+    // From rest, and when the screw pushes hard against the ship's speed
+    assert!(residual(1000.0, 0.0) < 1e-4);
+    assert!(residual(1000.0, 5.0) < 1e-4);
+    assert!(residual(-500.0, 2.0) < 1e-4);
+    assert!(outflow(1000.0, 0.0) > 0.0);
+    assert!(outflow(-500.0, 2.0) < 0.0);
+  }
+
+  #[test]
+  fn three_real_roots() {
+    // A fast ship with the throttle nearly closed
+    assert!(residual(10.0, 15.0) < 1e-4);
+    assert!(residual(-10.0, 15.0) < 1e-4);
+    assert!(outflow(10.0, 15.0) > 15.0 * 0.9);
+    assert!(outflow(-10.0, -15.0) < 0.0);
+  }
+
+  #[test]
+  fn ships_at_rest_stay_put() {
+    let mut motion = STILL;
+    HULL.run(
+      &mut motion,
+      &mut Machinery::default(),
+      0.0,
+      1.0,
+      DELTA_T,
+      600,
+    );
+    assert_eq!(motion, STILL);
+  }
+
+  #[test]
+  fn slow_ships_have_finite_friction() {
+    // The Reynolds number is 100 here, where the friction line blows up
+    let mut motion = Motion {
+      velocity: 100.0 * WATER_VISCOSITY / HULL.length,
+      ..STILL
+    };
+    HULL.run(
+      &mut motion,
+      &mut Machinery::default(),
+      0.0,
+      0.0,
+      DELTA_T,
+      10,
+    );
+    assert!(motion.velocity.is_finite());
+    assert!(motion.coords.1.is_finite());
+  }
+
+  #[test]
+  fn ships_speed_up_and_settle() {
+    let mut motion = STILL;
+    let mut machinery = Machinery::default();
+    HULL.run(&mut motion, &mut machinery, 1.0, 0.0, DELTA_T, 60 * 60);
+    let cruising = motion.velocity;
+    assert!(cruising > 5.0);
+    HULL.run(&mut motion, &mut machinery, 1.0, 0.0, DELTA_T, 60 * 60);
+    assert!((motion.velocity - cruising).abs() < 0.01);
+    // Heading north, which is up the screen
+    assert!(motion.coords.1 < 0.0);
+    assert!(motion.coords.0.abs() < 1e-3);
+  }
+
+  #[test]
+  fn reverse_thrust_goes_astern() {
+    let mut motion = STILL;
+    HULL.run(
+      &mut motion,
+      &mut Machinery::default(),
+      throttle(-0.5),
+      0.0,
+      DELTA_T,
+      600,
+    );
+    assert!(motion.velocity < 0.0);
+    assert!(motion.coords.1 > 0.0);
+    // and brakes a ship going ahead
+    let mut ahead = Motion {
+      velocity: 5.0,
+      ..STILL
+    };
+    let mut coasting = ahead;
+    HULL.run(
+      &mut ahead,
+      &mut Machinery::default(),
+      throttle(-0.5),
+      0.0,
+      DELTA_T,
+      60,
+    );
+    HULL.run(
+      &mut coasting,
+      &mut Machinery::default(),
+      0.0,
+      0.0,
+      DELTA_T,
+      60,
+    );
+    assert!(ahead.velocity < coasting.velocity);
+  }
+
+  #[test]
+  fn submerged_hulls_make_no_waves() {
+    let submerged = Hull {
+      submerged: true,
+      ..HULL
+    };
+    let mut surface = Motion {
+      velocity: 10.0,
+      ..STILL
+    };
+    let mut under = surface;
+    HULL.step(&mut surface, 0.0, 0.0, DELTA_T);
+    submerged.step(&mut under, 0.0, 0.0, DELTA_T);
+    assert!(under.velocity > surface.velocity);
+  }
+
+  #[test]
+  fn rough_seas_slow_ships_down() {
+    let rough = Hull {
+      wave_height: 5.0,
+      ..HULL
+    };
+    let mut calm_motion = STILL;
+    let mut rough_motion = STILL;
+    HULL.run(
+      &mut calm_motion,
+      &mut Machinery::default(),
+      1.0,
+      0.0,
+      DELTA_T,
+      6000,
+    );
+    rough.run(
+      &mut rough_motion,
+      &mut Machinery::default(),
+      1.0,
+      0.0,
+      DELTA_T,
+      6000,
+    );
+    assert!(rough_motion.velocity < calm_motion.velocity * 0.95);
+    // but not under the waves
+    let mut calm_under = STILL;
+    let mut rough_under = STILL;
+    let submerged = Hull {
+      submerged: true,
+      ..HULL
+    };
+    let rough_submerged = Hull {
+      submerged: true,
+      ..rough
+    };
+    submerged.run(
+      &mut calm_under,
+      &mut Machinery::default(),
+      1.0,
+      0.0,
+      DELTA_T,
+      600,
+    );
+    rough_submerged.run(
+      &mut rough_under,
+      &mut Machinery::default(),
+      1.0,
+      0.0,
+      DELTA_T,
+      600,
+    );
+    assert_eq!(calm_under, rough_under);
+  }
+
+  #[test]
+  fn turning_follows_the_helm() {
+    let mut motion = Motion {
+      velocity: 5.0,
+      ..STILL
+    };
+    HULL.step(&mut motion, 0.0, 1.0, DELTA_T);
+    assert!(motion.angle > 0.0);
+    let mut port = Motion {
+      velocity: 5.0,
+      ..STILL
+    };
+    HULL.step(&mut port, 0.0, -1.0, DELTA_T);
+    assert_eq!(port.angle, -motion.angle);
+  }
+
+  #[test]
+  fn machinery_takes_time_to_answer() {
+    let mut machinery = Machinery::default();
+    // Half a second of a ten second rudder and a twenty second engine
+    for _ in 0..30 {
+      machinery.follow(&HULL, 1.0, -1.0, 1.0 / 60.0);
+    }
+    assert!((machinery.rudder + 0.05).abs() < 1e-4);
+    assert!((machinery.engine - 0.025).abs() < 1e-4);
+    for _ in 0..60 * 30 {
+      machinery.follow(&HULL, 0.25, 0.5, 1.0 / 60.0);
+    }
+    assert_eq!(machinery.rudder, 0.5);
+    assert_eq!(machinery.engine, 0.25);
+    let instant = Hull {
+      rudder_time: 0.0,
+      spool_time: 0.0,
+      ..HULL
+    };
+    machinery.follow(&instant, -0.25, -1.0, 1.0 / 60.0);
+    assert_eq!(machinery.rudder, -1.0);
+    assert_eq!(machinery.engine, -0.25);
+  }
+
+  #[test]
+  fn big_ships_turn_later() {
+    let mut quick = Motion {
+      velocity: 5.0,
+      ..STILL
+    };
+    let mut slow = quick;
+    let sluggish = Hull {
+      rudder_time: 30.0,
+      ..HULL
+    };
+    HULL.run(&mut quick, &mut Machinery::default(), 0.0, 1.0, DELTA_T, 60);
+    sluggish.run(&mut slow, &mut Machinery::default(), 0.0, 1.0, DELTA_T, 60);
+    assert!(slow.angle < quick.angle);
+  }
+
+  #[test]
+  fn turns_settle_on_the_turning_circle() {
+    let diameter = HULL.measure_turning_circle(DELTA_T);
+    assert!(
+      (diameter / HULL.turning_circle - 1.0).abs() < 0.01,
+      "{diameter}"
+    );
+  }
+
+  #[test]
+  fn heavy_hulls_are_slow_to_swing() {
+    let heavy = Hull {
+      mass: HULL.mass * 10.0,
+      ..HULL
+    };
+    let mut light = Motion {
+      velocity: 5.0,
+      ..STILL
+    };
+    let mut laden = light;
+    for _ in 0..30 {
+      HULL.step(&mut light, 0.0, 1.0, DELTA_T);
+      heavy.step(&mut laden, 0.0, 1.0, DELTA_T);
+    }
+    assert!(laden.yaw_rate < light.yaw_rate / 2.0);
+    // and keep swinging once the rudder is amidships
+    for _ in 0..30 {
+      heavy.step(&mut laden, 0.0, 0.0, DELTA_T);
+    }
+    assert!(laden.yaw_rate > 0.0);
+  }
+
+  #[test]
+  fn ships_slide_out_of_turns() {
+    let mut motion = Motion {
+      velocity: 8.0,
+      ..STILL
+    };
+    HULL.run(
+      &mut motion,
+      &mut Machinery::default(),
+      0.0,
+      1.0,
+      DELTA_T,
+      600,
+    );
+    assert!(motion.yaw_rate > 0.0);
+    assert!(motion.drift < 0.0);
+    // Straightening up, the slide dies away
+    HULL.run(
+      &mut motion,
+      &mut Machinery::default(),
+      0.0,
+      0.0,
+      DELTA_T,
+      6000,
+    );
+    assert!(motion.drift.abs() < 1e-3);
+  }
+
+  #[test]
+  fn time_can_be_split_up_any_way() {
+    let mut step = FixedStep::new(0.25);
+    assert_eq!(step.ticks(0.1), 0);
+    assert_eq!(step.ticks(0.2), 1);
+    assert_eq!(step.ticks(0.7), 3);
+    assert_eq!(step.ticks(0.0), 0);
+  }
+
+  #[test]
+  fn energy_grows_with_the_square_of_speed() {
+    assert_eq!(HULL.energy(0.0), 0.0);
+    assert_eq!(HULL.energy(-2.0), 4.0 * HULL.energy(1.0));
+  }
+}
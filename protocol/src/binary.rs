@@ -0,0 +1,630 @@
+//! Compact encoding for clients that ask for the binary capability
+//!
+//! Each message is a frame made of a big-endian u16 length followed by a payload, which starts
+//! with a tag byte. Positions are sent in centimetres, angles in 65536ths of a turn and ships
+//! are named once and referred to by a numeric ID after that. Rare messages are sent as a text
+//! frame holding the usual line.
+//!
+//! Clients with the udp capability send the token they were given as an 8 byte datagram, and
+//! are then sent datagrams holding a big-endian u32 sequence number followed by frames. Every
+//! datagram is encoded with a fresh encoder, so it can be decoded on its own.
+use crate::{ClientMessage, Colour, ParseError, ServerMessage, ShipState, Splash, Wake};
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+use std::io::{Error, ErrorKind, Read, Write};
+
+pub const MAX_FRAME_LENGTH: usize = u16::MAX as usize;
+// Small enough to avoid fragmentation on any sensible link
+pub const MAX_DATAGRAM_LENGTH: usize = 1200;
+
+const TEXT: u8 = 0;
+// Server tags
+const SHIP: u8 = 1;
+const NAMED_SHIP: u8 = 2;
+const SUNK: u8 = 3;
+const SPLASH: u8 = 4;
+const WAKE: u8 = 5;
+const SNAPSHOT: u8 = 6;
+const APPEAR: u8 = 7;
+const DISAPPEAR: u8 = 8;
+// Client tags
+const SAIL: u8 = 1;
+const ACK: u8 = 2;
+
+// Metres are sent as centimetres
+const POSITION_SCALE: f32 = 100.0;
+// Ship and splash sizes are sent as decimetres
+const SIZE_SCALE: f32 = 10.0;
+// Seconds are sent as centiseconds
+const DURATION_SCALE: f32 = 100.0;
+const ANGLE_SCALE: f32 = 65536.0 / TAU;
+const SAIL_SCALE: f32 = 10_000.0;
+
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> std::io::Result<()> {
+  let length = u16::try_from(payload.len())
+    .map_err(|_| Error::new(ErrorKind::InvalidInput, "frame is too long"))?;
+  let mut frame = Vec::with_capacity(payload.len() + 2);
+  frame.extend_from_slice(&length.to_be_bytes());
+  frame.extend_from_slice(payload);
+  writer.write_all(&frame)
+}
+
+/// Reads the next frame into buf, replacing what was there
+pub fn read_frame(reader: &mut impl Read, buf: &mut Vec<u8>) -> std::io::Result<()> {
+  let mut length = [0; 2];
+  reader.read_exact(&mut length)?;
+  buf.resize(u16::from_be_bytes(length).into(), 0);
+  reader.read_exact(buf)
+}
+
+/// Frames packed into a datagram after a sequence number
+pub struct Datagram {
+  bytes: Vec<u8>,
+}
+
+impl Datagram {
+  pub fn new(sequence: u32) -> Self {
+    let mut bytes = Vec::with_capacity(MAX_DATAGRAM_LENGTH);
+    bytes.extend_from_slice(&sequence.to_be_bytes());
+    Self { bytes }
+  }
+
+  /// Adds a frame, returning false if there isn't room for it
+  pub fn push(&mut self, payload: &[u8]) -> bool {
+    if self.bytes.len() + 2 + payload.len() > MAX_DATAGRAM_LENGTH {
+      return false;
+    }
+    write_frame(&mut self.bytes, payload).is_ok()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.bytes.len() == 4
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+}
+
+/// Splits a datagram into its sequence number and frames
+pub fn read_datagram(datagram: &[u8]) -> Result<(u32, Vec<&[u8]>), ParseError> {
+  let mut payload = Payload { bytes: datagram };
+  let sequence = payload.u32()?;
+  let mut frames = Vec::new();
+  while !payload.bytes.is_empty() {
+    let length = payload.u16()?.into();
+    if payload.bytes.len() < length {
+      return Err(ParseError::Truncated);
+    }
+    let (frame, rest) = payload.bytes.split_at(length);
+    frames.push(frame);
+    payload.bytes = rest;
+  }
+  Ok((sequence, frames))
+}
+
+// Floats that don't fit saturate, and NaN becomes 0
+fn quantise_position(metres: f32) -> i32 {
+  (metres * POSITION_SCALE).round() as i32
+}
+
+fn quantise_angle(radians: f32) -> u16 {
+  // Rounding up to a full turn wraps back to 0
+  ((radians.rem_euclid(TAU) * ANGLE_SCALE).round() as u32) as u16
+}
+
+// Most effects stay put, so they leave the drift off the end
+fn push_drift(payload: &mut Vec<u8>, drift: (f32, f32)) {
+  if drift != (0.0, 0.0) {
+    for speed in [drift.0, drift.1] {
+      let speed = (speed * POSITION_SCALE).round() as i16;
+      payload.extend_from_slice(&speed.to_be_bytes());
+    }
+  }
+}
+
+fn text_frame(line: impl ToString) -> Vec<u8> {
+  let mut payload = vec![TEXT];
+  payload.extend_from_slice(line.to_string().as_bytes());
+  payload.truncate(MAX_FRAME_LENGTH);
+  payload
+}
+
+fn push_colour(payload: &mut Vec<u8>, colour: Colour) {
+  payload.extend_from_slice(&[colour.r, colour.g, colour.b, colour.a]);
+}
+
+/// Reads the fields of a frame one at a time
+struct Payload<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+  fn take<const N: usize>(&mut self) -> Result<[u8; N], ParseError> {
+    if self.bytes.len() < N {
+      return Err(ParseError::Truncated);
+    }
+    let (field, rest) = self.bytes.split_at(N);
+    self.bytes = rest;
+    Ok(field.try_into().expect("split at N"))
+  }
+
+  fn u8(&mut self) -> Result<u8, ParseError> {
+    Ok(self.take::<1>()?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16, ParseError> {
+    Ok(u16::from_be_bytes(self.take()?))
+  }
+
+  fn i16(&mut self) -> Result<i16, ParseError> {
+    Ok(i16::from_be_bytes(self.take()?))
+  }
+
+  fn u32(&mut self) -> Result<u32, ParseError> {
+    Ok(u32::from_be_bytes(self.take()?))
+  }
+
+  fn position(&mut self) -> Result<f32, ParseError> {
+    Ok(i32::from_be_bytes(self.take()?) as f32 / POSITION_SCALE)
+  }
+
+  fn size(&mut self) -> Result<f32, ParseError> {
+    Ok(f32::from(self.u16()?) / SIZE_SCALE)
+  }
+
+  fn duration(&mut self) -> Result<f32, ParseError> {
+    Ok(f32::from(self.u16()?) / DURATION_SCALE)
+  }
+
+  fn angle(&mut self) -> Result<f32, ParseError> {
+    Ok(f32::from(self.u16()?) / ANGLE_SCALE)
+  }
+
+  // Only there for effects that move
+  fn drift(&mut self) -> Result<(f32, f32), ParseError> {
+    if self.bytes.is_empty() {
+      return Ok((0.0, 0.0));
+    }
+    let x = f32::from(self.i16()?) / POSITION_SCALE;
+    let y = f32::from(self.i16()?) / POSITION_SCALE;
+    Ok((x, y))
+  }
+
+  fn colour(&mut self) -> Result<Colour, ParseError> {
+    let [r, g, b, a] = self.take()?;
+    Ok(Colour::new(r, g, b, a))
+  }
+
+  fn text(&mut self, length: usize) -> Result<&'a str, ParseError> {
+    if self.bytes.len() < length {
+      return Err(ParseError::Truncated);
+    }
+    let (text, rest) = self.bytes.split_at(length);
+    self.bytes = rest;
+    std::str::from_utf8(text).map_err(|_| ParseError::InvalidText)
+  }
+
+  fn rest(&mut self) -> Result<&'a str, ParseError> {
+    self.text(self.bytes.len())
+  }
+
+  fn finish(self) -> Result<(), ParseError> {
+    if self.bytes.is_empty() {
+      Ok(())
+    } else {
+      Err(ParseError::TrailingBytes)
+    }
+  }
+}
+
+/// Encodes messages for one connection, remembering which ships it has named
+#[derive(Default)]
+pub struct ServerEncoder {
+  ids: HashMap<String, u32>,
+  next_id: u32,
+}
+
+impl ServerEncoder {
+  // Ships are named the first time they are sent, and whenever they appear
+  fn encode_ship(&mut self, ship: &ShipState, appear: bool) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(32);
+    match self.ids.get(&ship.name) {
+      Some(id) if !appear => {
+        payload.push(SHIP);
+        payload.extend_from_slice(&id.to_be_bytes());
+      }
+      id => {
+        let id = id.copied().unwrap_or_else(|| {
+          let id = self.next_id;
+          self.next_id = self.next_id.wrapping_add(1);
+          self.ids.insert(ship.name.clone(), id);
+          id
+        });
+        payload.push(if appear { APPEAR } else { NAMED_SHIP });
+        payload.extend_from_slice(&id.to_be_bytes());
+        // Ship names are short, but don't trust that here
+        let name = &ship.name.as_bytes()[..ship.name.len().min(u8::MAX.into())];
+        payload.push(name.len() as u8);
+        payload.extend_from_slice(name);
+      }
+    }
+    payload.extend_from_slice(&quantise_position(ship.x).to_be_bytes());
+    payload.extend_from_slice(&quantise_position(ship.y).to_be_bytes());
+    payload.extend_from_slice(&quantise_angle(ship.angle).to_be_bytes());
+    let velocity = (ship.velocity * POSITION_SCALE).round() as i16;
+    payload.extend_from_slice(&velocity.to_be_bytes());
+    let size = (ship.size * SIZE_SCALE).round() as u16;
+    payload.extend_from_slice(&size.to_be_bytes());
+    payload.push(ship.texture.min(u8::MAX.into()) as u8);
+    push_colour(&mut payload, ship.colour);
+    payload.push((ship.health * 255.0).round() as u8);
+    payload
+  }
+
+  // Sunk and disappearing ships are forgotten, and named again if they come back
+  fn encode_removal(&mut self, tag: u8, name: &str, message: &ServerMessage) -> Vec<u8> {
+    match self.ids.remove(name) {
+      Some(id) => {
+        let mut payload = vec![tag];
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload
+      }
+      // Never named on this connection, so send the name itself
+      None => text_frame(message),
+    }
+  }
+
+  pub fn encode(&mut self, message: &ServerMessage) -> Vec<u8> {
+    match message {
+      ServerMessage::Ship(ship) => self.encode_ship(ship, false),
+      ServerMessage::Appear(ship) => self.encode_ship(ship, true),
+      ServerMessage::Sunk(name) => self.encode_removal(SUNK, name, message),
+      ServerMessage::Disappear(name) => self.encode_removal(DISAPPEAR, name, message),
+      ServerMessage::Splash(splash) => {
+        let mut payload = Vec::with_capacity(18);
+        payload.push(SPLASH);
+        payload.extend_from_slice(&quantise_position(splash.x).to_be_bytes());
+        payload.extend_from_slice(&quantise_position(splash.y).to_be_bytes());
+        let size = (splash.size * SIZE_SCALE).round() as u16;
+        payload.extend_from_slice(&size.to_be_bytes());
+        let duration = (splash.duration * DURATION_SCALE).round() as u16;
+        payload.extend_from_slice(&duration.to_be_bytes());
+        payload.push(splash.sprite.min(u8::MAX.into()) as u8);
+        push_colour(&mut payload, splash.colour);
+        push_drift(&mut payload, splash.drift);
+        payload
+      }
+      ServerMessage::Wake(wake) => {
+        let mut payload = Vec::with_capacity(17);
+        payload.push(WAKE);
+        payload.extend_from_slice(&quantise_position(wake.x).to_be_bytes());
+        payload.extend_from_slice(&quantise_position(wake.y).to_be_bytes());
+        let size = (wake.size * SIZE_SCALE).round() as u16;
+        payload.extend_from_slice(&size.to_be_bytes());
+        payload.extend_from_slice(&quantise_angle(wake.angle).to_be_bytes());
+        let duration = (wake.duration * DURATION_SCALE).round() as u16;
+        payload.extend_from_slice(&duration.to_be_bytes());
+        let growth = (wake.growth * POSITION_SCALE).round() as i16;
+        payload.extend_from_slice(&growth.to_be_bytes());
+        push_drift(&mut payload, wake.drift);
+        payload
+      }
+      ServerMessage::Snapshot(tick, base) => {
+        // Ticks start at 1, so 0 means a full snapshot
+        let mut payload = vec![SNAPSHOT];
+        payload.extend_from_slice(&tick.to_be_bytes());
+        payload.extend_from_slice(&base.unwrap_or(0).to_be_bytes());
+        payload
+      }
+      _ => text_frame(message),
+    }
+  }
+}
+
+/// Decodes messages from one connection, remembering the names of ships
+#[derive(Default)]
+pub struct ServerDecoder {
+  names: HashMap<u32, String>,
+}
+
+impl ServerDecoder {
+  pub fn decode(&mut self, payload: &[u8]) -> Result<ServerMessage, ParseError> {
+    let mut payload = Payload { bytes: payload };
+    let message = match payload.u8()? {
+      TEXT => return payload.rest()?.parse(),
+      tag @ (SHIP | NAMED_SHIP | APPEAR) => {
+        let id = payload.u32()?;
+        let name = if tag != SHIP {
+          let length = payload.u8()?.into();
+          let name = payload.text(length)?.to_owned();
+          self.names.insert(id, name.clone());
+          name
+        } else {
+          self
+            .names
+            .get(&id)
+            .ok_or(ParseError::UnknownShip(id))?
+            .clone()
+        };
+        let ship = ShipState {
+          name,
+          x: payload.position()?,
+          y: payload.position()?,
+          angle: payload.angle()?,
+          velocity: f32::from(payload.i16()?) / POSITION_SCALE,
+          size: payload.size()?,
+          texture: payload.u8()?.into(),
+          colour: payload.colour()?,
+          health: f32::from(payload.u8()?) / 255.0,
+        };
+        if tag == APPEAR {
+          ServerMessage::Appear(ship)
+        } else {
+          ServerMessage::Ship(ship)
+        }
+      }
+      tag @ (SUNK | DISAPPEAR) => {
+        let id = payload.u32()?;
+        let name = self.names.remove(&id).ok_or(ParseError::UnknownShip(id))?;
+        if tag == SUNK {
+          ServerMessage::Sunk(name)
+        } else {
+          ServerMessage::Disappear(name)
+        }
+      }
+      SPLASH => ServerMessage::Splash(Splash {
+        x: payload.position()?,
+        y: payload.position()?,
+        size: payload.size()?,
+        duration: payload.duration()?,
+        sprite: payload.u8()?.into(),
+        colour: payload.colour()?,
+        drift: payload.drift()?,
+      }),
+      WAKE => ServerMessage::Wake(Wake {
+        x: payload.position()?,
+        y: payload.position()?,
+        size: payload.size()?,
+        angle: payload.angle()?,
+        duration: payload.duration()?,
+        growth: f32::from(payload.i16()?) / POSITION_SCALE,
+        drift: payload.drift()?,
+      }),
+      SNAPSHOT => {
+        let tick = payload.u32()?;
+        let base = payload.u32()?;
+        ServerMessage::Snapshot(tick, (base != 0).then_some(base))
+      }
+      tag => return Err(ParseError::UnknownTag(tag)),
+    };
+    payload.finish()?;
+    Ok(message)
+  }
+}
+
+pub fn encode_client(message: &ClientMessage) -> Vec<u8> {
+  match message {
+    ClientMessage::Sail(power, helm) => {
+      let mut payload = vec![SAIL];
+      payload.extend_from_slice(&((power * SAIL_SCALE).round() as i16).to_be_bytes());
+      payload.extend_from_slice(&((helm * SAIL_SCALE).round() as i16).to_be_bytes());
+      payload
+    }
+    ClientMessage::Ack(tick) => {
+      let mut payload = vec![ACK];
+      payload.extend_from_slice(&tick.to_be_bytes());
+      payload
+    }
+    _ => text_frame(message),
+  }
+}
+
+pub fn decode_client(payload: &[u8]) -> Result<ClientMessage, ParseError> {
+  let mut payload = Payload { bytes: payload };
+  let message = match payload.u8()? {
+    TEXT => return payload.rest()?.parse(),
+    SAIL => {
+      let power = f32::from(payload.i16()?) / SAIL_SCALE;
+      let helm = f32::from(payload.i16()?) / SAIL_SCALE;
+      ClientMessage::Sail(power, helm)
+    }
+    ACK => ClientMessage::Ack(payload.u32()?),
+    tag => return Err(ParseError::UnknownTag(tag)),
+  };
+  payload.finish()?;
+  Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{Capability, ChatScope};
+
+  fn assert_close(a: f32, b: f32, tolerance: f32) {
+    assert!((a - b).abs() <= tolerance, "{a} is not close to {b}");
+  }
+
+  #[test]
+  fn ships_are_named_once() {
+    let ship = ShipState {
+      name: "Yorktown".to_owned(),
+      x: -444.218_02,
+      y: 246.136_14,
+      angle: 7.0,
+      velocity: 12.345_678,
+      size: 93.3,
+      texture: 1,
+      colour: Colour::new(0x99, 0x99, 0x99, 255),
+      health: 0.75,
+    };
+    let mut encoder = ServerEncoder::default();
+    let mut decoder = ServerDecoder::default();
+    let first = encoder.encode(&ServerMessage::Ship(ship.clone()));
+    let second = encoder.encode(&ServerMessage::Ship(ship.clone()));
+    assert_eq!(first.len(), second.len() + 1 + ship.name.len());
+    assert!(second.len() < ServerMessage::Ship(ship.clone()).to_string().len() / 2);
+    for payload in [first, second] {
+      let Ok(ServerMessage::Ship(decoded)) = decoder.decode(&payload) else {
+        panic!("expected a ship");
+      };
+      assert_eq!(decoded.name, ship.name);
+      assert_close(decoded.x, ship.x, 0.005);
+      assert_close(decoded.y, ship.y, 0.005);
+      assert_close(decoded.angle, ship.angle - TAU, 0.001);
+      assert_close(decoded.velocity, ship.velocity, 0.005);
+      assert_close(decoded.size, ship.size, 0.05);
+      assert_eq!(decoded.texture, ship.texture);
+      assert_eq!(decoded.colour, ship.colour);
+      assert_close(decoded.health, ship.health, 0.002);
+    }
+    let sunk = ServerMessage::Sunk("Yorktown".to_owned());
+    assert_eq!(encoder.encode(&sunk).len(), 5);
+    assert_eq!(decoder.decode(&encoder.encode(&sunk)), Ok(sunk.clone()));
+    // The name has been forgotten on both sides
+    assert_eq!(decoder.decode(&encoder.encode(&sunk)), Ok(sunk));
+    let appear = encoder.encode(&ServerMessage::Appear(ship.clone()));
+    assert_eq!(appear[0], APPEAR);
+    assert!(matches!(
+      decoder.decode(&appear),
+      Ok(ServerMessage::Appear(decoded)) if decoded.name == ship.name
+    ));
+    let disappear = ServerMessage::Disappear("Yorktown".to_owned());
+    assert_eq!(encoder.encode(&disappear)[0], DISAPPEAR);
+    assert_eq!(decoder.decode(&[DISAPPEAR, 0, 0, 0, 1]), Ok(disappear));
+    assert_eq!(
+      decoder.decode(&[SHIP, 0, 0, 0, 1]),
+      Err(ParseError::UnknownShip(1))
+    );
+  }
+
+  #[test]
+  fn effects_round_trip() {
+    let mut encoder = ServerEncoder::default();
+    let mut decoder = ServerDecoder::default();
+    let splash = Splash {
+      x: 9.27,
+      y: 4.47,
+      size: 10.7,
+      duration: 0.25,
+      sprite: 2,
+      colour: Colour::new(0, 0, 0, 0x99),
+      drift: (0.0, 0.0),
+    };
+    let message = ServerMessage::Splash(splash.clone());
+    assert_eq!(decoder.decode(&encoder.encode(&message)), Ok(message));
+    let smoke = ServerMessage::Splash(Splash {
+      drift: (-12.5, 3.25),
+      ..splash
+    });
+    let payload = encoder.encode(&smoke);
+    assert_eq!(payload.len(), 22);
+    assert_eq!(decoder.decode(&payload), Ok(smoke));
+    let wake = Wake {
+      x: 1.0,
+      y: -2.5,
+      size: 16.6,
+      angle: 0.0,
+      duration: 10.0,
+      growth: 4.2,
+      drift: (0.0, 0.0),
+    };
+    let message = ServerMessage::Wake(wake.clone());
+    assert_eq!(decoder.decode(&encoder.encode(&message)), Ok(message));
+    let carried = ServerMessage::Wake(Wake {
+      drift: (0.5, -1.25),
+      ..wake
+    });
+    assert_eq!(decoder.decode(&encoder.encode(&carried)), Ok(carried));
+    for message in [
+      ServerMessage::Snapshot(120, Some(117)),
+      ServerMessage::Snapshot(1, None),
+    ] {
+      assert_eq!(decoder.decode(&encoder.encode(&message)), Ok(message));
+    }
+  }
+
+  #[test]
+  fn rare_messages_are_text() {
+    let mut encoder = ServerEncoder::default();
+    let mut decoder = ServerDecoder::default();
+    for message in [
+      ServerMessage::Hello(2, Capability::ALL.to_vec()),
+      ServerMessage::Welcome("Yorktown2".to_owned()),
+      ServerMessage::Radius(2000.0),
+      ServerMessage::Say("Server is shutting down".to_owned()),
+      ServerMessage::Notice("Welcome aboard".to_owned()),
+      ServerMessage::Chat(
+        ChatScope::Team,
+        "Hornet".to_owned(),
+        "Form up on me".to_owned(),
+      ),
+      ServerMessage::Sunk("Kraken".to_owned()),
+      ServerMessage::Clock(60, 4.0),
+      ServerMessage::Weather(4.2, 11.5, 5),
+    ] {
+      let payload = encoder.encode(&message);
+      assert_eq!(payload[0], TEXT);
+      assert_eq!(decoder.decode(&payload), Ok(message));
+    }
+    for message in [
+      ClientMessage::Sail(0.81, -1.0),
+      ClientMessage::Anchor,
+      ClientMessage::Action(3),
+      ClientMessage::Ack(120),
+    ] {
+      assert_eq!(decode_client(&encode_client(&message)), Ok(message));
+    }
+  }
+
+  #[test]
+  fn frames_round_trip() {
+    let mut stream = Vec::new();
+    write_frame(&mut stream, b"hello").unwrap();
+    write_frame(&mut stream, b"").unwrap();
+    assert_eq!(stream, b"\x00\x05hello\x00\x00");
+    let mut reader = stream.as_slice();
+    let mut buf = Vec::new();
+    read_frame(&mut reader, &mut buf).unwrap();
+    assert_eq!(buf, b"hello");
+    read_frame(&mut reader, &mut buf).unwrap();
+    assert!(buf.is_empty());
+    assert!(read_frame(&mut reader, &mut buf).is_err());
+    assert!(write_frame(&mut stream, &[0; MAX_FRAME_LENGTH + 1]).is_err());
+  }
+
+  #[test]
+  fn datagrams_round_trip() {
+    let mut datagram = Datagram::new(7);
+    assert!(datagram.is_empty());
+    assert!(datagram.push(b"one"));
+    assert!(datagram.push(b""));
+    assert!(!datagram.push(&[0; MAX_DATAGRAM_LENGTH]));
+    assert_eq!(
+      read_datagram(datagram.as_bytes()),
+      Ok((7, vec![&b"one"[..], &b""[..]]))
+    );
+    assert_eq!(read_datagram(&[0, 0, 0]), Err(ParseError::Truncated));
+    assert_eq!(
+      read_datagram(&[0, 0, 0, 1, 0, 5, 1]),
+      Err(ParseError::Truncated)
+    );
+  }
+
+  #[test]
+  fn bad_frames_are_errors() {
+    let mut decoder = ServerDecoder::default();
+    assert_eq!(decoder.decode(&[]), Err(ParseError::Truncated));
+    assert_eq!(decoder.decode(&[SUNK, 0, 0]), Err(ParseError::Truncated));
+    assert_eq!(
+      decoder.decode(&[SUNK, 0, 0, 0, 7]),
+      Err(ParseError::UnknownShip(7))
+    );
+    assert_eq!(decoder.decode(&[42]), Err(ParseError::UnknownTag(42)));
+    assert_eq!(
+      decode_client(&[SAIL, 0, 0, 0, 0, 0]),
+      Err(ParseError::TrailingBytes)
+    );
+    assert_eq!(decode_client(&[TEXT, 0xff]), Err(ParseError::InvalidText));
+  }
+}
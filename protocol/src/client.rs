@@ -0,0 +1,204 @@
+use crate::{Capability, ChatScope, Fields, ParseError};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Messages from Enterprise to Midway
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientMessage {
+  // Protocol version and the optional messages the client understands
+  Hello(u32, Vec<Capability>),
+  // Joins with a ship name, which may be invalid
+  Ship(String),
+  // Joins as the ship a session token was issued for, instead of a new one
+  Resume(u64),
+  // Joins without a ship, only to watch
+  Spectate,
+  // Power, helm
+  Sail(f32, f32),
+  Anchor,
+  Smoke,
+  Action(usize),
+  // The client has applied this snapshot
+  Ack(u32),
+  Chat(ChatScope, String),
+  // Any timestamp the client likes, echoed back in a pong
+  Ping(u64),
+}
+
+impl Display for ClientMessage {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Hello(version, capabilities) => {
+        write!(f, "hello {version}")?;
+        for capability in capabilities {
+          write!(f, " {}", capability.name())?;
+        }
+        Ok(())
+      }
+      Self::Ship(name) => write!(f, "ship {name}"),
+      Self::Resume(token) => write!(f, "resume {token}"),
+      Self::Spectate => write!(f, "spectate"),
+      Self::Sail(power, helm) => write!(f, "sail {power} {helm}"),
+      Self::Anchor => write!(f, "anchor"),
+      Self::Smoke => write!(f, "smoke"),
+      Self::Action(action) => write!(f, "action {action}"),
+      Self::Ack(tick) => write!(f, "ack {tick}"),
+      Self::Chat(scope, text) => write!(f, "chat {scope} {text}"),
+      Self::Ping(timestamp) => write!(f, "ping {timestamp}"),
+    }
+  }
+}
+
+impl FromStr for ClientMessage {
+  type Err = ParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut words = s.split_whitespace();
+    let message = match words.next() {
+      Some("hello") => {
+        let mut fields = Fields::new("hello", words);
+        let version = fields.parse("version")?;
+        // Capabilities this side doesn't know about are ignored
+        let capabilities = fields.words.filter_map(Capability::from_name).collect();
+        return Ok(Self::Hello(version, capabilities));
+      }
+      Some("ship") => return Ok(Self::Ship(Fields::new("ship", words).rest())),
+      Some("resume") => {
+        let mut fields = Fields::new("resume", words);
+        let token = fields.parse("token")?;
+        fields.finish()?;
+        Self::Resume(token)
+      }
+      Some("spectate") => {
+        Fields::new("spectate", words).finish()?;
+        Self::Spectate
+      }
+      Some("sail") => {
+        let mut fields = Fields::new("sail", words);
+        let power = fields.parse("power")?;
+        let helm = fields.parse("helm")?;
+        fields.finish()?;
+        Self::Sail(power, helm)
+      }
+      Some("anchor") => {
+        Fields::new("anchor", words).finish()?;
+        Self::Anchor
+      }
+      Some("smoke") => {
+        Fields::new("smoke", words).finish()?;
+        Self::Smoke
+      }
+      Some("action") => {
+        let mut fields = Fields::new("action", words);
+        let action = fields.parse("action")?;
+        fields.finish()?;
+        Self::Action(action)
+      }
+      Some("ack") => {
+        let mut fields = Fields::new("ack", words);
+        let tick = fields.parse("tick")?;
+        fields.finish()?;
+        Self::Ack(tick)
+      }
+      Some("ping") => {
+        let mut fields = Fields::new("ping", words);
+        let timestamp = fields.parse("timestamp")?;
+        fields.finish()?;
+        Self::Ping(timestamp)
+      }
+      Some("chat") => {
+        let mut fields = Fields::new("chat", words);
+        let scope = ChatScope::parse(&mut fields)?;
+        return Ok(Self::Chat(scope, fields.rest()));
+      }
+      Some(word) => return Err(ParseError::UnknownMessage(word.to_owned())),
+      None => return Err(ParseError::Empty),
+    };
+    Ok(message)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn round_trip(message: ClientMessage) {
+    assert_eq!(message.to_string().parse(), Ok(message));
+  }
+
+  #[test]
+  fn messages_round_trip() {
+    round_trip(ClientMessage::Hello(
+      2,
+      vec![Capability::Welcome, Capability::Say],
+    ));
+    round_trip(ClientMessage::Hello(1, Vec::new()));
+    round_trip(ClientMessage::Ship("Enterprise".to_owned()));
+    round_trip(ClientMessage::Resume(u64::MAX));
+    round_trip(ClientMessage::Spectate);
+    round_trip(ClientMessage::Sail(0.81, -1.0));
+    round_trip(ClientMessage::Sail(-0.25, 0.0));
+    round_trip(ClientMessage::Anchor);
+    round_trip(ClientMessage::Smoke);
+    round_trip(ClientMessage::Action(3));
+    round_trip(ClientMessage::Ack(4_000_000_000));
+    round_trip(ClientMessage::Ping(u64::MAX));
+    round_trip(ClientMessage::Chat(
+      ChatScope::Global,
+      "Good hunting".to_owned(),
+    ));
+    round_trip(ClientMessage::Chat(
+      ChatScope::Team,
+      "Form up on me".to_owned(),
+    ));
+    round_trip(ClientMessage::Chat(
+      ChatScope::Whisper("Hornet".to_owned()),
+      "Cover my flank".to_owned(),
+    ));
+  }
+
+  #[test]
+  fn unknown_capabilities_are_ignored() {
+    assert_eq!(
+      "hello 2 say teleport".parse(),
+      Ok(ClientMessage::Hello(2, vec![Capability::Say]))
+    );
+  }
+
+  #[test]
+  fn errors_describe_the_problem() {
+    assert_eq!("".parse::<ClientMessage>(), Err(ParseError::Empty));
+    assert_eq!(
+      "fly 1".parse::<ClientMessage>(),
+      Err(ParseError::UnknownMessage("fly".to_owned()))
+    );
+    assert_eq!(
+      "sail 1".parse::<ClientMessage>(),
+      Err(ParseError::MissingField("sail", "helm"))
+    );
+    assert_eq!(
+      "sail full 0".parse::<ClientMessage>(),
+      Err(ParseError::InvalidField("sail", "power", "full".to_owned()))
+    );
+    assert_eq!(
+      "action 1 2".parse::<ClientMessage>(),
+      Err(ParseError::TooManyFields("action"))
+    );
+    assert_eq!(
+      "chat everyone hi".parse::<ClientMessage>(),
+      Err(ParseError::InvalidField(
+        "chat",
+        "scope",
+        "everyone".to_owned()
+      ))
+    );
+    assert_eq!(
+      "chat whisper".parse::<ClientMessage>(),
+      Err(ParseError::MissingField("chat", "name"))
+    );
+    assert_eq!(
+      ParseError::MissingField("sail", "helm").to_string(),
+      "sail is missing helm"
+    );
+  }
+}
@@ -0,0 +1,281 @@
+//! Messages passed between Midway and Enterprise
+//!
+//! Every message is a single line of whitespace separated words, starting with the message type.
+//! Clients that ask for it get the compact encoding in [`binary`] once the handshake is done.
+use std::fmt::{Display, Formatter};
+use std::str::{FromStr, SplitWhitespace};
+
+pub mod binary;
+mod client;
+mod server;
+
+pub use client::ClientMessage;
+pub use server::{ErrorCode, ServerMessage, ShipState, Splash, Wake};
+
+pub const PROTOCOL_VERSION: u32 = 2;
+// Longest chat message in characters, longer ones are refused
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+/// Optional messages, only sent to clients that ask for them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+  Welcome,
+  Say,
+  // Everything after the hello exchange uses binary frames
+  Binary,
+  // Ships are sent in numbered snapshots holding only what changed
+  Snapshot,
+  // Ships coming into and going out of range are announced
+  Appear,
+  // Snapshots and effects can be sent as datagrams, to clients that acknowledge snapshots
+  Udp,
+  // Errors and notices meant for the player
+  Notice,
+  // A token for resuming the same ship after losing the connection
+  Session,
+  // Clients can join without a ship to watch
+  Spectate,
+  // Chat between players, instead of the text of each message being said
+  Chat,
+  // Pings are answered, and the server reports how well it is keeping up
+  Ping,
+  // The clock and the player's hull, so the client can move its own ship before the server does
+  Predict,
+  // Wind and sea state, and effects that drift with the wind and current
+  Weather,
+}
+
+impl Capability {
+  pub const ALL: &'static [Self] = &[
+    Self::Welcome,
+    Self::Say,
+    Self::Binary,
+    Self::Snapshot,
+    Self::Appear,
+    Self::Udp,
+    Self::Notice,
+    Self::Session,
+    Self::Spectate,
+    Self::Chat,
+    Self::Ping,
+    Self::Predict,
+    Self::Weather,
+  ];
+
+  pub const fn name(self) -> &'static str {
+    match self {
+      Self::Welcome => "welcome",
+      Self::Say => "say",
+      Self::Binary => "binary",
+      Self::Snapshot => "snapshot",
+      Self::Appear => "appear",
+      Self::Udp => "udp",
+      Self::Notice => "notice",
+      Self::Session => "session",
+      Self::Spectate => "spectate",
+      Self::Chat => "chat",
+      Self::Ping => "ping",
+      Self::Predict => "predict",
+      Self::Weather => "weather",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL
+      .iter()
+      .copied()
+      .find(|capability| capability.name() == name)
+  }
+}
+
+/// Who a chat message is for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChatScope {
+  Global,
+  Team,
+  // Name of the one player who gets it
+  Whisper(String),
+}
+
+impl ChatScope {
+  fn parse(fields: &mut Fields<'_>) -> Result<Self, ParseError> {
+    match fields.word("scope")? {
+      "global" => Ok(Self::Global),
+      "team" => Ok(Self::Team),
+      "whisper" => Ok(Self::Whisper(fields.word("name")?.to_owned())),
+      scope => Err(ParseError::InvalidField(
+        fields.message,
+        "scope",
+        scope.to_owned(),
+      )),
+    }
+  }
+}
+
+impl Display for ChatScope {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Global => write!(f, "global"),
+      Self::Team => write!(f, "team"),
+      Self::Whisper(name) => write!(f, "whisper {name}"),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Colour {
+  pub r: u8,
+  pub g: u8,
+  pub b: u8,
+  pub a: u8,
+}
+
+impl Colour {
+  pub const fn new(r: u8, g: u8, b: u8, a: u8) -> Self {
+    Self { r, g, b, a }
+  }
+}
+
+impl FromStr for Colour {
+  type Err = ();
+
+  /// Parses #rgb, #rgba, #rrggbb or #rrggbbaa, the # is optional
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+      return Err(());
+    }
+    let digit = |i: usize| u8::from_str_radix(&hex[i..=i], 16).map_err(|_| ());
+    let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| ());
+    match hex.len() {
+      3 => Ok(Self::new(
+        digit(0)? * 17,
+        digit(1)? * 17,
+        digit(2)? * 17,
+        255,
+      )),
+      4 => Ok(Self::new(
+        digit(0)? * 17,
+        digit(1)? * 17,
+        digit(2)? * 17,
+        digit(3)? * 17,
+      )),
+      6 => Ok(Self::new(byte(0)?, byte(2)?, byte(4)?, 255)),
+      8 => Ok(Self::new(byte(0)?, byte(2)?, byte(4)?, byte(6)?)),
+      _ => Err(()),
+    }
+  }
+}
+
+impl Display for Colour {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let Self { r, g, b, a } = self;
+    if *a == 255 {
+      write!(f, "#{r:02x}{g:02x}{b:02x}")
+    } else {
+      write!(f, "#{r:02x}{g:02x}{b:02x}{a:02x}")
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+  Empty,
+  UnknownMessage(String),
+  MissingField(&'static str, &'static str),
+  InvalidField(&'static str, &'static str, String),
+  TooManyFields(&'static str),
+  // Binary frames
+  Truncated,
+  TrailingBytes,
+  UnknownTag(u8),
+  UnknownShip(u32),
+  InvalidText,
+}
+
+impl Display for ParseError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Empty => write!(f, "Empty message"),
+      Self::UnknownMessage(message) => write!(f, "Unknown message {message}"),
+      Self::MissingField(message, field) => write!(f, "{message} is missing {field}"),
+      Self::InvalidField(message, field, value) => {
+        write!(f, "Invalid {field} {value:?} in {message}")
+      }
+      Self::TooManyFields(message) => write!(f, "Too many fields in {message}"),
+      Self::Truncated => write!(f, "Frame is too short"),
+      Self::TrailingBytes => write!(f, "Frame is too long"),
+      Self::UnknownTag(tag) => write!(f, "Unknown frame type {tag}"),
+      Self::UnknownShip(id) => write!(f, "Unknown ship {id}"),
+      Self::InvalidText => write!(f, "Text is not valid UTF-8"),
+    }
+  }
+}
+
+/// Reads the fields of a message one at a time
+struct Fields<'a> {
+  message: &'static str,
+  words: SplitWhitespace<'a>,
+}
+
+impl<'a> Fields<'a> {
+  const fn new(message: &'static str, words: SplitWhitespace<'a>) -> Self {
+    Self { message, words }
+  }
+
+  fn word(&mut self, field: &'static str) -> Result<&'a str, ParseError> {
+    self
+      .words
+      .next()
+      .ok_or(ParseError::MissingField(self.message, field))
+  }
+
+  fn parse<T: FromStr>(&mut self, field: &'static str) -> Result<T, ParseError> {
+    let word = self.word(field)?;
+    word
+      .parse()
+      .map_err(|_| ParseError::InvalidField(self.message, field, word.to_owned()))
+  }
+
+  fn optional<T: FromStr>(&mut self, field: &'static str) -> Result<Option<T>, ParseError> {
+    match self.words.next() {
+      Some(word) => word
+        .parse()
+        .map(Some)
+        .map_err(|_| ParseError::InvalidField(self.message, field, word.to_owned())),
+      None => Ok(None),
+    }
+  }
+
+  // Free text that takes up the rest of the line
+  fn rest(self) -> String {
+    self.words.collect::<Vec<_>>().join(" ")
+  }
+
+  fn finish(mut self) -> Result<(), ParseError> {
+    match self.words.next() {
+      Some(_) => Err(ParseError::TooManyFields(self.message)),
+      None => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn colour_round_trip() {
+    for colour in ["#999999", "#336699", "#00000099", "#ff0000"] {
+      assert_eq!(colour.parse::<Colour>().unwrap().to_string(), colour);
+    }
+  }
+
+  #[test]
+  fn short_colours() {
+    assert_eq!("#999".parse(), Ok(Colour::new(0x99, 0x99, 0x99, 255)));
+    assert_eq!("0009".parse(), Ok(Colour::new(0, 0, 0, 0x99)));
+    assert_eq!("#12345".parse::<Colour>(), Err(()));
+    assert_eq!("#ggg".parse::<Colour>(), Err(()));
+  }
+}
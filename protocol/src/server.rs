@@ -0,0 +1,623 @@
+use crate::{Capability, ChatScope, Colour, Fields, ParseError};
+use physics::{Hull, Machinery};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShipState {
+  pub name: String,
+  pub x: f32,
+  pub y: f32,
+  pub angle: f32,
+  pub velocity: f32,
+  pub size: f32,
+  pub texture: usize,
+  pub colour: Colour,
+  // Fraction of health remaining
+  pub health: f32,
+}
+
+impl Display for ShipState {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let Self {
+      name,
+      x,
+      y,
+      angle,
+      velocity,
+      size,
+      texture,
+      colour,
+      health,
+    } = self;
+    write!(
+      f,
+      "{name} {x} {y} {angle} {velocity} {size} {texture} {colour} {health}"
+    )
+  }
+}
+
+impl ShipState {
+  fn parse(mut fields: Fields) -> Result<Self, ParseError> {
+    let ship = Self {
+      name: fields.word("name")?.to_owned(),
+      x: fields.parse("x")?,
+      y: fields.parse("y")?,
+      angle: fields.parse("angle")?,
+      velocity: fields.parse("velocity")?,
+      size: fields.parse("size")?,
+      texture: fields.parse("texture")?,
+      colour: fields.parse("colour")?,
+      health: fields.parse("health")?,
+    };
+    fields.finish()?;
+    Ok(ship)
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Splash {
+  pub x: f32,
+  pub y: f32,
+  pub size: f32,
+  // Seconds
+  pub duration: f32,
+  pub sprite: usize,
+  pub colour: Colour,
+  // Metres per second it moves at, such as smoke blowing downwind, only sent if it moves
+  pub drift: (f32, f32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Wake {
+  pub x: f32,
+  pub y: f32,
+  pub size: f32,
+  pub angle: f32,
+  // Seconds
+  pub duration: f32,
+  // Metres per second
+  pub growth: f32,
+  // Metres per second it is carried along at by the current, only sent if it moves
+  pub drift: (f32, f32),
+}
+
+/// What went wrong with something the client sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+  UnknownMessage,
+  InvalidMessage,
+  // The client sent too much, so the message was dropped or the client is being disconnected
+  RateLimited,
+  // An admin has stopped the player from chatting
+  Muted,
+}
+
+impl ErrorCode {
+  pub const ALL: &'static [Self] = &[
+    Self::UnknownMessage,
+    Self::InvalidMessage,
+    Self::RateLimited,
+    Self::Muted,
+  ];
+
+  pub const fn name(self) -> &'static str {
+    match self {
+      Self::UnknownMessage => "unknown_message",
+      Self::InvalidMessage => "invalid_message",
+      Self::RateLimited => "rate_limited",
+      Self::Muted => "muted",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.iter().copied().find(|code| code.name() == name)
+  }
+}
+
+impl From<&ParseError> for ErrorCode {
+  fn from(error: &ParseError) -> Self {
+    match error {
+      ParseError::Empty | ParseError::UnknownMessage(_) | ParseError::UnknownTag(_) => {
+        Self::UnknownMessage
+      }
+      _ => Self::InvalidMessage,
+    }
+  }
+}
+
+impl FromStr for ErrorCode {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Self::from_name(s).ok_or(())
+  }
+}
+
+/// Messages from Midway to Enterprise
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerMessage {
+  // Protocol version and the optional messages the server supports
+  Hello(u32, Vec<Capability>),
+  // Name of the server
+  Server(String),
+  // The name the player joined as
+  Welcome(String),
+  // Why the client can't join
+  Reject(String),
+  Radius(f32),
+  Ship(ShipState),
+  // Part of a snapshot, and repeated in each one until the client acknowledges one of them
+  Sunk(String),
+  Splash(Splash),
+  Wake(Wake),
+  Say(String),
+  // Tick number and the acknowledged tick it is relative to, or None if the client should
+  // forget every ship it knows about first
+  Snapshot(u32, Option<u32>),
+  // A ship has come into range, or the client is seeing it for the first time
+  Appear(ShipState),
+  // A ship is out of range and no more updates will be sent for it
+  Disappear(String),
+  // Token the client sends in a datagram so the server knows where to send state
+  Udp(u64),
+  // Something the client sent was dropped, and why
+  Error(ErrorCode, String),
+  // Anything else the player should see, such as the message of the day or a kick
+  Notice(String),
+  // Token the client can resume its ship with if the connection drops
+  Session(u64),
+  // Scope, the player who sent it and the text, whispers are named after who they are for
+  Chat(ChatScope, String, String),
+  // The timestamp from a ping, sent back once the game has seen it
+  Pong(u64),
+  // Current tick, the ticks per second the server aims for and the fraction of the last second
+  // it spent working, which approaches 1 as it falls behind
+  Status(u32, u32, f32),
+  // Ticks per second and how many seconds of game time pass in each real one
+  Clock(u32, f32),
+  // How the player's own ship moves, sent again whenever that changes
+  Hull(Hull),
+  // Where the player's rudder and engine are, sent with snapshots while they move
+  Machinery(Machinery),
+  // How fast the player's ship is swinging in radians per second, and sliding sideways in metres
+  // per second, both positive to starboard, sent with snapshots while they change
+  Turn(f32, f32),
+  // Heading the wind blows towards, its speed in metres per second and the Douglas sea state,
+  // from 0 for a flat calm to 9
+  Weather(f32, f32, u8),
+  // Velocity of the water where the player's ship is, sent with snapshots when it changes
+  Current(f32, f32),
+}
+
+impl Display for ServerMessage {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Hello(version, capabilities) => {
+        write!(f, "hello {version}")?;
+        for capability in capabilities {
+          write!(f, " {}", capability.name())?;
+        }
+        Ok(())
+      }
+      Self::Server(name) => write!(f, "server {name}"),
+      Self::Welcome(name) => write!(f, "welcome {name}"),
+      Self::Reject(reason) => write!(f, "reject {reason}"),
+      Self::Radius(radius) => write!(f, "radius {radius}"),
+      Self::Ship(ship) => write!(f, "ship {ship}"),
+      Self::Sunk(name) => write!(f, "sunk {name}"),
+      Self::Splash(Splash {
+        x,
+        y,
+        size,
+        duration,
+        sprite,
+        colour,
+        drift: (0.0, 0.0),
+      }) => write!(f, "splash {x} {y} {size} {duration} {sprite} {colour}"),
+      Self::Splash(Splash {
+        x,
+        y,
+        size,
+        duration,
+        sprite,
+        colour,
+        drift: (drift_x, drift_y),
+      }) => write!(
+        f,
+        "splash {x} {y} {size} {duration} {sprite} {colour} {drift_x} {drift_y}"
+      ),
+      Self::Wake(Wake {
+        x,
+        y,
+        size,
+        angle,
+        duration,
+        growth,
+        drift: (0.0, 0.0),
+      }) => write!(f, "wake {x} {y} {size} {angle} {duration} {growth}"),
+      Self::Wake(Wake {
+        x,
+        y,
+        size,
+        angle,
+        duration,
+        growth,
+        drift: (drift_x, drift_y),
+      }) => write!(
+        f,
+        "wake {x} {y} {size} {angle} {duration} {growth} {drift_x} {drift_y}"
+      ),
+      Self::Say(text) => write!(f, "say {text}"),
+      Self::Snapshot(tick, Some(base)) => write!(f, "snapshot {tick} {base}"),
+      Self::Snapshot(tick, None) => write!(f, "snapshot {tick}"),
+      Self::Appear(ship) => write!(f, "appear {ship}"),
+      Self::Disappear(name) => write!(f, "disappear {name}"),
+      Self::Udp(token) => write!(f, "udp {token}"),
+      Self::Error(code, text) => write!(f, "error {} {text}", code.name()),
+      Self::Notice(text) => write!(f, "notice {text}"),
+      Self::Session(token) => write!(f, "session {token}"),
+      Self::Chat(scope, from, text) => write!(f, "chat {scope} {from} {text}"),
+      Self::Pong(timestamp) => write!(f, "pong {timestamp}"),
+      Self::Status(tick, tps, load) => write!(f, "status {tick} {tps} {load}"),
+      Self::Clock(tps, acceleration) => write!(f, "clock {tps} {acceleration}"),
+      Self::Hull(Hull {
+        length,
+        mass,
+        power,
+        surface_area,
+        screw_area,
+        k,
+        froude_scale_factor,
+        turning_circle,
+        rudder_time,
+        spool_time,
+        wave_height,
+        submerged,
+      }) => write!(
+        f,
+        "hull {length} {mass} {power} {surface_area} {screw_area} {k} {froude_scale_factor} \
+         {turning_circle} {rudder_time} {spool_time} {wave_height} {submerged}"
+      ),
+      Self::Machinery(Machinery { rudder, engine }) => write!(f, "machinery {rudder} {engine}"),
+      Self::Turn(yaw_rate, drift) => write!(f, "turn {yaw_rate} {drift}"),
+      Self::Weather(wind_direction, wind_speed, sea_state) => {
+        write!(f, "weather {wind_direction} {wind_speed} {sea_state}")
+      }
+      Self::Current(x, y) => write!(f, "current {x} {y}"),
+    }
+  }
+}
+
+impl FromStr for ServerMessage {
+  type Err = ParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut words = s.split_whitespace();
+    let message = match words.next() {
+      Some("hello") => {
+        let mut fields = Fields::new("hello", words);
+        let version = fields.parse("version")?;
+        // Capabilities this side doesn't know about are ignored
+        let capabilities = fields.words.filter_map(Capability::from_name).collect();
+        return Ok(Self::Hello(version, capabilities));
+      }
+      Some("server") => return Ok(Self::Server(Fields::new("server", words).rest())),
+      Some("reject") => return Ok(Self::Reject(Fields::new("reject", words).rest())),
+      Some("say") => return Ok(Self::Say(Fields::new("say", words).rest())),
+      Some("notice") => return Ok(Self::Notice(Fields::new("notice", words).rest())),
+      Some("error") => {
+        let mut fields = Fields::new("error", words);
+        let code = fields.parse("code")?;
+        return Ok(Self::Error(code, fields.rest()));
+      }
+      Some("chat") => {
+        let mut fields = Fields::new("chat", words);
+        let scope = ChatScope::parse(&mut fields)?;
+        let from = fields.word("from")?.to_owned();
+        return Ok(Self::Chat(scope, from, fields.rest()));
+      }
+      Some("welcome") => {
+        let mut fields = Fields::new("welcome", words);
+        let name = fields.word("name")?.to_owned();
+        fields.finish()?;
+        Self::Welcome(name)
+      }
+      Some("radius") => {
+        let mut fields = Fields::new("radius", words);
+        let radius = fields.parse("radius")?;
+        fields.finish()?;
+        Self::Radius(radius)
+      }
+      Some("ship") => Self::Ship(ShipState::parse(Fields::new("ship", words))?),
+      Some("appear") => Self::Appear(ShipState::parse(Fields::new("appear", words))?),
+      Some("disappear") => {
+        let mut fields = Fields::new("disappear", words);
+        let name = fields.word("name")?.to_owned();
+        fields.finish()?;
+        Self::Disappear(name)
+      }
+      Some("sunk") => {
+        let mut fields = Fields::new("sunk", words);
+        let name = fields.word("name")?.to_owned();
+        fields.finish()?;
+        Self::Sunk(name)
+      }
+      Some("splash") => {
+        let mut fields = Fields::new("splash", words);
+        let splash = Splash {
+          x: fields.parse("x")?,
+          y: fields.parse("y")?,
+          size: fields.parse("size")?,
+          duration: fields.parse("duration")?,
+          sprite: fields.parse("sprite")?,
+          colour: fields.parse("colour")?,
+          drift: match fields.optional("drift_x")? {
+            Some(drift_x) => (drift_x, fields.parse("drift_y")?),
+            None => (0.0, 0.0),
+          },
+        };
+        fields.finish()?;
+        Self::Splash(splash)
+      }
+      Some("wake") => {
+        let mut fields = Fields::new("wake", words);
+        let wake = Wake {
+          x: fields.parse("x")?,
+          y: fields.parse("y")?,
+          size: fields.parse("size")?,
+          angle: fields.parse("angle")?,
+          duration: fields.parse("duration")?,
+          growth: fields.parse("growth")?,
+          drift: match fields.optional("drift_x")? {
+            Some(drift_x) => (drift_x, fields.parse("drift_y")?),
+            None => (0.0, 0.0),
+          },
+        };
+        fields.finish()?;
+        Self::Wake(wake)
+      }
+      Some("snapshot") => {
+        let mut fields = Fields::new("snapshot", words);
+        let tick = fields.parse("tick")?;
+        let base = fields.optional("base")?;
+        fields.finish()?;
+        Self::Snapshot(tick, base)
+      }
+      Some("session") => {
+        let mut fields = Fields::new("session", words);
+        let token = fields.parse("token")?;
+        fields.finish()?;
+        Self::Session(token)
+      }
+      Some("pong") => {
+        let mut fields = Fields::new("pong", words);
+        let timestamp = fields.parse("timestamp")?;
+        fields.finish()?;
+        Self::Pong(timestamp)
+      }
+      Some("status") => {
+        let mut fields = Fields::new("status", words);
+        let tick = fields.parse("tick")?;
+        let tps = fields.parse("tps")?;
+        let load = fields.parse("load")?;
+        fields.finish()?;
+        Self::Status(tick, tps, load)
+      }
+      Some("clock") => {
+        let mut fields = Fields::new("clock", words);
+        let tps = fields.parse("tps")?;
+        let acceleration = fields.parse("acceleration")?;
+        fields.finish()?;
+        Self::Clock(tps, acceleration)
+      }
+      Some("hull") => {
+        let mut fields = Fields::new("hull", words);
+        let hull = Hull {
+          length: fields.parse("length")?,
+          mass: fields.parse("mass")?,
+          power: fields.parse("power")?,
+          surface_area: fields.parse("surface_area")?,
+          screw_area: fields.parse("screw_area")?,
+          k: fields.parse("k")?,
+          froude_scale_factor: fields.parse("froude_scale_factor")?,
+          turning_circle: fields.parse("turning_circle")?,
+          rudder_time: fields.parse("rudder_time")?,
+          spool_time: fields.parse("spool_time")?,
+          wave_height: fields.parse("wave_height")?,
+          submerged: fields.parse("submerged")?,
+        };
+        fields.finish()?;
+        Self::Hull(hull)
+      }
+      Some("machinery") => {
+        let mut fields = Fields::new("machinery", words);
+        let machinery = Machinery {
+          rudder: fields.parse("rudder")?,
+          engine: fields.parse("engine")?,
+        };
+        fields.finish()?;
+        Self::Machinery(machinery)
+      }
+      Some("turn") => {
+        let mut fields = Fields::new("turn", words);
+        let yaw_rate = fields.parse("yaw_rate")?;
+        let drift = fields.parse("drift")?;
+        fields.finish()?;
+        Self::Turn(yaw_rate, drift)
+      }
+      Some("weather") => {
+        let mut fields = Fields::new("weather", words);
+        let wind_direction = fields.parse("wind_direction")?;
+        let wind_speed = fields.parse("wind_speed")?;
+        let sea_state = fields.parse("sea_state")?;
+        fields.finish()?;
+        Self::Weather(wind_direction, wind_speed, sea_state)
+      }
+      Some("current") => {
+        let mut fields = Fields::new("current", words);
+        let x = fields.parse("x")?;
+        let y = fields.parse("y")?;
+        fields.finish()?;
+        Self::Current(x, y)
+      }
+      Some("udp") => {
+        let mut fields = Fields::new("udp", words);
+        let token = fields.parse("token")?;
+        fields.finish()?;
+        Self::Udp(token)
+      }
+      Some(word) => return Err(ParseError::UnknownMessage(word.to_owned())),
+      None => return Err(ParseError::Empty),
+    };
+    Ok(message)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn round_trip(message: ServerMessage) {
+    assert_eq!(message.to_string().parse(), Ok(message));
+  }
+
+  #[test]
+  fn messages_round_trip() {
+    round_trip(ServerMessage::Hello(2, Capability::ALL.to_vec()));
+    round_trip(ServerMessage::Server("Coral Sea".to_owned()));
+    round_trip(ServerMessage::Welcome("Yorktown2".to_owned()));
+    round_trip(ServerMessage::Reject(
+      "There is already a ship called Yorktown".to_owned(),
+    ));
+    round_trip(ServerMessage::Radius(2000.0));
+    round_trip(ServerMessage::Ship(ShipState {
+      name: "Yorktown".to_owned(),
+      x: -444.218_02,
+      y: 246.136_14,
+      angle: 0.1,
+      velocity: 12.345_678,
+      size: 93.3,
+      texture: 1,
+      colour: Colour::new(0x99, 0x99, 0x99, 255),
+      health: 0.75,
+    }));
+    round_trip(ServerMessage::Sunk("Kraken".to_owned()));
+    round_trip(ServerMessage::Splash(Splash {
+      x: 9.268_377,
+      y: 4.471_542_4,
+      size: 10.723_325,
+      duration: 0.25,
+      sprite: 2,
+      colour: Colour::new(0, 0, 0, 0x99),
+      drift: (0.0, 0.0),
+    }));
+    round_trip(ServerMessage::Splash(Splash {
+      x: 9.268_377,
+      y: 4.471_542_4,
+      size: 10.723_325,
+      duration: 30.0,
+      sprite: 2,
+      colour: Colour::new(0, 0, 0, 0x99),
+      drift: (-12.5, 3.25),
+    }));
+    round_trip(ServerMessage::Wake(Wake {
+      x: 1.0,
+      y: -2.5,
+      size: 16.65,
+      angle: 3.1,
+      duration: 10.0,
+      growth: 4.2,
+      drift: (0.0, 0.0),
+    }));
+    round_trip(ServerMessage::Wake(Wake {
+      x: 1.0,
+      y: -2.5,
+      size: 16.65,
+      angle: 3.1,
+      duration: 10.0,
+      growth: 4.2,
+      drift: (0.5, -1.25),
+    }));
+    round_trip(ServerMessage::Say("Server is shutting down".to_owned()));
+    round_trip(ServerMessage::Snapshot(120, Some(117)));
+    round_trip(ServerMessage::Snapshot(1, None));
+    round_trip(ServerMessage::Appear(ShipState {
+      name: "Kraken".to_owned(),
+      x: 2100.0,
+      y: -35.5,
+      angle: 0.0,
+      velocity: 0.0,
+      size: 40.0,
+      texture: 8,
+      colour: Colour::new(0x99, 0x99, 0x99, 255),
+      health: 1.0,
+    }));
+    round_trip(ServerMessage::Disappear("Kraken".to_owned()));
+    round_trip(ServerMessage::Udp(u64::MAX));
+    round_trip(ServerMessage::Error(
+      ErrorCode::InvalidMessage,
+      "Invalid power \"fast\" in sail".to_owned(),
+    ));
+    round_trip(ServerMessage::Notice("You have been kicked".to_owned()));
+    round_trip(ServerMessage::Session(1_234_567_890_123));
+    round_trip(ServerMessage::Pong(1_700_000_000_000));
+    round_trip(ServerMessage::Status(123_456, 60, 0.25));
+    round_trip(ServerMessage::Clock(60, 4.0));
+    round_trip(ServerMessage::Hull(Hull {
+      length: 100.0,
+      mass: 1_100_000.0,
+      power: 1_800_000.0,
+      surface_area: 2200.0,
+      screw_area: 2.8,
+      k: 0.2,
+      froude_scale_factor: 0.03,
+      turning_circle: 450.0,
+      rudder_time: 12.5,
+      spool_time: 30.0,
+      wave_height: 1.875,
+      submerged: true,
+    }));
+    round_trip(ServerMessage::Machinery(Machinery {
+      rudder: -0.35,
+      engine: 0.64,
+    }));
+    round_trip(ServerMessage::Turn(0.021, -1.25));
+    round_trip(ServerMessage::Weather(4.2, 11.5, 5));
+    round_trip(ServerMessage::Current(0.75, -0.3));
+    round_trip(ServerMessage::Chat(
+      ChatScope::Global,
+      "Yorktown".to_owned(),
+      "Anyone seen the kraken?".to_owned(),
+    ));
+    round_trip(ServerMessage::Chat(
+      ChatScope::Whisper("Enterprise".to_owned()),
+      "Hornet".to_owned(),
+      "Meet me north of the island".to_owned(),
+    ));
+  }
+
+  #[test]
+  fn errors_describe_the_problem() {
+    assert_eq!(
+      "ship Yorktown 1 2 0 0 93.3 1 #999".parse::<ServerMessage>(),
+      Err(ParseError::MissingField("ship", "health"))
+    );
+    assert_eq!(
+      "ship Yorktown 1 2 0 0 93.3 1 grey 1".parse::<ServerMessage>(),
+      Err(ParseError::InvalidField(
+        "ship",
+        "colour",
+        "grey".to_owned()
+      ))
+    );
+    assert_eq!(
+      "error oops Something broke".parse::<ServerMessage>(),
+      Err(ParseError::InvalidField("error", "code", "oops".to_owned()))
+    );
+    assert_eq!(
+      "sunk".parse::<ServerMessage>(),
+      Err(ParseError::MissingField("sunk", "name"))
+    );
+  }
+}